@@ -0,0 +1,33 @@
+// Links the kernel binary with its linker script and builds the user programs (user/) the
+// kernel embeds, see elf::PROGRAMS
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+const USER_TARGET: &str = "riscv64gc-unknown-none-elf";
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    println!("cargo:rustc-link-arg-bins=-T{}", manifest_dir.join("src/lds/virt.lds").display());
+
+    // a cargo of their own, with its own target directory so it doesn't wait on the lock this
+    // build holds. Always release, the images end up in the kernel either way
+    let target_dir = PathBuf::from(env::var("OUT_DIR").unwrap()).join("user");
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    let status = Command::new(cargo)
+        .args(["build", "--release", "--package", "user", "--target", USER_TARGET, "--target-dir"])
+        .arg(&target_dir)
+        .current_dir(&manifest_dir)
+        // whatever this build runs under (clippy, the kernel's rustflags) isn't meant for them
+        .env_remove("RUSTC_WORKSPACE_WRAPPER")
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .env_remove("RUSTFLAGS")
+        .status()
+        .expect("couldn't run cargo for the user programs");
+    if !status.success() {
+        panic!("building the user programs failed");
+    }
+    println!("cargo:rustc-env=USER_PROGRAMS={}", target_dir.join(USER_TARGET).join("release").display());
+    println!("cargo:rerun-if-changed=user");
+    println!("cargo:rerun-if-changed=src/lds/virt.lds");
+}
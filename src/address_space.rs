@@ -0,0 +1,612 @@
+//! Owning, error returning wrapper around a Sv39 root page table.
+//! `page::map` and `page::unmap` work on a bare `&mut PageTable` and just assert when something is off,
+//! which is fine for the kernel's own identity mappings but not for anything a process can ask for.
+use core::ptr::null_mut;
+use crate::page::{self, PageTable, PageTableEntry, PageTableEntryBits, PAGE_SIZE};
+use crate::page_box::{PageBox, PageRange};
+use crate::tlb;
+
+// Everything that can go wrong when editing an address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    // none of the read write execute bits were given, so the entry would be a branch
+    InvalidBits,
+    // only levels 0 (4 KiB), 1 (2 MiB) and 2 (1 GiB) exist in Sv39
+    InvalidLevel,
+    // virtual or physical address not aligned to the page size of the level
+    Misaligned,
+    // something is already mapped at (or over) the virtual address
+    Overlap,
+    // nothing is mapped at the virtual address
+    NotMapped,
+    // page::zalloc couldn't give us a page for an intermediate table
+    OutOfMemory,
+    // a store hit a read only page that isn't copy on write, so it really is illegal
+    PermissionDenied,
+    // every region slot of the address space is in use
+    TooManyRegions,
+    // the leaf maps a page the space doesn't own (the trampoline, a process's frame, an identity
+    // mapping), its permissions aren't ours to change
+    NotOwned,
+}
+
+// What the faulting instruction was trying to do, taken from the scause code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Load,
+    Store,
+    Execute,
+}
+
+// How many lazily backed regions one address space can have
+pub const MAX_REGIONS: usize = 16;
+
+// A reserved range of virtual memory with no pages behind it yet. The page fault
+// handler backs it one zeroed page at a time, on first touch
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub bits: i64,
+}
+
+impl Region {
+    const fn empty() -> Self {
+        Region { start: 0, end: 0, bits: 0 }
+    }
+    fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+    fn contains(&self, virtual_address: usize) -> bool {
+        virtual_address >= self.start && virtual_address < self.end
+    }
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        !self.is_empty() && start < self.end && self.start < end
+    }
+    fn allows(&self, access: Access) -> bool {
+        let needed = match access {
+            Access::Load => PageTableEntryBits::Read,
+            Access::Store => PageTableEntryBits::Write,
+            Access::Execute => PageTableEntryBits::Execute,
+        };
+        self.bits & needed.as_i64() != 0
+    }
+}
+
+// The low 10 bits of an entry are flags (V R W X U G A D + 2 RSW bits), the rest is the ppn
+pub(crate) const FLAG_MASK: i64 = 0b1111111111;
+// Read write execute user global accessed dirty, the bits a caller is allowed to set
+const PERMISSION_MASK: i64 = 0b11111110;
+// read write execute, if any of these are set the entry is a leaf
+const LEAF_MASK: i64 = 0b1110;
+const VALID: i64 = PageTableEntryBits::Valid.as_i64();
+const WRITE: i64 = PageTableEntryBits::Write.as_i64();
+const USER: i64 = PageTableEntryBits::User.as_i64();
+const ACCESSED: i64 = PageTableEntryBits::Access.as_i64();
+const DIRTY: i64 = PageTableEntryBits::Dirty.as_i64();
+const COPY_ON_WRITE: i64 = PageTableEntryBits::CopyOnWrite.as_i64();
+const OWNED: i64 = PageTableEntryBits::Owned.as_i64();
+
+// Leaves start out accessed, and dirty if they're writable. Hardware without Svadu doesn't set
+// them itself and faults on the first access instead, which nothing here would know to fix
+const fn accessed_dirty(bits: i64) -> i64 {
+    if bits & WRITE != 0 { ACCESSED | DIRTY } else { ACCESSED }
+}
+
+// The bits a leaf needs for an access from user mode
+fn needed_bits(access: Access) -> i64 {
+    USER | match access {
+        Access::Load => PageTableEntryBits::Read.as_i64(),
+        Access::Store => WRITE,
+        Access::Execute => PageTableEntryBits::Execute.as_i64(),
+    }
+}
+
+// Size in bytes of a page mapped at the given level
+pub const fn level_page_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+// Split a virtual address into its 3 virtual page numbers (index 0 is the leaf level)
+fn virtual_page_numbers(virtual_address: usize) -> [usize; 3] {
+    [
+        (virtual_address >> 12) & 0b111111111, // bits 12:20 of the address
+        (virtual_address >> 21) & 0b111111111, // bits 21:29 of the address
+        (virtual_address >> 30) & 0b111111111, // bits 30:38 of the address
+    ]
+}
+
+// Follow a valid non leaf entry to the table it points at
+pub(crate) fn next_table(entry: &PageTableEntry) -> *mut PageTable {
+    ((entry.get_entry() & !FLAG_MASK) << 2) as *mut PageTable
+}
+
+// Physical address a leaf entry points at (the start of its page)
+pub(crate) fn leaf_physical_address(entry: &PageTableEntry) -> usize {
+    ((entry.get_entry() & !FLAG_MASK) << 2) as usize
+}
+
+pub struct AddressSpace {
+    root: *mut PageTable,
+    regions: [Region; MAX_REGIONS],
+    // from tlb::refresh_asid, 0 until the space is first activated
+    asid: usize,
+    // how many owned pages are mapped, shared copy on write ones included
+    owned_pages: usize,
+}
+
+impl AddressSpace {
+    // Allocate an empty root table
+    pub fn new() -> Result<Self, MapError> {
+        let root = page::zalloc(1) as *mut PageTable;
+        if root.is_null() {
+            return Err(MapError::OutOfMemory);
+        }
+        Ok(AddressSpace { root, regions: [Region::empty(); MAX_REGIONS], asid: 0, owned_pages: 0 })
+    }
+
+    pub fn root(&self) -> &PageTable {
+        unsafe { &*self.root }
+    }
+
+    pub fn root_mut(&mut self) -> &mut PageTable {
+        unsafe { &mut *self.root }
+    }
+
+    // Physical address of the root table, this is what ends up in satp
+    pub fn root_address(&self) -> usize {
+        self.root as usize
+    }
+
+    pub fn asid(&self) -> usize {
+        self.asid
+    }
+
+    // Switch this hart over to the address space: make sure it has an asid from the
+    // current generation and load satp. The hart only remembers the root and the asid, so the
+    // space is free to move afterwards
+    pub fn activate(&mut self) {
+        tlb::switch_to(self.root_address(), self.refresh_asid());
+    }
+
+    // Just the asid half of activate, for the trampoline that loads satp on its own
+    pub fn refresh_asid(&mut self) -> usize {
+        self.asid = tlb::refresh_asid(self.asid);
+        self.asid
+    }
+
+    // Drop whatever the TLB remembers about one address. A space that was never
+    // activated can't have anything cached
+    fn flush(&self, virtual_address: usize) {
+        if self.asid != 0 {
+            tlb::flush_address_asid(virtual_address, self.asid);
+        }
+    }
+
+    fn flush_all(&self) {
+        if self.asid != 0 {
+            tlb::flush_asid(self.asid);
+        }
+    }
+
+    // Map one page of the given level (0 = 4 KiB, 1 = 2 MiB, 2 = 1 GiB)
+    pub fn map(&mut self, virtual_address: usize, physical_address: usize, bits: i64, level: usize) -> Result<(), MapError> {
+        if level > 2 {
+            return Err(MapError::InvalidLevel);
+        }
+        if bits & LEAF_MASK == 0 || bits & !PERMISSION_MASK != 0 {
+            return Err(MapError::InvalidBits);
+        }
+        let size = level_page_size(level);
+        if !virtual_address.is_multiple_of(size) || !physical_address.is_multiple_of(size) {
+            return Err(MapError::Misaligned);
+        }
+        // same layout page::map uses: ppn[2] at 28, ppn[1] at 19, ppn[0] at 10
+        self.map_entry(virtual_address, level, (physical_address >> 2) as i64 | bits | accessed_dirty(bits) | VALID)
+    }
+
+    // Allocate a fresh zeroed page and map it. The address space owns the page
+    // and gives it back on unmap or drop. Returns the physical address
+    pub fn allocate(&mut self, virtual_address: usize, bits: i64) -> Result<usize, MapError> {
+        if bits & LEAF_MASK == 0 || bits & !PERMISSION_MASK != 0 {
+            return Err(MapError::InvalidBits);
+        }
+        if !virtual_address.is_multiple_of(PAGE_SIZE) {
+            return Err(MapError::Misaligned);
+        }
+        let page = PageRange::zeroed(1).ok_or(MapError::OutOfMemory)?;
+        let entry = (page.address() as i64 >> 2) | bits | accessed_dirty(bits) | OWNED | VALID;
+        // if the map fails the page just drops
+        self.map_entry(virtual_address, 0, entry)?;
+        self.owned_pages += 1;
+        Ok(page.into_raw() as usize)
+    }
+
+    // Put an already built leaf entry in place, the entry isn't checked
+    fn map_entry(&mut self, virtual_address: usize, level: usize, entry: i64) -> Result<(), MapError> {
+        let slot = self.walk_create(virtual_address, level)?;
+        if slot.is_valid() {
+            // either a leaf is already here or a smaller table hangs off this entry
+            return Err(MapError::Overlap);
+        }
+        slot.set_entry(entry);
+        self.flush(virtual_address);
+        Ok(())
+    }
+
+    // Map a range of 4 KiB pages, undoing whatever was mapped if any page fails
+    pub fn map_range(&mut self, virtual_address: usize, physical_address: usize, size: usize, bits: i64) -> Result<(), MapError> {
+        if !virtual_address.is_multiple_of(PAGE_SIZE) || !physical_address.is_multiple_of(PAGE_SIZE) {
+            return Err(MapError::Misaligned);
+        }
+        let num_pages = page::align_value(size, 12) / PAGE_SIZE;
+        for i in 0..num_pages {
+            if let Err(error) = self.map(virtual_address + i * PAGE_SIZE, physical_address + i * PAGE_SIZE, bits, 0) {
+                for j in 0..i {
+                    let _ = self.unmap(virtual_address + j * PAGE_SIZE);
+                }
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    // Remove the leaf covering the virtual address, returning the physical address it pointed to.
+    // Owned pages lose this space's reference. Intermediate tables are left in place and get freed on drop
+    pub fn unmap(&mut self, virtual_address: usize) -> Result<usize, MapError> {
+        let (entry, _) = self.find_leaf(virtual_address).ok_or(MapError::NotMapped)?;
+        let entry = unsafe { &mut *entry };
+        let physical_address = leaf_physical_address(entry);
+        let owned = entry.get_entry() & OWNED != 0;
+        entry.set_entry(0);
+        self.flush(virtual_address);
+        if owned {
+            page::release(physical_address as *mut u8);
+            self.owned_pages -= 1;
+        }
+        Ok(physical_address)
+    }
+
+    // Reserve a range that gets backed by zeroed pages on first access instead of up front,
+    // so a big heap or stack costs nothing until it is touched
+    pub fn reserve(&mut self, virtual_address: usize, size: usize, bits: i64) -> Result<(), MapError> {
+        if bits & LEAF_MASK == 0 || bits & !PERMISSION_MASK != 0 {
+            return Err(MapError::InvalidBits);
+        }
+        if !virtual_address.is_multiple_of(PAGE_SIZE) || size == 0 {
+            return Err(MapError::Misaligned);
+        }
+        let end = virtual_address.checked_add(page::align_value(size, 12)).ok_or(MapError::Misaligned)?;
+        if self.regions.iter().any(|region| region.overlaps(virtual_address, end)) {
+            return Err(MapError::Overlap);
+        }
+        // nor over anything that's already mapped, the fault handler would never see it
+        let mut mapped = false;
+        for_each_leaf(self.root, 2, 0, &mut |leaf_address, _, level| {
+            mapped |= leaf_address < end && virtual_address < leaf_address + level_page_size(level);
+        });
+        if mapped {
+            return Err(MapError::Overlap);
+        }
+        let slot = self.regions.iter_mut().find(|region| region.is_empty()).ok_or(MapError::TooManyRegions)?;
+        *slot = Region { start: virtual_address, end, bits };
+        Ok(())
+    }
+
+    // Drop a reservation made with reserve, along with every page that got faulted in for it
+    pub fn unreserve(&mut self, virtual_address: usize) -> Result<(), MapError> {
+        let slot = self.regions.iter_mut().find(|region| !region.is_empty() && region.start == virtual_address)
+            .ok_or(MapError::NotMapped)?;
+        let region = *slot;
+        *slot = Region::empty();
+        let mut released = 0;
+        for_each_leaf(self.root, 2, 0, &mut |leaf_address, entry, _| {
+            if region.contains(leaf_address) && entry.get_entry() & OWNED != 0 {
+                let physical_address = leaf_physical_address(entry);
+                entry.set_entry(0);
+                page::release(physical_address as *mut u8);
+                released += 1;
+            }
+        });
+        self.owned_pages -= released;
+        self.flush_all();
+        Ok(())
+    }
+
+    // Pages the space owns (allocated, faulted in or shared copy on write), not counting the page
+    // tables themselves
+    pub fn owned_pages(&self) -> usize {
+        self.owned_pages
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter().filter(|region| !region.is_empty())
+    }
+
+    // Entry point for page faults from user mode: back a reserved page on first touch, or hand
+    // stores to copy on write pages over to handle_store_fault. A fault on a page that already
+    // allows the access came from a stale TLB entry (another hart changed the leaf since this one
+    // cached it), or from an A or D bit the hardware won't set, and just gets retried. Anything
+    // else is a real fault
+    pub fn handle_fault(&mut self, virtual_address: usize, access: Access) -> Result<(), MapError> {
+        if let Some((entry, _)) = self.find_leaf(virtual_address) {
+            let entry = unsafe { &mut *entry };
+            let bits = entry.get_entry();
+            let needed = needed_bits(access);
+            if bits & needed == needed {
+                entry.set_entry(bits | accessed_dirty(needed));
+                tlb::flush_address(virtual_address);
+                return Ok(());
+            }
+            // otherwise only a store to a copy on write page is fixable
+            if access == Access::Store && bits & COPY_ON_WRITE != 0 {
+                return self.handle_store_fault(virtual_address);
+            }
+            return Err(MapError::PermissionDenied);
+        }
+        let region = *self.regions.iter().find(|region| region.contains(virtual_address)).ok_or(MapError::NotMapped)?;
+        if !region.allows(access) {
+            return Err(MapError::PermissionDenied);
+        }
+        self.allocate(virtual_address & !(PAGE_SIZE - 1), region.bits)?;
+        Ok(())
+    }
+
+    // Duplicate the address space the way fork does. Owned pages are shared rather than
+    // copied: both sides lose write access and get the copy on write bit, and the first
+    // store from either side lands in handle_store_fault. Pages the space doesn't own
+    // (mmio, the kernel's identity mappings) are just mapped again
+    pub fn fork(&mut self) -> Result<AddressSpace, MapError> {
+        let mut child = AddressSpace::new()?;
+        // reservations carry over, pages that were never touched stay untouched in both
+        child.regions = self.regions;
+        let mut result = Ok(());
+        for_each_leaf(self.root, 2, 0, &mut |virtual_address, entry, level| {
+            if result.is_err() {
+                return;
+            }
+            let mut bits = entry.get_entry();
+            if bits & OWNED != 0 {
+                page::add_reference(leaf_physical_address(entry) as *mut u8);
+                if bits & (WRITE | COPY_ON_WRITE) != 0 {
+                    bits = (bits & !WRITE) | COPY_ON_WRITE;
+                    entry.set_entry(bits);
+                }
+            }
+            match child.map_entry(virtual_address, level, bits) {
+                Ok(()) if bits & OWNED != 0 => child.owned_pages += 1,
+                Ok(()) => {}
+                Err(error) => {
+                    if bits & OWNED != 0 {
+                        page::release(leaf_physical_address(entry) as *mut u8);
+                    }
+                    result = Err(error);
+                }
+            }
+        });
+        // the parent lost write access to every shared page
+        self.flush_all();
+        // on failure the child drops here and gives back whatever it had already shared
+        result.map(|_| child)
+    }
+
+    // Resolve a store page fault on a copy on write page. The last space holding the page
+    // just gets write access back, everyone else gets a private copy of it
+    pub fn handle_store_fault(&mut self, virtual_address: usize) -> Result<(), MapError> {
+        let (entry, _) = self.find_leaf(virtual_address).ok_or(MapError::NotMapped)?;
+        let entry = unsafe { &mut *entry };
+        let bits = entry.get_entry();
+        if bits & COPY_ON_WRITE == 0 {
+            return Err(MapError::PermissionDenied);
+        }
+        let old_page = leaf_physical_address(entry) as *mut u8;
+        if page::reference_count(old_page) == 1 {
+            entry.set_entry((bits & !COPY_ON_WRITE) | WRITE | ACCESSED | DIRTY);
+            self.flush(virtual_address);
+            return Ok(());
+        }
+        let new_page = page::alloc(1);
+        if new_page.is_null() {
+            return Err(MapError::OutOfMemory);
+        }
+        // the kernel runs on physical addresses, so both pages can be copied directly
+        // (u64 at a time like zalloc does)
+        let source = old_page as *const u64;
+        let destination = new_page as *mut u64;
+        for i in 0..PAGE_SIZE / 8 {
+            unsafe { *destination.add(i) = *source.add(i) };
+        }
+        page::release(old_page);
+        let flags = bits & FLAG_MASK & !COPY_ON_WRITE;
+        entry.set_entry((new_page as i64 >> 2) | flags | WRITE | ACCESSED | DIRTY | OWNED);
+        self.flush(virtual_address);
+        Ok(())
+    }
+
+    // Swap the permission bits of an existing leaf. The ppn and RSW bits are kept. Only pages
+    // the space owns, anything mapped in with map is left the way whoever mapped it wanted
+    pub fn protect(&mut self, virtual_address: usize, bits: i64) -> Result<(), MapError> {
+        if bits & LEAF_MASK == 0 || bits & !PERMISSION_MASK != 0 {
+            return Err(MapError::InvalidBits);
+        }
+        let (entry, _) = self.find_leaf(virtual_address).ok_or(MapError::NotMapped)?;
+        let entry = unsafe { &mut *entry };
+        if entry.get_entry() & OWNED == 0 {
+            return Err(MapError::NotOwned);
+        }
+        let kept = entry.get_entry() & !(PERMISSION_MASK | VALID);
+        entry.set_entry(kept | bits | accessed_dirty(bits) | VALID);
+        self.flush(virtual_address);
+        Ok(())
+    }
+
+    // Virtual to physical translation, None acts as a page fault
+    pub fn translate(&self, virtual_address: usize) -> Option<usize> {
+        let (entry, level) = self.find_leaf(virtual_address)?;
+        let offset_mask = level_page_size(level) - 1;
+        let entry = unsafe { &*entry };
+        Some((leaf_physical_address(entry) & !offset_mask) | (virtual_address & offset_mask))
+    }
+
+    // Permission bits of the leaf mapping the virtual address
+    pub fn flags(&self, virtual_address: usize) -> Option<i64> {
+        let (entry, _) = self.find_leaf(virtual_address)?;
+        Some(unsafe { (*entry).get_entry() } & FLAG_MASK)
+    }
+
+    // Walk down to the entry for the virtual address at the given level, allocating
+    // intermediate tables on the way. Running into a leaf above the level is an overlap
+    fn walk_create(&mut self, virtual_address: usize, level: usize) -> Result<&mut PageTableEntry, MapError> {
+        // free_table gives the tables back along with the rest of the tree
+        walk_create(self.root, virtual_address, level).map(|entry| unsafe { &mut *entry })
+    }
+
+    // Find the leaf entry covering the virtual address and the level it sits at
+    fn find_leaf(&self, virtual_address: usize) -> Option<(*mut PageTableEntry, usize)> {
+        find_leaf(self.root, virtual_address)
+    }
+}
+
+// AddressSpace::walk_create for any table
+fn walk_create(root: *mut PageTable, virtual_address: usize, level: usize) -> Result<*mut PageTableEntry, MapError> {
+    let virtual_page_numbers = virtual_page_numbers(virtual_address);
+    let mut table = root;
+    for i in (level + 1..=2).rev() {
+        let entry = unsafe { &mut (*table).entries[virtual_page_numbers[i]] };
+        if !entry.is_valid() {
+            let page = PageBox::<PageTable>::table().ok_or(MapError::OutOfMemory)?.into_raw();
+            entry.set_entry((page as i64 >> 2) | VALID);
+        } else if entry.is_leaf() {
+            return Err(MapError::Overlap);
+        }
+        table = next_table(entry);
+    }
+    Ok(unsafe { &mut (*table).entries[virtual_page_numbers[level]] })
+}
+
+// Identity map [start, end) into a table nobody wraps in an AddressSpace (the kernel's from
+// malloc::get_page_table) with 4 KiB pages. If a page can't be mapped, everything this mapped
+// comes back out, along with the tables it had to create
+pub(crate) fn map_identity(root: *mut PageTable, start: usize, end: usize, bits: i64) -> Result<(), MapError> {
+    let start = start & !(PAGE_SIZE - 1);
+    let end = page::align_value(end, 12);
+    for address in (start..end).step_by(PAGE_SIZE) {
+        let result = walk_create(root, address, 0).and_then(|slot| {
+            let slot = unsafe { &mut *slot };
+            if slot.is_valid() {
+                return Err(MapError::Overlap);
+            }
+            slot.set_entry((address >> 2) as i64 | bits | accessed_dirty(bits) | VALID);
+            Ok(())
+        });
+        if let Err(error) = result {
+            unmap_identity(root, start, address);
+            // the walk may have made tables for this page before it failed
+            prune(root, address);
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+// Take the 4 KiB leaves in [start, end) back out of a table like map_identity's, and free the
+// tables under them that end up empty. The pages themselves belong to whoever mapped them
+pub(crate) fn unmap_identity(root: *mut PageTable, start: usize, end: usize) {
+    for address in (start & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+        if let Some((entry, 0)) = find_leaf(root, address) {
+            unsafe { (*entry).set_entry(0) };
+            tlb::flush_address(address);
+        }
+        prune(root, address);
+    }
+}
+
+// Free the level 0 and level 1 tables on the way to the virtual address if they're empty
+fn prune(root: *mut PageTable, virtual_address: usize) {
+    let is_empty = |table: *mut PageTable| unsafe { (*table).entries.iter().all(|entry| !entry.is_valid()) };
+    let virtual_page_numbers = virtual_page_numbers(virtual_address);
+    let top = unsafe { &mut (*root).entries[virtual_page_numbers[2]] };
+    if !top.is_valid() || top.is_leaf() {
+        return;
+    }
+    let middle_table = next_table(top);
+    let middle = unsafe { &mut (*middle_table).entries[virtual_page_numbers[1]] };
+    if middle.is_valid() && !middle.is_leaf() && is_empty(next_table(middle)) {
+        page::dealloc(next_table(middle) as *mut u8);
+        middle.set_entry(0);
+    }
+    if is_empty(middle_table) {
+        page::dealloc(middle_table as *mut u8);
+        top.set_entry(0);
+    }
+}
+
+// Walk any Sv39 table (not just one an AddressSpace owns, the kernel's table from
+// malloc::get_page_table too) down to the leaf covering the virtual address
+pub(crate) fn find_leaf(root: *mut PageTable, virtual_address: usize) -> Option<(*mut PageTableEntry, usize)> {
+    let virtual_page_numbers = virtual_page_numbers(virtual_address);
+    let mut table = root;
+    for level in (0..=2).rev() {
+        let entry = unsafe { &mut (*table).entries[virtual_page_numbers[level]] };
+        if !entry.is_valid() {
+            return None;
+        }
+        if entry.is_leaf() {
+            return Some((entry as *mut PageTableEntry, level));
+        }
+        if level == 0 {
+            // a non leaf at level 0 is malformed, treat it like a fault
+            return None;
+        }
+        table = next_table(entry);
+    }
+    None
+}
+
+// Call f with the virtual address, entry and level of every leaf below the table
+fn for_each_leaf(table: *mut PageTable, level: usize, virtual_base: usize, f: &mut dyn FnMut(usize, &mut PageTableEntry, usize)) {
+    for i in 0..PageTable::len() {
+        let entry = unsafe { &mut (*table).entries[i] };
+        if !entry.is_valid() {
+            continue;
+        }
+        let virtual_address = virtual_base | (i << (12 + 9 * level));
+        if entry.is_leaf() {
+            f(virtual_address, entry, level);
+        } else if level > 0 {
+            for_each_leaf(next_table(entry), level - 1, virtual_address, f);
+        }
+    }
+}
+
+// Free every table below (and including) the given one. Only owned leaves give
+// their page back, the rest point at memory someone else is responsible for
+fn free_table(table: *mut PageTable, level: usize) {
+    for i in 0..PageTable::len() {
+        let entry = unsafe { &(*table).entries[i] };
+        if !entry.is_valid() {
+            continue;
+        }
+        if entry.is_leaf() {
+            if entry.get_entry() & OWNED != 0 {
+                page::release(leaf_physical_address(entry) as *mut u8);
+            }
+        } else if level > 0 {
+            free_table(next_table(entry), level - 1);
+        }
+    }
+    page::dealloc(table as *mut u8);
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if tlb::active().is_some_and(|(root, _)| root == self.root_address()) {
+            // can't keep translating through tables that are about to be freed
+            tlb::switch_to_bare();
+        }
+        if !self.root.is_null() {
+            free_table(self.root, 2);
+            self.root = null_mut();
+        }
+    }
+}
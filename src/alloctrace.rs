@@ -0,0 +1,138 @@
+//! A ring buffer of page allocator and kernel heap events for the `alloctrace` shell command.
+//! Tracing is off until someone turns it on, after that every page::alloc/dealloc and
+//! kernel_malloc/kernel_free lands here with who called it, so when test_alloc misbehaves there's
+//! more to go on than the final page table. Only the newest TRACE_LENGTH events are kept
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::cpu;
+use crate::sync::{SpinLock, TRACE_CLASS};
+use crate::{print, println};
+
+pub const TRACE_LENGTH: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    PageAlloc,
+    PageDealloc,
+    Malloc,
+    Realloc,
+    Free,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::PageAlloc => "page alloc",
+            Event::PageDealloc => "page free",
+            Event::Malloc => "malloc",
+            Event::Realloc => "realloc",
+            Event::Free => "free",
+        }
+    }
+
+    pub fn is_page(&self) -> bool {
+        matches!(self, Event::PageAlloc | Event::PageDealloc)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Record {
+    pub event: Event,
+    pub address: usize,
+    // in bytes, pages included
+    pub size: usize,
+    // return address into whoever called the allocator
+    pub caller: usize,
+    pub hart: usize,
+    pub time: usize,
+}
+
+impl Record {
+    const fn empty() -> Self {
+        Record { event: Event::Malloc, address: 0, size: 0, caller: 0, hart: 0, time: 0 }
+    }
+}
+
+// Which records alloctrace prints
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Filter {
+    All,
+    Pages,
+    Heap,
+    Hart(usize),
+}
+
+impl Filter {
+    pub fn matches(&self, record: &Record) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Pages => record.event.is_page(),
+            Filter::Heap => !record.event.is_page(),
+            Filter::Hart(hart) => record.hart == *hart,
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// how many records were ever written since the last enable, the next one goes in slot RECORDED % TRACE_LENGTH
+static RECORDED: AtomicUsize = AtomicUsize::new(0);
+// the allocators call in here with their own locks held, so nothing else gets taken under this one
+static TRACE_LOCK: SpinLock = SpinLock::ranked(TRACE_CLASS);
+static mut RECORDS: [Record; TRACE_LENGTH] = [Record::empty(); TRACE_LENGTH];
+
+// Throw away whatever was traced before and start tracing
+pub fn enable() {
+    let _guard = TRACE_LOCK.lock();
+    RECORDED.store(0, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Everything recorded since tracing was turned on, including what's been overwritten
+pub fn recorded() -> usize {
+    RECORDED.load(Ordering::Relaxed)
+}
+
+pub fn record(event: Event, address: usize, size: usize, caller: usize) {
+    if !is_enabled() {
+        return;
+    }
+    let record = Record { event, address, size, caller, hart: cpu::hart_id(), time: cpu::time() };
+    let _guard = TRACE_LOCK.lock();
+    let index = RECORDED.load(Ordering::Relaxed);
+    unsafe { (*core::ptr::addr_of_mut!(RECORDS))[index % TRACE_LENGTH] = record };
+    RECORDED.store(index + 1, Ordering::Relaxed);
+}
+
+// Oldest to newest. The trace is locked the whole time, so f mustn't allocate while tracing is on
+pub fn for_each_record(mut f: impl FnMut(&Record)) {
+    let _guard = TRACE_LOCK.lock();
+    let recorded = RECORDED.load(Ordering::Relaxed);
+    let records = unsafe { &*core::ptr::addr_of!(RECORDS) };
+    for index in recorded.saturating_sub(TRACE_LENGTH)..recorded {
+        f(&records[index % TRACE_LENGTH]);
+    }
+}
+
+pub fn print_trace(filter: Filter) {
+    let recorded = recorded();
+    if recorded > TRACE_LENGTH {
+        println!("[INFO] {} older events were overwritten", recorded - TRACE_LENGTH);
+    }
+    println!("{:>12} {:>4} {:<10} {:>18} {:>10} {:>18}", "time", "hart", "event", "address", "size", "caller");
+    let mut shown = 0;
+    for_each_record(|record| {
+        if filter.matches(record) {
+            println!("{:>12} {:>4} {:<10} {:#18x} {:>10} {:#18x}",
+                record.time, record.hart, record.event.name(), record.address, record.size, record.caller);
+            shown += 1;
+        }
+    });
+    println!("{} event(s)", shown);
+}
@@ -0,0 +1,90 @@
+//! Console input.
+//! Output goes straight to the uart, anybody can print. Input has one reader at a time, the
+//! foreground: the shell, or the process it's waiting on. Anybody else reading would be racing it
+//! for bytes out of the uart's FIFO, so whatever a background process picked up would be missing
+//! from the shell's line. There's no receive interrupt, readers poll, and whatever a poll turns up
+//! waits in a buffer for the foreground to read it. That lets the shell watch for Ctrl-C while a
+//! process has the foreground without eating the process's input
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::process::{self, Pid, KERNEL_PID};
+use crate::sync::IrqSpinLock;
+use crate::uart::Uart;
+
+// Ctrl-C
+pub const INTERRUPT: u8 = 0x03;
+
+const BUFFER_SIZE: usize = 256;
+
+// Bytes that came in for the foreground and haven't been read yet, oldest at head
+struct Input {
+    bytes: [u8; BUFFER_SIZE],
+    head: usize,
+    length: usize,
+}
+
+impl Input {
+    // a full buffer drops what comes in, the same as a full uart FIFO would
+    fn push(&mut self, byte: u8) {
+        if self.length < BUFFER_SIZE {
+            self.bytes[(self.head + self.length) % BUFFER_SIZE] = byte;
+            self.length += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.length == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % BUFFER_SIZE;
+        self.length -= 1;
+        Some(byte)
+    }
+}
+
+static FOREGROUND: AtomicUsize = AtomicUsize::new(KERNEL_PID);
+static INPUT: IrqSpinLock<Input> = IrqSpinLock::new(Input { bytes: [0; BUFFER_SIZE], head: 0, length: 0 });
+
+// Who reads input right now, KERNEL_PID for the shell
+pub fn foreground() -> Pid {
+    FOREGROUND.load(Ordering::Relaxed)
+}
+
+// Hand input over to pid. Only the shell does this, around waiting for a process it started.
+// Whatever the last foreground didn't read goes with it
+pub fn set_foreground(pid: Pid) {
+    let mut input = INPUT.lock();
+    FOREGROUND.store(pid, Ordering::Relaxed);
+    input.length = 0;
+}
+
+// Move whatever the uart has into the buffer. A Ctrl-C while a process has the foreground kills
+// the process instead of going in, that's how the shell gets its prompt back from one that won't
+// stop on its own
+pub fn poll() {
+    let mut interrupted = false;
+    {
+        let mut input = INPUT.lock();
+        let mut uart = Uart::new(0xD4017000);
+        while let Some(byte) = uart.get() {
+            if byte == INTERRUPT && foreground() != KERNEL_PID {
+                interrupted = true;
+            } else {
+                input.push(byte);
+            }
+        }
+    }
+    // the process table lock isn't taken with the input locked
+    if interrupted {
+        let _ = process::kill(foreground());
+    }
+}
+
+// The next byte of input for pid, None if nothing has come in or pid isn't in the foreground
+pub fn get(pid: Pid) -> Option<u8> {
+    if foreground() != pid {
+        return None;
+    }
+    poll();
+    INPUT.lock().pop()
+}
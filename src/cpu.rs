@@ -0,0 +1,160 @@
+//! Per hart bits and pieces.
+//! boot.S stashes the hart id the bootloader hands us in a0 into tp before doing anything else,
+//! nothing in the kernel touches tp after that so it's a free way to ask "which hart am I"
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// The KY X1 on the Orangepi RV2 has 8 cores, qemu is run with 4
+pub const MAX_HARTS: usize = 8;
+
+pub fn hart_id() -> usize {
+    let id: usize;
+    unsafe { asm!("mv {}, tp", out(reg) id) };
+    id
+}
+
+// How fast the time CSR ticks when the device tree doesn't say, qemu virt's
+const DEFAULT_TIMEBASE_FREQUENCY: usize = 10_000_000;
+static TIMEBASE_FREQUENCY: AtomicUsize = AtomicUsize::new(DEFAULT_TIMEBASE_FREQUENCY);
+
+// Pick up the timebase-frequency from the DTB. It normally sits on /cpus, but it's allowed on
+// each cpu node instead. Runs once on the boot hart before anything starts the timer
+pub fn init_timebase() {
+    let frequency = crate::fdt::boot().and_then(|fdt| {
+        fdt.property("/cpus", "timebase-frequency")
+            .or_else(|| fdt.property("/cpus/cpu", "timebase-frequency"))
+            .and_then(crate::fdt::read_number)
+    });
+    if let Some(frequency) = frequency.filter(|&frequency| frequency != 0) {
+        TIMEBASE_FREQUENCY.store(frequency as usize, Ordering::Relaxed);
+    }
+}
+
+// Time CSR ticks per second
+pub fn timebase_frequency() -> usize {
+    TIMEBASE_FREQUENCY.load(Ordering::Relaxed)
+}
+
+// The time CSR, ticking at the platform's timebase frequency (the SBI lets S-mode read it)
+pub fn time() -> usize {
+    let time: usize;
+    unsafe { asm!("rdtime {}", out(reg) time) };
+    time
+}
+
+// Where the function this gets inlined into will return to, i.e. its caller. Only good as the
+// first thing the function does, before any call of its own overwrites ra, and only in a function
+// marked #[inline(never)]: inlined into its caller it would report the caller's caller instead
+#[inline(always)]
+pub fn return_address() -> usize {
+    let address: usize;
+    unsafe { asm!("mv {}, ra", out(reg) address) };
+    address
+}
+
+// sstatus.SIE, supervisor interrupts on this hart
+const SSTATUS_SIE: usize = 0b1 << 1;
+
+// Turn this hart's interrupts off, handing back whether they were on for restore_interrupts
+pub fn interrupts_off() -> bool {
+    let sstatus: usize;
+    unsafe { asm!("csrrci {}, sstatus, 2", out(reg) sstatus) };
+    sstatus & SSTATUS_SIE != 0
+}
+
+pub fn restore_interrupts(were_on: bool) {
+    if were_on {
+        unsafe { asm!("csrsi sstatus, 2") };
+    }
+}
+
+pub fn sstatus() -> usize {
+    let sstatus: usize;
+    unsafe { asm!("csrr {}, sstatus", out(reg) sstatus) };
+    sstatus
+}
+
+pub fn set_sstatus(sstatus: usize) {
+    unsafe { asm!("csrw sstatus, {}", in(reg) sstatus) };
+}
+
+// Sleep until an interrupt is pending. Pending counts even with interrupts off, so this won't
+// sleep through one that came in while they were
+pub fn wait_for_interrupt() {
+    unsafe { asm!("wfi") };
+}
+
+// The SBI hart state management extension, how a supervisor kernel wakes up the other harts
+const SBI_EXT_HSM: usize = 0x48534d;
+const SBI_HSM_HART_START: usize = 0;
+const SBI_HSM_HART_STOP: usize = 1;
+const SBI_HSM_HART_GET_STATUS: usize = 2;
+// what hart_get_status hands back for a hart that is parked in the SBI
+pub const HART_STOPPED: usize = 1;
+
+// Make an SBI call, giving back (error, value). error 0 is success
+pub(crate) fn sbi_call(extension: usize, function: usize, arg0: usize, arg1: usize, arg2: usize) -> (isize, usize) {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        asm!("ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") function,
+            in("a7") extension);
+    }
+    (error, value)
+}
+
+unsafe extern "C" {
+    // boot.S, sets up tp and sp for a hart the SBI just started and calls secondary_main
+    fn _secondary_start();
+}
+
+// What each hart started with start_hart should run
+static HART_ENTRY: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+// Wake a stopped hart up running entry(hart id) on the given stack. When entry returns the hart
+// stops again, so it can be started with something else later. Gives back the SBI error on failure
+// (e.g. the hart doesn't exist or is already running)
+pub fn start_hart(hart: usize, entry: fn(usize), stack_top: usize) -> Result<(), isize> {
+    if hart >= MAX_HARTS {
+        return Err(-3);
+    }
+    HART_ENTRY[hart].store(entry as usize, Ordering::Release);
+    let (error, _) = sbi_call(SBI_EXT_HSM, SBI_HSM_HART_START, hart, _secondary_start as *const () as usize, stack_top);
+    if error != 0 {
+        return Err(error);
+    }
+    Ok(())
+}
+
+// The SBI's idea of the hart's state (HART_STOPPED and so on), None if it doesn't know the hart
+pub fn hart_status(hart: usize) -> Option<usize> {
+    let (error, status) = sbi_call(SBI_EXT_HSM, SBI_HSM_HART_GET_STATUS, hart, 0, 0);
+    if error != 0 {
+        return None;
+    }
+    Some(status)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn secondary_main(hart: usize) -> ! {
+    crate::trap::init();
+    crate::thread::init();
+    crate::timer::init();
+    let entry = HART_ENTRY[hart].load(Ordering::Acquire);
+    if entry != 0 {
+        let entry: fn(usize) = unsafe { core::mem::transmute(entry) };
+        entry(hart);
+    }
+    // nothing should get scheduled here while the hart is parked
+    crate::timer::stop();
+    crate::thread::stop();
+    sbi_call(SBI_EXT_HSM, SBI_HSM_HART_STOP, 0, 0, 0);
+    // hart_stop doesn't come back when it works
+    loop {
+        unsafe { asm!("wfi") };
+    }
+}
@@ -0,0 +1,89 @@
+//! Physically contiguous buffers for devices to DMA into (virtio rings, block buffers...).
+//! The kernel maps RAM one to one, so the kernel virtual address and the physical address
+//! of a buffer are the same number today. Callers should still use the one they mean,
+//! physical_address() for what goes to the device and as_ptr()/as_slice() for what the
+//! kernel touches, so nothing breaks once the kernel moves out of the identity map.
+//! Nothing here touches caches: the harts we run on keep DMA coherent, and code talking
+//! to devices should treat the memory as shared with the device (volatile accesses, fences)
+use crate::page::{self, PAGE_SIZE};
+use crate::page_box::PageRange;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    // alignment has to be a power of two (anything under a page gets a page)
+    InvalidAlignment,
+    // boundary has to be a power of two no smaller than the buffer, or 0 for none
+    InvalidBoundary,
+    // no run of free pages meets the constraints
+    OutOfMemory,
+}
+
+pub struct DmaBuffer {
+    range: PageRange,
+    size: usize,
+}
+
+impl DmaBuffer {
+    // Zeroed buffer of at least size bytes starting at a multiple of alignment
+    pub fn new(size: usize, alignment: usize) -> Result<Self, DmaError> {
+        DmaBuffer::with_boundary(size, alignment, 0)
+    }
+
+    // Same as new but the buffer also won't cross a multiple of boundary, for devices that
+    // can't DMA across e.g. a 64 KiB line
+    pub fn with_boundary(size: usize, alignment: usize, boundary: usize) -> Result<Self, DmaError> {
+        if !alignment.is_power_of_two() {
+            return Err(DmaError::InvalidAlignment);
+        }
+        let pages = page::align_value(size.max(1), 12) / PAGE_SIZE;
+        if boundary != 0 && (!boundary.is_power_of_two() || boundary < pages * PAGE_SIZE) {
+            return Err(DmaError::InvalidBoundary);
+        }
+        let pointer = page::alloc_constrained(pages, alignment.max(PAGE_SIZE), boundary);
+        if pointer.is_null() {
+            return Err(DmaError::OutOfMemory);
+        }
+        let range = unsafe { PageRange::from_raw(pointer, pages) };
+        // the device may read the buffer before we write all of it, don't leak old data to it
+        let big_pointer = pointer as *mut u64;
+        for i in 0..pages * PAGE_SIZE / 8 {
+            unsafe { big_pointer.add(i).write_volatile(0) };
+        }
+        Ok(DmaBuffer { range, size })
+    }
+
+    // Address to hand to the device
+    pub fn physical_address(&self) -> usize {
+        self.range.address()
+    }
+
+    // Address the kernel reads and writes the buffer through
+    pub fn virtual_address(&self) -> usize {
+        self.range.address()
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.range.as_ptr()
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    // How many pages back the buffer, the tail past len() is usable padding
+    pub fn pages(&self) -> usize {
+        self.range.pages()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.range.as_slice()[..self.size]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.range.as_mut_slice()[..self.size]
+    }
+}
@@ -0,0 +1,328 @@
+//! ELF64 loader for user programs.
+//! Only what it takes to start a statically linked RISC-V executable: the file header, the
+//! program headers and the PT_LOAD segments they describe. Sections, symbols and relocations
+//! are never looked at. Every segment goes into fresh pages of the process with the permissions
+//! its flags ask for, and the bss past the end of the file data is just the zeroed rest of those
+//! pages.
+//! exec builds the whole process: the segments, a stack at STACK_TOP with argc, argv, envp and
+//! auxv laid out the way the RISC-V Linux ABI has them, and the entry point. Everything about the
+//! image gets checked before it's used, a broken file is an error and never a kernel fault.
+//! Until there's a filesystem, programs live in the kernel image and get looked up by name
+use crate::address_space::MapError;
+use crate::page::{self, PageTableEntryBits, PAGE_SIZE};
+use crate::process::{Process, A0, A1, A2, HEAP_START, SP, STACK_TOP};
+
+// e_ident
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+// e_type
+const TYPE_EXECUTABLE: u16 = 2;
+// e_machine
+const MACHINE_RISCV: u16 = 243;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+// p_type
+pub const PT_LOAD: u32 = 1;
+pub const PT_PHDR: u32 = 6;
+// p_flags
+pub const PF_X: u32 = 0b1;
+pub const PF_W: u32 = 0b1 << 1;
+pub const PF_R: u32 = 0b1 << 2;
+
+// auxv keys
+pub const AT_NULL: usize = 0;
+pub const AT_PHDR: usize = 3;
+pub const AT_PHENT: usize = 4;
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_ENTRY: usize = 9;
+
+// How big a process's stack can grow, backed a page at a time as it's touched
+pub const STACK_SIZE: usize = 64 * PAGE_SIZE;
+// The most the argument and environment strings plus their pointers can take up on the stack
+pub const ARGUMENTS_MAX: usize = 4 * PAGE_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    // shorter than a header, or a header or segment points past the end of the image
+    Truncated,
+    // no \x7fELF at the start
+    BadMagic,
+    // not a 64 bit little endian RISC-V executable
+    Unsupported,
+    // a program header that makes no sense: file size over memory size, an address outside
+    // where programs go, a segment with no permissions
+    BadSegment,
+    // no PT_LOAD segments, or an entry point none of them covers
+    NoEntry,
+    // argv and envp don't fit in ARGUMENTS_MAX
+    ArgumentsTooLong,
+    Map(MapError),
+}
+
+impl From<MapError> for ElfError {
+    fn from(error: MapError) -> Self {
+        ElfError::Map(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: usize,
+    pub virtual_address: usize,
+    pub file_size: usize,
+    pub memory_size: usize,
+}
+
+// A checked view of an ELF image, parse has made sure every program header is inside it
+pub struct Elf<'a> {
+    image: &'a [u8],
+    entry: usize,
+    program_headers: usize,
+    program_header_count: usize,
+}
+
+// Little endian fields at a byte offset, the image has no alignment guarantees
+fn read_u16(image: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([image[offset], image[offset + 1]])
+}
+
+fn read_u32(image: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&image[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(image: &[u8], offset: usize) -> usize {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&image[offset..offset + 8]);
+    u64::from_le_bytes(bytes) as usize
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(image: &'a [u8]) -> Result<Self, ElfError> {
+        if image.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if image[..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if image[4] != CLASS_64 || image[5] != DATA_LITTLE_ENDIAN
+            || read_u16(image, 16) != TYPE_EXECUTABLE || read_u16(image, 18) != MACHINE_RISCV {
+            return Err(ElfError::Unsupported);
+        }
+        let entry = read_u64(image, 24);
+        let program_headers = read_u64(image, 32);
+        let entry_size = read_u16(image, 54) as usize;
+        let program_header_count = read_u16(image, 56) as usize;
+        if program_header_count != 0 && entry_size != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::Unsupported);
+        }
+        let end = program_header_count.checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(program_headers));
+        if end.is_none_or(|end| end > image.len()) {
+            return Err(ElfError::Truncated);
+        }
+        Ok(Elf { image, entry, program_headers, program_header_count })
+    }
+
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.program_header_count).map(|i| {
+            let at = self.program_headers + i * PROGRAM_HEADER_SIZE;
+            ProgramHeader {
+                kind: read_u32(self.image, at),
+                flags: read_u32(self.image, at + 4),
+                offset: read_u64(self.image, at + 8),
+                virtual_address: read_u64(self.image, at + 16),
+                file_size: read_u64(self.image, at + 32),
+                memory_size: read_u64(self.image, at + 40),
+            }
+        })
+    }
+
+    // Where the program headers end up in the process, for AT_PHDR. Either there's a PT_PHDR
+    // saying so, or they're inside the file data of a PT_LOAD
+    fn program_headers_address(&self) -> Option<usize> {
+        if let Some(header) = self.program_headers().find(|header| header.kind == PT_PHDR) {
+            return Some(header.virtual_address);
+        }
+        self.program_headers()
+            .find(|header| header.kind == PT_LOAD && header.offset <= self.program_headers
+                && self.program_headers - header.offset < header.file_size)
+            .map(|header| header.virtual_address + (self.program_headers - header.offset))
+    }
+}
+
+// The bits of a page's flags that load merges when segments share it
+const PERMISSIONS: i64 = PageTableEntryBits::UserReadWriteExecute.as_i64();
+// set on every page load allocates, and on none of the kernel's that a process has mapped
+const LOADED: i64 = PageTableEntryBits::User.as_i64() | PageTableEntryBits::Owned.as_i64();
+
+// Page table bits for a segment's p_flags. RISC-V has no write only pages, so W brings R along
+fn segment_bits(flags: u32) -> Option<i64> {
+    let mut bits = PageTableEntryBits::User.as_i64();
+    if flags & (PF_R | PF_W) != 0 {
+        bits |= PageTableEntryBits::Read.as_i64();
+    }
+    if flags & PF_W != 0 {
+        bits |= PageTableEntryBits::Write.as_i64();
+    }
+    if flags & PF_X != 0 {
+        bits |= PageTableEntryBits::Execute.as_i64();
+    }
+    if flags & (PF_R | PF_W | PF_X) == 0 {
+        return None;
+    }
+    Some(bits)
+}
+
+// Map every PT_LOAD segment into the process. Segments don't have to start on a page boundary,
+// and when two of them share a page it gets the permissions of both
+pub fn load(process: &mut Process, elf: &Elf) -> Result<(), ElfError> {
+    let mut entry_covered = false;
+    for header in elf.program_headers().filter(|header| header.kind == PT_LOAD) {
+        let bits = segment_bits(header.flags).ok_or(ElfError::BadSegment)?;
+        let file_end = header.offset.checked_add(header.file_size).ok_or(ElfError::Truncated)?;
+        if file_end > elf.image.len() {
+            return Err(ElfError::Truncated);
+        }
+        let end = header.virtual_address.checked_add(header.memory_size).ok_or(ElfError::BadSegment)?;
+        // the heap, mmap and the stack all live above HEAP_START
+        if header.file_size > header.memory_size || header.virtual_address < PAGE_SIZE || end > HEAP_START {
+            return Err(ElfError::BadSegment);
+        }
+        if header.flags & PF_X != 0 && (header.virtual_address..end).contains(&elf.entry) {
+            entry_covered = true;
+        }
+        let space = process.space();
+        let first = header.virtual_address & !(PAGE_SIZE - 1);
+        for address in (first..page::align_value(end, 12)).step_by(PAGE_SIZE) {
+            match space.flags(address) {
+                // a page an earlier segment allocated, anything else already there belongs to
+                // the kernel (the trampoline) and the program doesn't get to touch it
+                Some(flags) if flags & LOADED == LOADED => space.protect(address, (flags & PERMISSIONS) | bits)?,
+                Some(_) => return Err(ElfError::BadSegment),
+                None => {
+                    space.allocate(address, bits)?;
+                }
+            }
+        }
+        // the pages are all there now, copy the file data in a page at a time
+        let data = &elf.image[header.offset..file_end];
+        let mut copied = 0;
+        while copied < data.len() {
+            let address = header.virtual_address + copied;
+            let piece = (PAGE_SIZE - address % PAGE_SIZE).min(data.len() - copied);
+            let physical_address = space.translate(address).ok_or(MapError::NotMapped)?;
+            // the kernel runs on physical addresses
+            unsafe { core::ptr::copy_nonoverlapping(data[copied..].as_ptr(), physical_address as *mut u8, piece) };
+            copied += piece;
+        }
+    }
+    if !entry_covered {
+        return Err(ElfError::NoEntry);
+    }
+    process.set_entry(elf.entry);
+    Ok(())
+}
+
+// Lay out argc, argv, envp and auxv on the process's stack, the strings above them and sp
+// pointing at argc:
+//   sp -> argc, argv[0..argc], 0, envp[..], 0, (key, value) pairs, AT_NULL, 0, then the strings
+// a0, a1 and a2 get argc, argv and envp as well, so a runtime doesn't have to dig them out
+fn push_arguments(process: &mut Process, args: &[&str], env: &[&str], auxv: &[(usize, usize)]) -> Result<(), ElfError> {
+    let strings: usize = args.iter().chain(env).map(|string| string.len() + 1).sum();
+    let words = 1 + args.len() + 1 + env.len() + 1 + 2 * (auxv.len() + 1);
+    if strings + words * 8 > ARGUMENTS_MAX {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+    let mut string_address = STACK_TOP - strings;
+    // the ABI wants sp 16 byte aligned on entry
+    let sp = (string_address - words * 8) & !0xf;
+    let mut vector = alloc::vec::Vec::with_capacity(words);
+    vector.push(args.len());
+    for list in [args, env] {
+        for string in list {
+            process.copy_to_user(string_address, string.as_bytes())?;
+            process.copy_to_user(string_address + string.len(), &[0])?;
+            vector.push(string_address);
+            string_address += string.len() + 1;
+        }
+        vector.push(0);
+    }
+    for (key, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        vector.push(*key);
+        vector.push(*value);
+    }
+    for (i, word) in vector.iter().enumerate() {
+        process.copy_to_user(sp + i * 8, &word.to_le_bytes())?;
+    }
+    let frame = process.frame();
+    frame.regs[SP] = sp;
+    frame.regs[A0] = args.len();
+    frame.regs[A1] = sp + 8;
+    frame.regs[A2] = sp + 8 * (args.len() + 2);
+    Ok(())
+}
+
+// A new process running the image, ready for Process::run
+pub fn exec(image: &[u8], args: &[&str], env: &[&str]) -> Result<Process, ElfError> {
+    let elf = Elf::parse(image)?;
+    let mut process = Process::new()?;
+    load(&mut process, &elf)?;
+    process.reserve_stack(STACK_TOP, STACK_SIZE)?;
+    let mut auxv = alloc::vec![
+        (AT_PHENT, PROGRAM_HEADER_SIZE),
+        (AT_PHNUM, elf.program_header_count),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, elf.entry),
+    ];
+    if let Some(address) = elf.program_headers_address() {
+        auxv.push((AT_PHDR, address));
+    }
+    push_arguments(&mut process, args, env, &auxv)?;
+    Ok(process)
+}
+
+// A program built into the kernel image
+pub struct Program {
+    pub name: &'static str,
+    pub image: &'static [u8],
+}
+
+// The programs in user/src/bin, build.rs builds them and points USER_PROGRAMS at them
+macro_rules! user_program {
+    ($name:literal) => {
+        Program { name: $name, image: include_bytes!(concat!(env!("USER_PROGRAMS"), "/", $name)) }
+    };
+}
+
+// Everything exec_program can start
+pub static PROGRAMS: &[Program] = &[
+    user_program!("hello"),
+    user_program!("echo"),
+    user_program!("memstress"),
+    user_program!("spin"),
+    user_program!("family"),
+];
+
+pub fn find_program(name: &str) -> Option<&'static Program> {
+    PROGRAMS.iter().find(|program| program.name == name)
+}
+
+// exec one of PROGRAMS by name, argv[0] is the name
+pub fn exec_program(name: &str, args: &[&str], env: &[&str]) -> Option<Result<Process, ElfError>> {
+    let program = find_program(name)?;
+    let mut argv = alloc::vec![name];
+    argv.extend_from_slice(args);
+    Some(exec(program.image, &argv, env))
+}
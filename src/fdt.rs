@@ -0,0 +1,224 @@
+//! Flattened device tree parsing.
+//! The bootloader hands us a DTB in a1 (boot.S keeps it in BOOT_DTB). This reads just enough of
+//! the format (devicetree spec, chapter 5) to walk the structure block and pull properties out of
+//! it: where the RAM banks are and what's reserved in them for page::init, and the cpu timebase.
+//! Everything is big endian, tokens and property values are padded out to 4 bytes
+use core::mem::size_of;
+
+unsafe extern "C" {
+    static BOOT_DTB: usize;
+}
+
+const MAGIC: u32 = 0xd00dfeed;
+// the oldest layout with every header field we read
+const LAST_COMPATIBLE_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+
+// structure block tokens
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const NOP: u32 = 4;
+const END: u32 = 9;
+
+// How deep walk follows the tree, real ones stop at 4 or 5
+pub const MAX_DEPTH: usize = 16;
+
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+    reservations: &'a [u8],
+}
+
+// A node while walk is inside it: the names from the root ("") down to it, and how many cells an
+// address and a size take up in its reg (its parent's #address-cells and #size-cells)
+pub struct Node<'n> {
+    pub path: &'n [&'n str],
+    pub address_cells: usize,
+    pub size_cells: usize,
+}
+
+impl Node<'_> {
+    pub fn name(&self) -> &str {
+        self.path.last().copied().unwrap_or("")
+    }
+
+    // Whether the node sits at path ("/cpus", "/reserved-memory"). Components without a unit
+    // address match any, so "/memory" is also "/memory@80000000"
+    pub fn is(&self, path: &str) -> bool {
+        let mut components = path.trim_end_matches('/').split('/');
+        self.path.iter().all(|name| components.next().is_some_and(|component| matches(component, name)))
+            && components.next().is_none()
+    }
+}
+
+fn matches(component: &str, name: &str) -> bool {
+    name == component || (!component.contains('@') && name.split('@').next() == Some(component))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let word = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some((read_u32(bytes, offset)? as u64) << 32 | read_u32(bytes, offset + 4)? as u64)
+}
+
+// The nul terminated string at offset
+fn read_string(bytes: &[u8], offset: usize) -> Option<&str> {
+    let rest = bytes.get(offset..)?;
+    let length = rest.iter().position(|&byte| byte == 0)?;
+    core::str::from_utf8(&rest[..length]).ok()
+}
+
+const fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+// A property holding a single number, either one cell or two
+pub fn read_number(value: &[u8]) -> Option<u64> {
+    match value.len() {
+        4 => read_u32(value, 0).map(u64::from),
+        8 => read_u64(value, 0),
+        _ => None,
+    }
+}
+
+// A number spread over some cells, most significant first. Anything past 64 bits gets shifted out
+fn read_cells(value: &[u8], cells: usize) -> u64 {
+    (0..cells).fold(0, |number, cell| number << 32 | read_u32(value, cell * 4).unwrap_or(0) as u64)
+}
+
+// The (address, size) pairs in a node's reg property
+pub fn reg<'v>(node: &Node, value: &'v [u8]) -> impl Iterator<Item = (usize, usize)> + 'v {
+    let (address_cells, size_cells) = (node.address_cells, node.size_cells);
+    // chunks_exact can't do 0, and a reg with no cells has nothing to say anyway
+    let entry = (address_cells + size_cells).max(1) * 4;
+    value.chunks_exact(entry)
+        .filter(move |_| address_cells + size_cells != 0)
+        .map(move |chunk| {
+            let address = read_cells(chunk, address_cells);
+            let size = read_cells(&chunk[address_cells * 4..], size_cells);
+            (address as usize, size as usize)
+        })
+}
+
+impl<'a> Fdt<'a> {
+    // Check the header and find the blocks, None if it isn't a DTB we can read
+    pub fn new(blob: &'a [u8]) -> Option<Self> {
+        if read_u32(blob, 0)? != MAGIC {
+            return None;
+        }
+        let size = read_u32(blob, 4)? as usize;
+        let blob = blob.get(..size)?;
+        if size < HEADER_SIZE || read_u32(blob, 24)? < LAST_COMPATIBLE_VERSION {
+            return None;
+        }
+        let field = |offset| read_u32(blob, offset).map(|value| value as usize);
+        let structs_offset = field(8)?;
+        let strings_offset = field(12)?;
+        let reservations_offset = field(16)?;
+        let strings = blob.get(strings_offset..strings_offset.checked_add(field(32)?)?)?;
+        let structs = blob.get(structs_offset..structs_offset.checked_add(field(36)?)?)?;
+        let reservations = blob.get(reservations_offset..)?;
+        Some(Fdt { blob, structs, strings, reservations })
+    }
+
+    // The DTB at a physical address. It has to stay put for as long as the Fdt is around
+    unsafe fn from_address(address: usize) -> Option<Fdt<'static>> {
+        if address == 0 || !address.is_multiple_of(size_of::<u32>()) {
+            return None;
+        }
+        let header = unsafe { core::slice::from_raw_parts(address as *const u8, HEADER_SIZE) };
+        if read_u32(header, 0)? != MAGIC {
+            return None;
+        }
+        let size = read_u32(header, 4)? as usize;
+        Fdt::new(unsafe { core::slice::from_raw_parts(address as *const u8, size) })
+    }
+
+    pub fn address(&self) -> usize {
+        self.blob.as_ptr() as usize
+    }
+
+    pub fn size(&self) -> usize {
+        self.blob.len()
+    }
+
+    // The memory reservation block: (address, size) pairs up to the one that's all zero
+    pub fn reservations(&self) -> impl Iterator<Item = (usize, usize)> + 'a {
+        let reservations = self.reservations;
+        (0..).map_while(move |entry| {
+            let address = read_u64(reservations, entry * 16)?;
+            let size = read_u64(reservations, entry * 16 + 8)?;
+            (address != 0 || size != 0).then_some((address as usize, size as usize))
+        })
+    }
+
+    // Call f with every property in the tree, along with the node it belongs to. A malformed
+    // structure block just ends the walk early
+    pub fn walk(&self, mut f: impl FnMut(&Node, &'a str, &'a [u8])) {
+        self.walk_until(&mut f);
+    }
+
+    fn walk_until(&self, f: &mut impl FnMut(&Node, &'a str, &'a [u8])) -> Option<()> {
+        let mut path = [""; MAX_DEPTH];
+        // the #address-cells and #size-cells each open node sets for its children
+        let mut cells = [(2, 1); MAX_DEPTH];
+        let mut depth = 0;
+        let mut offset = 0;
+        loop {
+            let token = read_u32(self.structs, offset)?;
+            offset += 4;
+            match token {
+                BEGIN_NODE => {
+                    let name = read_string(self.structs, offset)?;
+                    offset = align4(offset + name.len() + 1);
+                    if depth == MAX_DEPTH {
+                        return None;
+                    }
+                    path[depth] = name;
+                    // the spec's defaults
+                    cells[depth] = (2, 1);
+                    depth += 1;
+                }
+                END_NODE => depth = depth.checked_sub(1)?,
+                PROP => {
+                    let length = read_u32(self.structs, offset)? as usize;
+                    let name = read_string(self.strings, read_u32(self.structs, offset + 4)? as usize)?;
+                    let value = self.structs.get(offset + 8..offset + 8 + length)?;
+                    offset = align4(offset + 8 + length);
+                    let current = depth.checked_sub(1)?;
+                    match name {
+                        "#address-cells" => cells[current].0 = read_u32(value, 0)? as usize,
+                        "#size-cells" => cells[current].1 = read_u32(value, 0)? as usize,
+                        _ => {}
+                    }
+                    let (address_cells, size_cells) = current.checked_sub(1).map_or((2, 1), |parent| cells[parent]);
+                    f(&Node { path: &path[..depth], address_cells, size_cells }, name, value);
+                }
+                NOP => {}
+                END => return Some(()),
+                _ => return None,
+            }
+        }
+    }
+
+    // The value of one property of the node at path, see Node::is for how path matches
+    pub fn property(&self, path: &str, name: &str) -> Option<&'a [u8]> {
+        let mut found = None;
+        self.walk(|node, property, value| {
+            if found.is_none() && property == name && node.is(path) {
+                found = Some(value);
+            }
+        });
+        found
+    }
+}
+
+// The DTB the bootloader passed in, if it passed one we can read
+pub fn boot() -> Option<Fdt<'static>> {
+    unsafe { Fdt::from_address(BOOT_DTB) }
+}
@@ -1,10 +1,30 @@
 #![no_std]
 
+extern crate alloc;
+
 pub mod uart;
+pub mod console;
 pub mod page;
+pub mod fdt;
+pub mod address_space;
+pub mod pgdump;
+pub mod trap;
+pub mod tlb;
+pub mod cpu;
+pub mod stack;
+pub mod thread;
+pub mod timer;
+pub mod process;
+pub mod syscall;
+pub mod elf;
+pub mod dma;
+pub mod page_box;
+pub mod slab;
+pub mod sync;
 pub mod linear_allocator;
 pub mod shmage;
 pub mod malloc;
+pub mod alloctrace;
 // pub mod test;
 
 #[macro_export]
@@ -68,8 +88,7 @@ pub fn mmio_read(address: usize, offset: usize) -> u8 {
 pub extern "C" fn abort() -> !{
     loop {
         // process waits for some interrupt indefinitely on abort
-        use core::arch::asm;
-        unsafe {asm!("wfi")};
+        cpu::wait_for_interrupt();
     }
 }
 
@@ -131,14 +150,17 @@ pub fn test_alloc() {
     page::init();
     println!("[ok]");
     println!("allocating some pages");
-    let page = page::zalloc(1);
+    let page = page_box::PageRange::zeroed(1).unwrap();
+    let table = page_box::PageBox::<page::PageTable>::table().unwrap();
     let mut alloc_start: *mut u8;
-    alloc_start = page::alloc(1);
     for i in (1..32704).step_by(1000) {
         alloc_start = page::alloc(1000);
     }
     page::print_page_allocations();
     println!("[ok]");
+    // these two give their pages back on their own
+    drop(page);
+    drop(table);
     println!("deallocating all the pages (i think)");
     page::deallocate_all_pages();
     page::print_page_allocations();
@@ -146,6 +168,1600 @@ pub fn test_alloc() {
 ;
 }
 
+// Build a multi level table by hand and walk it back through translate
+pub fn test_address_space() {
+    use address_space::{AddressSpace, MapError};
+    use page::PageTableEntryBits;
+    println!("running test test_address_space:");
+    page::init();
+    let pages_before = page::allocated_pages();
+    {
+        let mut space = AddressSpace::new().unwrap();
+        let frame = page::zalloc(1) as usize;
+        let read_write = PageTableEntryBits::ReadWrite.as_i64();
+        // a 4 KiB page needs the root plus two more levels of tables
+        space.map(0x4000_1000, frame, read_write, 0).unwrap();
+        assert!(space.translate(0x4000_1234) == Some(frame + 0x234));
+        assert!(space.translate(0x4000_2000).is_none());
+        assert!(space.map(0x4000_1000, frame, read_write, 0) == Err(MapError::Overlap));
+        assert!(space.map(0x4000_1010, frame, read_write, 0) == Err(MapError::Misaligned));
+        assert!(space.map(0x4000_3000, frame, 0, 0) == Err(MapError::InvalidBits));
+        assert!(space.map(0x4000_3000, frame, read_write, 3) == Err(MapError::InvalidLevel));
+        // a 2 MiB page in a different gigabyte, and a 4 KiB page can't go inside it
+        space.map(0x8020_0000, 0x8040_0000, read_write, 1).unwrap();
+        assert!(space.translate(0x8021_2345) == Some(0x8041_2345));
+        assert!(space.map(0x8020_1000, frame, read_write, 0) == Err(MapError::Overlap));
+        // the 2 MiB slot holding the 4 KiB table can't take a huge page either
+        assert!(space.map(0x4000_0000, 0x8040_0000, read_write, 1) == Err(MapError::Overlap));
+        // protect only changes pages the space owns, not ones somebody else mapped in
+        assert!(space.protect(0x4000_1000, PageTableEntryBits::ReadExecute.as_i64()) == Err(MapError::NotOwned));
+        assert!(space.flags(0x4000_1000).unwrap() & PageTableEntryBits::ReadWriteExecute.as_i64() == read_write);
+        let owned = space.allocate(0x4000_5000, read_write).unwrap();
+        space.protect(0x4000_5000, PageTableEntryBits::ReadExecute.as_i64()).unwrap();
+        let flags = space.flags(0x4000_5000).unwrap();
+        assert!(flags & PageTableEntryBits::Execute.as_i64() != 0);
+        assert!(flags & PageTableEntryBits::Write.as_i64() == 0);
+        assert!(space.translate(0x4000_5000) == Some(owned));
+        assert!(space.protect(0x5000_0000, read_write) == Err(MapError::NotMapped));
+        assert!(space.unmap(0x4000_1000) == Ok(frame));
+        assert!(space.translate(0x4000_1000).is_none());
+        assert!(space.unmap(0x4000_1000) == Err(MapError::NotMapped));
+        space.map_range(0x1000_0000, frame, 3 * page::PAGE_SIZE, read_write).unwrap();
+        assert!(space.translate(0x1000_2008) == Some(frame + 0x2008));
+        page::dealloc(frame as *mut u8);
+    }
+    // dropping the address space hands every table back to the page allocator
+    assert!(page::allocated_pages() == pages_before);
+    println!("[ok]");
+}
+
+// Neighbouring pages with the same flags should come out of a dump as a single range
+pub fn test_pgdump() {
+    use address_space::AddressSpace;
+    use page::PageTableEntryBits;
+    println!("running test test_pgdump:");
+    page::init();
+    let mut space = AddressSpace::new().unwrap();
+    let read_write = PageTableEntryBits::ReadWrite.as_i64();
+    space.map_range(0x1000_0000, 0x8000_0000, 3 * page::PAGE_SIZE, read_write).unwrap();
+    space.map(0x1000_3000, 0x8000_3000, PageTableEntryBits::ReadExecute.as_i64(), 0).unwrap();
+    space.map(0x4000_0000, 0x8020_0000, read_write, 1).unwrap();
+    let before = pgdump::PageTableSnapshot::take(space.root());
+    assert!(before.ranges().len() == 3);
+    assert!(before.ranges()[0].size == 3 * page::PAGE_SIZE);
+    assert!(before.ranges()[2].level == 1);
+    space.unmap(0x1000_3000).unwrap();
+    let after = pgdump::PageTableSnapshot::take(space.root());
+    assert!(after.ranges().len() == 2);
+    pgdump::print_diff(&before, &after);
+    println!("[ok]");
+}
+
+// Fork an address space and make sure stores from either side only ever touch their own copy
+pub fn test_copy_on_write() {
+    use address_space::AddressSpace;
+    use page::PageTableEntryBits;
+    println!("running test test_copy_on_write:");
+    page::init();
+    let pages_before = page::allocated_pages();
+    {
+        let mut parent = AddressSpace::new().unwrap();
+        let original = parent.allocate(0x1000_0000, PageTableEntryBits::UserReadWrite.as_i64()).unwrap();
+        unsafe { (original as *mut u64).write_volatile(0x5348_4d41_4745) };
+        let mut child = parent.fork().unwrap();
+        // both sides share the page read only until someone writes
+        assert!(child.translate(0x1000_0000) == Some(original));
+        assert!(page::reference_count(original as *mut u8) == 2);
+        let flags = parent.flags(0x1000_0000).unwrap();
+        assert!(flags & PageTableEntryBits::Write.as_i64() == 0);
+        assert!(flags & PageTableEntryBits::CopyOnWrite.as_i64() != 0);
+        // the child writes first and gets its own copy with the same contents
+        child.handle_store_fault(0x1000_0008).unwrap();
+        let copy = child.translate(0x1000_0000).unwrap();
+        assert!(copy != original);
+        assert!(unsafe { (copy as *const u64).read_volatile() } == 0x5348_4d41_4745);
+        assert!(page::reference_count(original as *mut u8) == 1);
+        // the parent is the last holder, so it just gets write access back
+        parent.handle_store_fault(0x1000_0000).unwrap();
+        assert!(parent.translate(0x1000_0000) == Some(original));
+        assert!(parent.flags(0x1000_0000).unwrap() & PageTableEntryBits::Write.as_i64() != 0);
+        // a read only page that was never copy on write really is a fault
+        parent.allocate(0x2000_0000, PageTableEntryBits::UserReadExecute.as_i64()).unwrap();
+        assert!(parent.handle_store_fault(0x2000_0000) == Err(address_space::MapError::PermissionDenied));
+    }
+    // both spaces gave back their tables and their pages
+    assert!(page::allocated_pages() == pages_before);
+    println!("[ok]");
+}
+
+// Reserved memory should only cost pages once it gets touched
+pub fn test_demand_paging() {
+    use address_space::{Access, AddressSpace, MapError};
+    use page::PageTableEntryBits;
+    println!("running test test_demand_paging:");
+    page::init();
+    let pages_before = page::allocated_pages();
+    {
+        let mut space = AddressSpace::new().unwrap();
+        // a 64 MiB heap only costs the root table up front
+        space.reserve(0x2000_0000, 64 << 20, PageTableEntryBits::UserReadWrite.as_i64()).unwrap();
+        assert!(page::allocated_pages() == pages_before + 1);
+        assert!(space.translate(0x2100_0000).is_none());
+        assert!(space.reserve(0x23ff_f000, 0x2000, PageTableEntryBits::UserReadWrite.as_i64()) == Err(MapError::Overlap));
+        // first touch backs the page with zeroes
+        space.handle_fault(0x2100_0123, Access::Store).unwrap();
+        let physical = space.translate(0x2100_0123).unwrap();
+        assert!(unsafe { (physical as *const u8).read_volatile() } == 0);
+        assert!(space.owned_pages() == 1);
+        // nothing outside the reservation and no executing from a read write heap
+        assert!(space.handle_fault(0x3000_0000, Access::Load) == Err(MapError::NotMapped));
+        assert!(space.handle_fault(0x2000_0000, Access::Execute) == Err(MapError::PermissionDenied));
+        // a fork shares the touched page copy on write and keeps the reservation
+        let mut child = space.fork().unwrap();
+        child.handle_fault(0x2100_0000, Access::Store).unwrap();
+        assert!(child.translate(0x2100_0000) != space.translate(0x2100_0000));
+        child.handle_fault(0x2200_0000, Access::Load).unwrap();
+        assert!(space.translate(0x2200_0000).is_none());
+        assert!(child.owned_pages() == 2);
+        space.unreserve(0x2000_0000).unwrap();
+        assert!(space.owned_pages() == 0);
+        assert!(space.translate(0x2100_0000).is_none());
+        assert!(space.unreserve(0x2000_0000) == Err(MapError::NotMapped));
+        // leaves start out accessed, and dirty when writable
+        let page = space.allocate(0x3000_0000, PageTableEntryBits::UserReadWrite.as_i64()).unwrap();
+        let accessed_dirty = PageTableEntryBits::Access.as_i64() | PageTableEntryBits::Dirty.as_i64();
+        assert!(space.flags(0x3000_0000).unwrap() & accessed_dirty == accessed_dirty);
+        // a fault on a page that already allows the access came from a stale TLB entry, so it
+        // just gets retried
+        space.handle_fault(0x3000_0010, Access::Store).unwrap();
+        assert!(space.translate(0x3000_0000) == Some(page));
+        // and nothing can be reserved over a page that's already there
+        assert!(space.reserve(0x2fff_f000, 0x2000, PageTableEntryBits::UserReadWrite.as_i64()) == Err(MapError::Overlap));
+    }
+    assert!(page::allocated_pages() == pages_before);
+    println!("[ok]");
+}
+
+// ASIDs stick within a generation and two spaces never share one
+pub fn test_asid() {
+    println!("running test test_asid:");
+    println!("asid bits: {}", tlb::asid_bits());
+    let first = tlb::refresh_asid(0);
+    let second = tlb::refresh_asid(0);
+    assert!(first != 0 && second != 0);
+    if tlb::asid_bits() > 1 {
+        assert!(first != second);
+        assert!(tlb::refresh_asid(first) == first);
+    }
+    println!("[ok]");
+}
+
+// Guard pages get registered to the right owner, catch a stack running into them and get
+// forgotten once the stack goes away
+pub fn test_kernel_stack() {
+    use stack::{KernelStack, StackOwner};
+    println!("running test test_kernel_stack:");
+    page::init();
+    let pages_before = page::allocated_pages();
+    let owner = StackOwner { hart: 1, task: 7 };
+    let guard = {
+        let kernel_stack = KernelStack::new(stack::KERNEL_STACK_PAGES, owner).unwrap();
+        assert!(kernel_stack.top() - kernel_stack.bottom() == stack::KERNEL_STACK_PAGES * page::PAGE_SIZE);
+        assert!(kernel_stack.bottom() == kernel_stack.guard() + page::PAGE_SIZE);
+        assert!(stack::guard_owner(kernel_stack.guard() + 0x10) == Some(owner));
+        // the usable stack itself isn't a guard
+        assert!(stack::guard_owner(kernel_stack.bottom()).is_none());
+        // nothing has gone wrong yet
+        assert!(stack::overflowed(kernel_stack.top() - 0x10).is_none());
+        // sp has gone past the bottom
+        assert!(stack::overflowed(kernel_stack.bottom() - 0x10) == Some(owner));
+        // or sp came back up, but a frame below the bottom got written on the way
+        unsafe { ((kernel_stack.bottom() - 0x20) as *mut usize).write_volatile(0) };
+        assert!(stack::overflowed(kernel_stack.top() - 0x10) == Some(owner));
+        kernel_stack.guard()
+    };
+    assert!(stack::guard_owner(guard).is_none());
+    assert!(page::allocated_pages() == pages_before);
+    // a .bss stack, registering it again only moves it to the new owner
+    static mut STATIC_STACK: stack::GuardedStack<{ 2 * page::PAGE_SIZE }> = stack::GuardedStack::new();
+    let static_stack = core::ptr::addr_of_mut!(STATIC_STACK);
+    let top = stack::register_static(static_stack, owner);
+    assert!(top == static_stack as usize + 3 * page::PAGE_SIZE);
+    assert!(stack::guard_owner(static_stack as usize) == Some(owner));
+    assert!(stack::overflowed(top - 0x10).is_none());
+    let moved = StackOwner { hart: 2, task: 9 };
+    stack::register_static(static_stack, moved);
+    assert!(stack::guard_owner(static_stack as usize) == Some(moved));
+    assert!(stack::overflowed(static_stack as usize + 0x10) == Some(moved));
+    println!("[ok]");
+}
+
+// Two banks with a hole in each: nothing reserved gets handed out and nothing straddles the banks
+pub fn test_reserved_regions() {
+    use page::{MemoryRegion, PAGE_SIZE};
+    println!("running test test_reserved_regions:");
+    let base = page::align_value(page::heap().start, 12);
+    let bank_a = MemoryRegion { start: base, size: 64 * PAGE_SIZE };
+    let bank_b = MemoryRegion { start: base + 128 * PAGE_SIZE, size: 64 * PAGE_SIZE };
+    // firmware sits where bank a's descriptors would have gone, so they have to move up past it
+    let firmware = MemoryRegion { start: base, size: 2 * PAGE_SIZE };
+    let mmio_hole = MemoryRegion { start: bank_b.start + 10 * PAGE_SIZE, size: 3 * PAGE_SIZE };
+    page::init_regions(&[bank_a, bank_b], &[firmware, mmio_hole]);
+    assert!(page::total_pages() == 128);
+    // 2 firmware + 3 hole + one page of descriptors per bank
+    assert!(page::reserved_pages() == 7);
+    // bank a has 61 free pages in a row after the firmware and its descriptors, bank b only 51
+    assert!(page::alloc(62).is_null());
+    assert!(page::alloc(61) as usize == base + 3 * PAGE_SIZE);
+    assert!(page::alloc(51) as usize == bank_b.start + 13 * PAGE_SIZE);
+    assert!(page::alloc(1) as usize == bank_b.start + PAGE_SIZE);
+    assert!(page::allocated_pages() == 113);
+    page::deallocate_all_pages();
+    assert!(page::allocated_pages() == 0);
+    assert!(page::reserved_pages() == 7);
+    page::init();
+    println!("[ok]");
+}
+
+// A hand built DTB: the header, memreserve, properties by path, and page::init_from_fdt taking the
+// banks and every kind of reservation out of it
+pub fn test_fdt() {
+    use fdt::Fdt;
+    use page::PAGE_SIZE;
+    println!("running test test_fdt:");
+    page::init();
+    let heap = page::heap();
+    let base = page::align_value(heap.start, 12);
+    const STRINGS: &str = "#address-cells\0#size-cells\0reg\0linux,initrd-start\0linux,initrd-end\0timebase-frequency\0";
+    fn word(blob: &mut [u8], at: &mut usize, value: u32) {
+        blob[*at..*at + 4].copy_from_slice(&value.to_be_bytes());
+        *at += 4;
+    }
+    fn node(blob: &mut [u8], at: &mut usize, name: &str) {
+        word(blob, at, 1);
+        blob[*at..*at + name.len()].copy_from_slice(name.as_bytes());
+        *at = (*at + name.len() + 1 + 3) & !3;
+    }
+    fn prop(blob: &mut [u8], at: &mut usize, name: &str, cells: &[u32]) {
+        word(blob, at, 3);
+        word(blob, at, cells.len() as u32 * 4);
+        word(blob, at, STRINGS.find(name).unwrap() as u32);
+        for &cell in cells {
+            word(blob, at, cell);
+        }
+    }
+    let pair = |start: usize, size: usize| [(start >> 32) as u32, start as u32, (size >> 32) as u32, size as u32];
+    // on the stack, so it isn't anywhere the allocator hands out
+    let mut blob = [0u8; 1024];
+    let at = &mut 40;
+    // memreserve: one page, then the terminator
+    for value in pair(base + 32 * PAGE_SIZE, PAGE_SIZE).into_iter().chain([0; 4]) {
+        word(&mut blob, at, value);
+    }
+    let structs = *at;
+    node(&mut blob, at, "");
+    prop(&mut blob, at, "#address-cells", &[2]);
+    prop(&mut blob, at, "#size-cells", &[2]);
+    node(&mut blob, at, "memory@0");
+    prop(&mut blob, at, "reg", &pair(heap.start, heap.size));
+    word(&mut blob, at, 2);
+    node(&mut blob, at, "reserved-memory");
+    prop(&mut blob, at, "#address-cells", &[2]);
+    prop(&mut blob, at, "#size-cells", &[2]);
+    node(&mut blob, at, "firmware@0");
+    prop(&mut blob, at, "reg", &pair(base, 4 * PAGE_SIZE));
+    word(&mut blob, at, 2);
+    word(&mut blob, at, 2);
+    node(&mut blob, at, "chosen");
+    let initrd = base + 16 * PAGE_SIZE;
+    prop(&mut blob, at, "linux,initrd-start", &pair(initrd, 0)[..2]);
+    prop(&mut blob, at, "linux,initrd-end", &pair(initrd + 2 * PAGE_SIZE, 0)[..2]);
+    word(&mut blob, at, 2);
+    node(&mut blob, at, "cpus");
+    prop(&mut blob, at, "#address-cells", &[1]);
+    prop(&mut blob, at, "#size-cells", &[0]);
+    prop(&mut blob, at, "timebase-frequency", &[24_000_000]);
+    node(&mut blob, at, "cpu@0");
+    prop(&mut blob, at, "reg", &[0]);
+    word(&mut blob, at, 2);
+    word(&mut blob, at, 2);
+    word(&mut blob, at, 2);
+    word(&mut blob, at, 9);
+    let strings = *at;
+    blob[strings..strings + STRINGS.len()].copy_from_slice(STRINGS.as_bytes());
+    let size = strings + STRINGS.len();
+    // magic, size, structure block, strings block, memreserve, version 17, compatible with 16,
+    // boot hart, strings size, structure size
+    for (i, value) in [0xd00dfeed, size, structs, strings, 40, 17, 16, 0, STRINGS.len(), strings - structs].into_iter().enumerate() {
+        blob[i * 4..i * 4 + 4].copy_from_slice(&(value as u32).to_be_bytes());
+    }
+    let fdt = Fdt::new(&blob).unwrap();
+    assert!(fdt.size() == size);
+    let mut reservations = fdt.reservations();
+    assert!(reservations.next() == Some((base + 32 * PAGE_SIZE, PAGE_SIZE)));
+    assert!(reservations.next().is_none());
+    assert!(fdt.property("/cpus", "timebase-frequency").and_then(fdt::read_number) == Some(24_000_000));
+    assert!(fdt.property("/chosen", "linux,initrd-start").and_then(fdt::read_number) == Some(initrd as u64));
+    assert!(fdt.property("/timebase-frequency", "reg").is_none());
+    // cpu@0's reg is in /cpus' cells, one for the address and none for a size
+    let mut cpus = 0;
+    fdt.walk(|node, property, value| {
+        if property == "reg" && node.is("/cpus/cpu") {
+            assert!(node.name() == "cpu@0");
+            assert!(fdt::reg(node, value).eq([(0, 0)]));
+            cpus += 1;
+        }
+    });
+    assert!(cpus == 1);
+    // anything that isn't a DTB gets turned away
+    let mut bad = blob;
+    bad[0] = 0;
+    assert!(Fdt::new(&bad).is_none());
+    assert!(Fdt::new(&blob[..size - 1]).is_none());
+    // the heap bank with only its descriptors taken, then again out of the DTB: 4 firmware pages,
+    // 2 of initrd and the memreserve page come out of it on top
+    page::init_regions(&[heap], &[]);
+    let descriptors = page::reserved_pages();
+    page::init_from_fdt(&fdt);
+    assert!(page::total_pages() == heap.size / PAGE_SIZE);
+    assert!(page::reserved_pages() == descriptors + 7);
+    for _ in 0..page::total_pages() - page::reserved_pages() {
+        let address = page::alloc(1) as usize;
+        assert!(address != 0);
+        assert!(!(base..base + 4 * PAGE_SIZE).contains(&address));
+        assert!(!(initrd..initrd + 2 * PAGE_SIZE).contains(&address));
+        assert!(address != base + 32 * PAGE_SIZE);
+    }
+    assert!(page::alloc(1).is_null());
+    page::init();
+    println!("[ok]");
+}
+
+// DMA buffers land on the alignment and boundaries asked for and free themselves
+pub fn test_dma() {
+    use dma::{DmaBuffer, DmaError};
+    use page::PAGE_SIZE;
+    println!("running test test_dma:");
+    page::init();
+    let pages_before = page::allocated_pages();
+    {
+        // knock the next free page off a 64 KiB line so alignment actually has to skip something
+        let offset = page::alloc(1);
+        let mut ring = DmaBuffer::new(3 * PAGE_SIZE, 0x10000).unwrap();
+        assert!(ring.physical_address().is_multiple_of(0x10000));
+        assert!(ring.pages() == 3);
+        assert!(ring.as_slice().iter().all(|byte| *byte == 0));
+        ring.as_mut_slice()[0] = 0xff;
+        let small = DmaBuffer::with_boundary(100, 8, 2 * PAGE_SIZE).unwrap();
+        assert!(small.len() == 100 && small.pages() == 1);
+        let straddle_free = DmaBuffer::with_boundary(2 * PAGE_SIZE, PAGE_SIZE, 2 * PAGE_SIZE).unwrap();
+        let last_byte = straddle_free.physical_address() + 2 * PAGE_SIZE - 1;
+        assert!(straddle_free.physical_address() / (2 * PAGE_SIZE) == last_byte / (2 * PAGE_SIZE));
+        assert!(DmaBuffer::new(PAGE_SIZE, 3).err() == Some(DmaError::InvalidAlignment));
+        assert!(DmaBuffer::with_boundary(2 * PAGE_SIZE, PAGE_SIZE, PAGE_SIZE).err() == Some(DmaError::InvalidBoundary));
+        page::dealloc(offset);
+    }
+    assert!(page::allocated_pages() == pages_before);
+    println!("[ok]");
+}
+
+// Aligned allocations and realloc growing into a free neighbour instead of moving
+pub fn test_kernel_heap() {
+    println!("running test test_kernel_heap:");
+    page::init();
+    malloc::init();
+    for align in [16, 64, 4096] {
+        let pointer = malloc::kernel_malloc_aligned(24, align);
+        assert!(!pointer.is_null() && (pointer as usize).is_multiple_of(align));
+        malloc::kernel_free(pointer);
+    }
+    let zeroed = malloc::kernel_zmalloc_aligned(100, 256) as *const u8;
+    assert!((0..100).all(|i| unsafe { *zeroed.add(i) } == 0));
+    malloc::kernel_free(zeroed as *mut u8);
+
+    let first = malloc::kernel_malloc(32);
+    let second = malloc::kernel_malloc(32);
+    malloc::kernel_free(second);
+    for i in 0..32 {
+        unsafe { *first.add(i) = i as u8 };
+    }
+    // the chunk after first is free again, so growing stays put
+    assert!(malloc::kernel_realloc(first, 8, 200) == first);
+    // shrinking always stays put
+    assert!(malloc::kernel_realloc(first, 8, 64) == first);
+    let blocker = malloc::kernel_malloc(16);
+    // now the neighbour is taken, so growing has to move and carry the data along
+    let moved = malloc::kernel_realloc(first, 8, 400);
+    assert!(moved != first);
+    assert!((0..32).all(|i| unsafe { *moved.add(i) } == i as u8));
+    malloc::kernel_free(moved);
+    malloc::kernel_free(blocker);
+    // zero bytes still gets a chunk of its own that frees like any other
+    let empty = malloc::kernel_malloc(0);
+    let other = malloc::kernel_malloc(0);
+    assert!(!empty.is_null() && !other.is_null() && empty != other);
+    assert!(malloc::kernel_realloc(empty, 8, 0) == empty);
+    malloc::kernel_free(empty);
+    malloc::kernel_free(other);
+    println!("[ok]");
+}
+
+// Allocating past the first arena grows the heap, freeing it all gives the pages back
+pub fn test_kernel_heap_growth() {
+    println!("running test test_kernel_heap_growth:");
+    use page::PAGE_SIZE;
+    page::init();
+    malloc::init();
+    let pages_before = page::allocated_pages();
+    let heap_pages = malloc::get_number_allocations();
+    // well past the initial arena, and one allocation bigger than it on its own
+    let mut pointers = [core::ptr::null_mut(); 32];
+    for (i, pointer) in pointers.iter_mut().enumerate() {
+        *pointer = malloc::kernel_malloc(3 * PAGE_SIZE);
+        assert!(!pointer.is_null());
+        unsafe { **pointer = i as u8 };
+    }
+    let big = malloc::kernel_malloc_aligned(100 * PAGE_SIZE, PAGE_SIZE);
+    assert!(!big.is_null() && (big as usize).is_multiple_of(PAGE_SIZE));
+    assert!(malloc::number_of_arenas() > 1);
+    assert!(malloc::get_number_allocations() > heap_pages);
+    let grown = malloc::get_number_allocations() - heap_pages;
+    let pages_at_peak = page::allocated_pages();
+    assert!(pages_at_peak >= pages_before + grown);
+    // nothing got handed out twice
+    assert!(pointers.iter().enumerate().all(|(i, pointer)| unsafe { **pointer } == i as u8));
+    malloc::kernel_free(big);
+    for pointer in pointers {
+        malloc::kernel_free(pointer);
+    }
+    assert!(malloc::number_of_arenas() == 1);
+    assert!(malloc::get_number_allocations() == heap_pages);
+    // the arenas went back to the page allocator, and so did the tables malloc built to map them
+    assert!(page::allocated_pages() == pages_before);
+    // a grow that gets its arena but runs out of pages for the kernel table's second level gives
+    // back the arena and the first level table it already made. Take every page but 17 in a row:
+    // 16 for the arena and one for a table
+    let filler = malloc::kernel_malloc(60 * PAGE_SIZE);
+    let mut hogged: *mut usize = core::ptr::null_mut();
+    loop {
+        let page = page::alloc(1) as *mut usize;
+        if page.is_null() {
+            break;
+        }
+        unsafe { *page = hogged as usize };
+        hogged = page;
+    }
+    // the last pages out sit next to each other at the top of the bank
+    for _ in 0..17 {
+        let next = unsafe { *hogged } as *mut usize;
+        page::dealloc(hogged as *mut u8);
+        hogged = next;
+    }
+    let pages_hogged = page::allocated_pages();
+    assert!(malloc::kernel_malloc(8 * PAGE_SIZE).is_null());
+    assert!(page::allocated_pages() == pages_hogged);
+    assert!(malloc::number_of_arenas() == 1);
+    let kernel_root = malloc::get_page_table();
+    assert!(unsafe { (*kernel_root).entries.iter().all(|entry| !entry.is_valid()) });
+    while !hogged.is_null() {
+        let next = unsafe { *hogged } as *mut usize;
+        page::dealloc(hogged as *mut u8);
+        hogged = next;
+    }
+    malloc::kernel_free(filler);
+    assert!(page::allocated_pages() == pages_before);
+    println!("[ok]");
+}
+
+// A named cache with a constructor, and small GlobalAlloc allocations landing in the size classes
+pub fn test_slab() {
+    println!("running test test_slab:");
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use slab::SlabError;
+    page::init();
+    malloc::init();
+    let pages_before = page::allocated_pages();
+    static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+    fn fill(object: *mut u8) {
+        unsafe { core::ptr::write_bytes(object, 0xab, 48) };
+        CONSTRUCTED.fetch_add(1, Ordering::Relaxed);
+    }
+    CONSTRUCTED.store(0, Ordering::Relaxed);
+    let cache = slab::create_cache("test-48", 48, 16, Some(fill)).unwrap();
+    assert!(slab::create_cache("too-big", 8 * page::PAGE_SIZE, 8, None).err() == Some(SlabError::ObjectTooLarge));
+    assert!(slab::create_cache("bad-align", 48, 24, None).err() == Some(SlabError::InvalidAlignment));
+    assert!(slab::create_cache("empty", 0, 8, None).err() == Some(SlabError::ZeroSize));
+    let per_slab = cache.stats().objects_per_slab;
+    let mut objects = [core::ptr::null_mut(); 200];
+    for object in objects.iter_mut() {
+        *object = cache.alloc();
+        assert!(!object.is_null() && (*object as usize).is_multiple_of(16));
+        assert!((0..48).all(|i| unsafe { *object.add(i) } == 0xab));
+    }
+    let stats = cache.stats();
+    assert!(stats.active_objects == 200 && stats.slabs * per_slab >= 200);
+    assert!(page::allocated_pages() == pages_before + stats.slabs);
+    // every object got constructed once, when its slab was carved up
+    assert!(CONSTRUCTED.load(Ordering::Relaxed) == stats.slabs * per_slab);
+    for object in objects {
+        cache.free(object);
+    }
+    // some of them are still sitting in this hart's magazine
+    cache.drain();
+    let stats = cache.stats();
+    // everything but the one spare slab went back
+    assert!(stats.active_objects == 0 && stats.slabs == 1 && stats.frees == 200);
+    assert!(page::allocated_pages() == pages_before + 1);
+    // a freed object comes back out still constructed, without the constructor running again
+    let constructed = CONSTRUCTED.load(Ordering::Relaxed);
+    let object = cache.alloc();
+    assert!((0..48).all(|i| unsafe { *object.add(i) } == 0xab));
+    assert!(CONSTRUCTED.load(Ordering::Relaxed) == constructed);
+    cache.free(object);
+    // big objects get slabs of a few pages instead of leaving most of one page empty
+    let kmalloc_1024 = slab::size_class(1024, 8).unwrap().stats();
+    assert!(kmalloc_1024.objects_per_slab >= slab::MIN_OBJECTS_PER_SLAB && kmalloc_1024.pages_per_slab > 1);
+    let big = slab::create_cache("test-1000", 1000, 8, None).unwrap();
+    let objects: [*mut u8; 20] = core::array::from_fn(|_| big.alloc());
+    // every object has to find its way back to its own slab, which needs them aligned to their size
+    for object in objects {
+        assert!(!object.is_null());
+        big.free(object);
+    }
+    big.drain();
+    assert!(big.stats().slabs == 1);
+
+    // the debug heap takes small allocations itself, see test_heapcheck
+    #[cfg(not(feature = "debug-heap"))]
+    {
+        let kmalloc_64 = slab::size_class(40, 8).unwrap();
+        let allocations = kmalloc_64.stats().allocations;
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let pointer = unsafe { malloc::KernelAllocator.alloc(layout) };
+        assert!(!pointer.is_null() && kmalloc_64.stats().allocations == allocations + 1);
+        // growing inside the size class keeps the object, past it moves to the next class
+        let grown = unsafe { malloc::KernelAllocator.realloc(pointer, layout, 64) };
+        assert!(grown == pointer);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let moved = unsafe { malloc::KernelAllocator.realloc(grown, layout, 100) };
+        assert!(moved != grown && kmalloc_64.stats().frees == 1);
+        unsafe { malloc::KernelAllocator.dealloc(moved, Layout::from_size_align(100, 8).unwrap()) };
+    }
+    // too big for a size class, or more aligned than one, and it comes from the heap
+    assert!(slab::size_class(slab::MAX_SLAB_OBJECT + 1, 8).is_none());
+    assert!(slab::size_class(16, 4096).is_none());
+    println!("[ok]");
+}
+
+// heapcheck on a healthy heap, and with the debug heap, catching writes past the end and after free
+pub fn test_heapcheck() {
+    println!("running test test_heapcheck:");
+    page::init();
+    malloc::init();
+    let first = malloc::kernel_malloc(20);
+    let second = malloc::kernel_malloc(100);
+    let third = malloc::kernel_malloc_aligned(48, 64);
+    malloc::kernel_free(second);
+    assert!(malloc::heapcheck() == 0);
+    #[cfg(feature = "debug-heap")]
+    unsafe {
+        // one byte past the end lands in the red zone
+        *first.add(20) = 0;
+        assert!(malloc::heapcheck() == 1);
+        *first.add(20) = malloc::REDZONE_BYTE;
+        // second sits free between two taken chunks, writing to it spoils its poison
+        *second = 0;
+        assert!(malloc::heapcheck() == 1);
+        *second = malloc::POISON_BYTE;
+        assert!(malloc::heapcheck() == 0);
+        // small allocations through the global allocator land in the heap too, not in a slab
+        use core::alloc::{GlobalAlloc, Layout};
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let small = malloc::KernelAllocator.alloc(layout);
+        *small.add(24) = 0;
+        assert!(malloc::heapcheck() == 1);
+        *small.add(24) = malloc::REDZONE_BYTE;
+        malloc::KernelAllocator.dealloc(small, layout);
+        assert!(malloc::heapcheck() == 0);
+    }
+    malloc::kernel_free(third);
+    malloc::kernel_free(first);
+    assert!(malloc::heapcheck() == 0);
+    println!("[ok]");
+}
+
+// Every hart hammers the global allocator at once, with sizes from both the slabs and the heap.
+// Each hart fills its allocations with its own id, so memory handed out twice shows up as a
+// mismatch when it gets freed
+pub fn test_allocator_stress() {
+    println!("running test test_allocator_stress:");
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use stack::{KernelStack, StackOwner};
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+    static ERRORS: AtomicUsize = AtomicUsize::new(0);
+    fn worker(hart: usize) {
+        const LIVE: usize = 32;
+        let mut live = [(core::ptr::null_mut::<u8>(), Layout::new::<u8>()); LIVE];
+        let mut seed = hart * 7919 + 1;
+        for _ in 0..2000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let slot = (seed >> 33) % LIVE;
+            let (pointer, layout) = live[slot];
+            if pointer.is_null() {
+                let layout = Layout::from_size_align(1 + (seed >> 40) % 3000, 8).unwrap();
+                let pointer = unsafe { malloc::KernelAllocator.alloc(layout) };
+                if pointer.is_null() {
+                    ERRORS.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                unsafe { core::ptr::write_bytes(pointer, hart as u8, layout.size()) };
+                live[slot] = (pointer, layout);
+            } else {
+                if !(0..layout.size()).all(|i| unsafe { *pointer.add(i) } == hart as u8) {
+                    ERRORS.fetch_add(1, Ordering::Relaxed);
+                }
+                unsafe { malloc::KernelAllocator.dealloc(pointer, layout) };
+                live[slot].0 = core::ptr::null_mut();
+            }
+        }
+        for (pointer, layout) in live {
+            if !pointer.is_null() {
+                unsafe { malloc::KernelAllocator.dealloc(pointer, layout) };
+            }
+        }
+        slab::drain_all();
+        FINISHED.fetch_add(1, Ordering::Release);
+    }
+
+    page::init();
+    malloc::init();
+    FINISHED.store(0, Ordering::Relaxed);
+    ERRORS.store(0, Ordering::Relaxed);
+    let me = cpu::hart_id();
+    let mut stacks = [const { None }; cpu::MAX_HARTS];
+    let mut started = 0;
+    for (hart, slot) in stacks.iter_mut().enumerate() {
+        if hart == me {
+            continue;
+        }
+        let stack = KernelStack::new(stack::KERNEL_STACK_PAGES, StackOwner { hart, task: 0 }).unwrap();
+        // harts that don't exist (or are busy) just don't join in
+        if cpu::start_hart(hart, worker, stack.top()).is_ok() {
+            *slot = Some(stack);
+            started += 1;
+        }
+    }
+    worker(me);
+    while FINISHED.load(Ordering::Acquire) < started + 1 {
+        core::hint::spin_loop();
+    }
+    // the other harts have to be off their stacks before those get freed
+    for (hart, slot) in stacks.iter_mut().enumerate() {
+        if let Some(stack) = slot.take() {
+            while cpu::hart_status(hart) != Some(cpu::HART_STOPPED) {
+                core::hint::spin_loop();
+            }
+            drop(stack);
+        }
+    }
+    assert!(ERRORS.load(Ordering::Relaxed) == 0);
+    assert!(malloc::heapcheck() == 0);
+    assert!(malloc::number_of_arenas() == 1);
+    println!("[ok] ({} harts)", started + 1);
+}
+
+// Bump allocation, checkpoints, scopes and reset on an arena over its own pages
+pub fn test_linear_allocator() {
+    println!("running test test_linear_allocator:");
+    use linear_allocator::LinearAllocator;
+    page::init();
+    let pages_before = page::allocated_pages();
+    let mut arena = LinearAllocator::from_pages(2).unwrap();
+    assert!(page::allocated_pages() == pages_before + 2);
+    assert!(arena.capacity() == 2 * page::PAGE_SIZE && arena.used() == 0);
+    let byte = arena.alloc_bytes(1, 1);
+    let aligned = arena.alloc_bytes(8, 64);
+    assert!(!byte.is_null() && (aligned as usize).is_multiple_of(64) && aligned > byte);
+    let checkpoint = arena.checkpoint();
+    let value = arena.alloc_value(0x1234u64).unwrap();
+    assert!(*value == 0x1234);
+    let slice = arena.alloc_slice(&[1u16, 2, 3]).unwrap();
+    assert!(slice == [1, 2, 3]);
+    arena.rollback(checkpoint);
+    assert!(arena.checkpoint() == checkpoint);
+    // a scope gives everything back on the way out
+    let used = arena.used();
+    let inside = arena.scope(|arena| {
+        assert!(!arena.alloc_bytes(100, 8).is_null());
+        arena.used()
+    });
+    assert!(inside > used && arena.used() == used);
+    // too big for what's left
+    assert!(arena.alloc_bytes(arena.remaining() + 1, 1).is_null());
+    arena.reset();
+    assert!(arena.used() == 0 && arena.remaining() == arena.capacity());
+    drop(arena);
+    assert!(page::allocated_pages() == pages_before);
+    println!("[ok]");
+}
+
+pub fn test_collections() {
+    println!("running test test_collections:");
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeMap;
+    use alloc::string::String;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::fmt::Write;
+    page::init();
+    malloc::init();
+    {
+        let mut numbers: Vec<usize> = (0..1000).collect();
+        numbers.retain(|n| n % 3 == 0);
+        assert!(numbers.len() == 334 && numbers[333] == 999);
+        let mut name = String::from("shmage");
+        write!(name, "OS {}", numbers.len()).unwrap();
+        assert!(name == "shmageOS 334");
+        let boxed = Box::new([7u64; 64]);
+        assert!(boxed.iter().all(|n| *n == 7));
+        let shared = Arc::new(name);
+        let other = Arc::clone(&shared);
+        assert!(Arc::strong_count(&shared) == 2 && other.len() == 12);
+        drop(other);
+        assert!(Arc::strong_count(&shared) == 1);
+        let mut map = BTreeMap::new();
+        for n in numbers.iter().rev() {
+            map.insert(*n, *n * 2);
+        }
+        assert!(map.len() == 334 && map[&999] == 1998);
+        assert!(map.keys().next() == Some(&0));
+        // the fallible paths hand back an error instead of taking the kernel down
+        let mut huge: Vec<u8> = Vec::new();
+        assert!(malloc::fallible(|| huge.try_reserve(usize::MAX / 2)).is_err());
+        assert!(malloc::fallible(|| huge.try_reserve(1 << 40)).is_err());
+        assert!(malloc::fallible(|| huge.try_reserve(4096)).is_ok());
+        assert!(malloc::try_push(&mut huge, 1).is_ok() && huge == [1]);
+        let value = malloc::try_box(0xabcdu32).unwrap();
+        assert!(*value == 0xabcd);
+    }
+    assert!(malloc::heapcheck() == 0);
+    println!("[ok]");
+}
+
+pub fn test_alloctrace() {
+    println!("running test test_alloctrace:");
+    use alloctrace::{Event, Record, TRACE_LENGTH};
+    page::init();
+    malloc::init();
+    // nothing gets recorded while it's off
+    alloctrace::disable();
+    let before = alloctrace::recorded();
+    page::dealloc(page::alloc(1));
+    assert!(alloctrace::recorded() == before);
+    alloctrace::enable();
+    assert!(alloctrace::recorded() == 0);
+    // every call goes through a tiny function of its own, which is what the trace should blame
+    #[inline(never)]
+    fn alloc_pages() -> *mut u8 {
+        page::alloc(2)
+    }
+    #[inline(never)]
+    fn malloc_bytes() -> *mut u8 {
+        malloc::kernel_malloc(100)
+    }
+    #[inline(never)]
+    fn realloc_bytes(bytes: *mut u8) -> *mut u8 {
+        malloc::kernel_realloc(bytes, 8, 50)
+    }
+    #[inline(never)]
+    fn free_bytes(bytes: *mut u8) {
+        malloc::kernel_free(bytes)
+    }
+    #[inline(never)]
+    fn dealloc_pages(pages: *mut u8) {
+        page::dealloc(pages)
+    }
+    let pages = alloc_pages();
+    let bytes = malloc_bytes();
+    let smaller = realloc_bytes(bytes);
+    free_bytes(smaller);
+    dealloc_pages(pages);
+    let hart = cpu::hart_id();
+    let mut events = [Record { event: Event::Malloc, address: 0, size: 0, caller: 0, hart: 0, time: 0 }; 8];
+    let mut count = 0;
+    alloctrace::for_each_record(|record| {
+        // the heap might have grown or shrunk on the way, leave those pages out
+        let ours = record.address == pages as usize || record.address == bytes as usize;
+        if ours && record.hart == hart && count < events.len() {
+            events[count] = *record;
+            count += 1;
+        }
+    });
+    assert!(count == 5);
+    assert!(events[0].event == Event::PageAlloc && events[0].size == 2 * page::PAGE_SIZE);
+    assert!(events[1].event == Event::Malloc && events[1].address == bytes as usize && events[1].size == 100);
+    // shrinking always happens in place
+    assert!(smaller == bytes && events[2].event == Event::Realloc && events[2].size == 50);
+    assert!(events[3].event == Event::Free && events[3].size >= 50);
+    assert!(events[4].event == Event::PageDealloc && events[4].size == 2 * page::PAGE_SIZE);
+    // the return address lands just past the call, a few instructions into each one
+    let callers = [alloc_pages as *const () as usize, malloc_bytes as *const () as usize, realloc_bytes as *const () as usize,
+        free_bytes as *const () as usize, dealloc_pages as *const () as usize];
+    assert!(events.iter().zip(callers).all(|(record, caller)| record.caller > caller && record.caller < caller + 64));
+    assert!(events.windows(2).take(count - 1).all(|pair| pair[0].time <= pair[1].time));
+    assert!(alloctrace::Filter::Pages.matches(&events[0]) && !alloctrace::Filter::Heap.matches(&events[0]));
+    // the ring only keeps the newest TRACE_LENGTH events
+    for _ in 0..TRACE_LENGTH {
+        malloc::kernel_free(malloc::kernel_malloc(16));
+    }
+    let mut kept = 0;
+    alloctrace::for_each_record(|_| kept += 1);
+    assert!(kept == TRACE_LENGTH && alloctrace::recorded() > TRACE_LENGTH);
+    alloctrace::disable();
+    println!("[ok]");
+}
+
+pub fn test_page_box() {
+    println!("running test test_page_box:");
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use page_box::{PageBox, PageRange};
+    page::init();
+    let pages_before = page::allocated_pages();
+    {
+        let mut range = PageRange::new(3).unwrap();
+        assert!(range.pages() == 3 && range.size() == 3 * page::PAGE_SIZE);
+        assert!(range.address().is_multiple_of(page::PAGE_SIZE));
+        range.as_mut_slice().fill(0x5a);
+        assert!(range.as_slice().iter().all(|b| *b == 0x5a));
+        assert!(page::allocated_pages() == pages_before + 3);
+        let zeroed = PageRange::zeroed(1).unwrap();
+        assert!(zeroed.as_slice().iter().all(|b| *b == 0));
+        let table = PageBox::<page::PageTable>::table().unwrap();
+        assert!(table.entries.iter().all(|entry| !entry.is_valid()));
+        assert!(page::allocated_pages() == pages_before + 5);
+    }
+    assert!(page::allocated_pages() == pages_before);
+    // the value gets dropped along with its pages
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+    struct Counted([u64; 1000]);
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    let mut boxed = PageBox::new(Counted([7; 1000])).unwrap();
+    boxed.0[999] = 8;
+    assert!(boxed.0[0] == 7 && boxed.0[999] == 8);
+    // 8000 bytes takes two pages
+    assert!(page::allocated_pages() == pages_before + 2);
+    let raw = boxed.into_raw();
+    assert!(DROPS.load(Ordering::Relaxed) == 0 && page::allocated_pages() == pages_before + 2);
+    drop(unsafe { PageBox::from_raw(raw) });
+    assert!(DROPS.load(Ordering::Relaxed) == 1 && page::allocated_pages() == pages_before);
+    // leaking keeps the pages for good
+    let leaked = PageRange::new(1).unwrap().leak();
+    assert!(leaked.len() == page::PAGE_SIZE && page::allocated_pages() == pages_before + 1);
+    page::dealloc(leaked.as_mut_ptr());
+    // a failed map doesn't leak the page it allocated
+    {
+        let mut space = address_space::AddressSpace::new().unwrap();
+        let read_write = page::PageTableEntryBits::ReadWrite.as_i64();
+        space.allocate(0x4000_0000, read_write).unwrap();
+        let pages = page::allocated_pages();
+        assert!(space.allocate(0x4000_0000, read_write) == Err(address_space::MapError::Overlap));
+        assert!(page::allocated_pages() == pages);
+    }
+    assert!(page::allocated_pages() == pages_before);
+    println!("[ok]");
+}
+
+pub fn test_threads() {
+    println!("running test test_threads:");
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    page::init();
+    malloc::init();
+    thread::init();
+    // no preempting, so the turns below come out in order
+    timer::stop();
+    assert!(thread::current() == Some(cpu::hart_id()));
+    let threads_before = thread::thread_count();
+    // nothing runs until something yields, then everybody ready gets one turn before we're back
+    let counter = Arc::new(AtomicUsize::new(0));
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let counter = Arc::clone(&counter);
+        handles.push(thread::spawn(move || {
+            for _ in 0..10 {
+                counter.fetch_add(1, Ordering::Relaxed);
+                thread::yield_now();
+            }
+        }).unwrap());
+    }
+    assert!(counter.load(Ordering::Relaxed) == 0);
+    assert!(thread::thread_count() == threads_before + 4);
+    thread::yield_now();
+    assert!(counter.load(Ordering::Relaxed) == 4);
+    for handle in handles {
+        assert!(handle.join() == 0);
+    }
+    assert!(counter.load(Ordering::Relaxed) == 40);
+    // exit codes come back through join, and a thread knows who it is
+    let seen = Arc::new(AtomicUsize::new(0));
+    let inside = Arc::clone(&seen);
+    let exiting = thread::spawn_named("exiting", move || {
+        inside.store(thread::current().unwrap(), Ordering::Relaxed);
+        thread::exit(42);
+    }).unwrap();
+    let id = exiting.id();
+    assert!(exiting.join() == 42 && seen.load(Ordering::Relaxed) == id);
+    // a detached thread is freed by whoever runs after it exits
+    let done = Arc::new(AtomicBool::new(false));
+    let finished = Arc::clone(&done);
+    drop(thread::spawn(move || finished.store(true, Ordering::Relaxed)).unwrap());
+    while !done.load(Ordering::Relaxed) {
+        thread::yield_now();
+    }
+    assert!(thread::thread_count() == threads_before);
+    // a sleeper stays off the run queues until its time is up or it gets woken
+    let deadline = cpu::time() + 10_000;
+    thread::sleep_until(deadline);
+    assert!(cpu::time() >= deadline);
+    let woke = Arc::new(AtomicBool::new(false));
+    let inside = Arc::clone(&woke);
+    let sleeper = thread::spawn(move || {
+        thread::sleep_until(usize::MAX);
+        inside.store(true, Ordering::Relaxed);
+    }).unwrap();
+    for _ in 0..10 {
+        thread::yield_now();
+    }
+    assert!(!woke.load(Ordering::Relaxed));
+    assert!(thread::wake(sleeper.id()));
+    assert!(sleeper.join() == 0 && woke.load(Ordering::Relaxed));
+    // a wake that comes first ends the next sleep before it starts
+    assert!(thread::wake(thread::current().unwrap()));
+    thread::sleep_until(usize::MAX);
+    assert!(!thread::wake(usize::MAX));
+    timer::init();
+    println!("[ok]");
+}
+
+pub fn test_scheduler() {
+    println!("running test test_scheduler:");
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use thread::Priority;
+    page::init();
+    malloc::init();
+    thread::init();
+    timer::stop();
+    let threads_before = thread::thread_count();
+    // higher priorities go first no matter when they were spawned, set_priority moves a waiting thread
+    let turn = Arc::new(AtomicUsize::new(0));
+    let order = Arc::new([const { AtomicUsize::new(usize::MAX) }; 4]);
+    let mut handles = Vec::new();
+    for (slot, priority) in [Priority::Low, Priority::Normal, Priority::High, Priority::Normal].into_iter().enumerate() {
+        let (turn, order) = (Arc::clone(&turn), Arc::clone(&order));
+        let handle = thread::spawn(move || {
+            order[slot].store(turn.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+        }).unwrap();
+        handle.set_priority(priority);
+        handles.push(handle);
+    }
+    handles[3].set_priority(Priority::High);
+    // we're normal too, so the low one waits until we block in join
+    thread::yield_now();
+    assert!(turn.load(Ordering::Relaxed) == 3);
+    for handle in handles {
+        handle.join();
+    }
+    let order: Vec<usize> = order.iter().map(|slot| slot.load(Ordering::Relaxed)).collect();
+    assert!(order == [3, 2, 0, 1]);
+    // time spent running gets counted
+    let handle = thread::spawn(|| {
+        let start = cpu::time();
+        while cpu::time() == start {
+            core::hint::spin_loop();
+        }
+    }).unwrap();
+    let id = handle.id();
+    while !handle.is_finished() {
+        thread::yield_now();
+    }
+    let mut cpu_time = 0;
+    thread::for_each_thread(|info| {
+        if info.id == id {
+            cpu_time = info.cpu_time;
+        }
+    });
+    assert!(cpu_time > 0);
+    handle.join();
+    // join blocks instead of spinning
+    let boot = thread::current().unwrap();
+    let blocked = Arc::new(AtomicBool::new(false));
+    let seen = Arc::clone(&blocked);
+    let handle = thread::spawn(move || {
+        thread::for_each_thread(|info| {
+            if info.id == boot && info.state == thread::State::Blocked {
+                seen.store(true, Ordering::Relaxed);
+            }
+        });
+    }).unwrap();
+    handle.join();
+    assert!(blocked.load(Ordering::Relaxed));
+    // with another hart online, spawned threads get spread over both and run on either
+    static ONLINE: AtomicBool = AtomicBool::new(false);
+    static DONE: AtomicBool = AtomicBool::new(false);
+    ONLINE.store(false, Ordering::Relaxed);
+    DONE.store(false, Ordering::Relaxed);
+    fn helper(_hart: usize) {
+        thread::init();
+        ONLINE.store(true, Ordering::Release);
+        while !DONE.load(Ordering::Acquire) {
+            thread::yield_now();
+        }
+        thread::stop();
+    }
+    if cpu::start_hart(1, helper, 0).is_ok() {
+        while !ONLINE.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        let harts = Arc::new(AtomicUsize::new(0));
+        let mut handles = Vec::new();
+        for _ in 0..4 {
+            let harts = Arc::clone(&harts);
+            // spawn spreads them out, and they keep taking turns until one of them ran on hart 1
+            handles.push(thread::spawn(move || {
+                while harts.fetch_or(1 << cpu::hart_id(), Ordering::Relaxed) & 0b10 == 0 {
+                    thread::yield_now();
+                }
+            }).unwrap());
+        }
+        for handle in handles {
+            handle.join();
+        }
+        assert!(harts.load(Ordering::Relaxed) & 0b10 != 0);
+        DONE.store(true, Ordering::Release);
+        while cpu::hart_status(1) != Some(cpu::HART_STOPPED) {
+            core::hint::spin_loop();
+        }
+        assert!(thread::run_queue_length(1) == 0);
+    }
+    assert!(thread::thread_count() == threads_before);
+    timer::init();
+    println!("[ok]");
+}
+
+// Needs the timer going, a thread that never yields only gives up the hart when a tick preempts it
+pub fn test_sync() {
+    println!("running test test_sync:");
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use sync::{Condvar, IrqSpinLock, Lazy, LockClass, Mutex, Once, RwLock, Semaphore, TicketLock};
+    page::init();
+    malloc::init();
+    thread::init();
+    // no preempting, so nothing runs until the test yields
+    timer::stop();
+    let threads_before = thread::thread_count();
+    // the spinning locks, nobody yields while holding one of those
+    let irq = IrqSpinLock::new(0);
+    {
+        let mut guard = irq.lock();
+        *guard += 1;
+        assert!(irq.is_locked() && irq.try_lock().is_none());
+    }
+    assert!(!irq.is_locked() && *irq.try_lock().unwrap() == 1);
+    let ticket = Arc::new(TicketLock::new(0usize));
+    {
+        let _guard = ticket.lock();
+        assert!(ticket.is_locked() && ticket.try_lock().is_none());
+    }
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let ticket = Arc::clone(&ticket);
+        handles.push(thread::spawn(move || {
+            for _ in 0..10 {
+                *ticket.lock() += 1;
+                thread::yield_now();
+            }
+        }).unwrap());
+    }
+    for handle in handles {
+        assert!(handle.join() == 0);
+    }
+    assert!(*ticket.lock() == 40 && !ticket.is_locked());
+    // a Mutex can be held across a yield, everybody else sleeps until it's free
+    let mutex = Arc::new(Mutex::new(0usize));
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let mutex = Arc::clone(&mutex);
+        handles.push(thread::spawn(move || {
+            for _ in 0..10 {
+                let mut guard = mutex.lock();
+                let value = *guard;
+                thread::yield_now();
+                *guard = value + 1;
+            }
+        }).unwrap());
+    }
+    {
+        let guard = mutex.lock();
+        assert!(mutex.try_lock().is_none());
+        thread::yield_now();
+        drop(guard);
+    }
+    for handle in handles {
+        assert!(handle.join() == 0);
+    }
+    assert!(*mutex.lock() == 40 && !mutex.is_locked());
+    // readers share, a writer doesn't, and a waiting writer keeps new readers out
+    let rwlock = Arc::new(RwLock::new(0usize));
+    {
+        let first = rwlock.read();
+        let second = rwlock.try_read().unwrap();
+        assert!(rwlock.readers() == 2 && rwlock.try_write().is_none());
+        assert!(*first == 0 && *second == 0);
+    }
+    {
+        let mut writer = rwlock.try_write().unwrap();
+        *writer = 1;
+        assert!(rwlock.try_read().is_none());
+    }
+    let reader = rwlock.read();
+    let writing = Arc::clone(&rwlock);
+    let writer = thread::spawn(move || *writing.write() += 1).unwrap();
+    thread::yield_now();
+    assert!(rwlock.try_read().is_none());
+    drop(reader);
+    assert!(writer.join() == 0);
+    assert!(*rwlock.read() == 2 && rwlock.readers() == 0);
+    // a semaphore with nothing in it holds the thread until it's released
+    let semaphore = Arc::new(Semaphore::new(0));
+    let passed = Arc::new(AtomicBool::new(false));
+    let (waiting, flag) = (Arc::clone(&semaphore), Arc::clone(&passed));
+    let handle = thread::spawn(move || {
+        waiting.acquire();
+        flag.store(true, Ordering::Relaxed);
+    }).unwrap();
+    thread::yield_now();
+    thread::yield_now();
+    assert!(!passed.load(Ordering::Relaxed));
+    semaphore.release();
+    assert!(handle.join() == 0 && passed.load(Ordering::Relaxed));
+    assert!(semaphore.available() == 0 && !semaphore.try_acquire());
+    semaphore.release();
+    assert!(semaphore.try_acquire());
+    // producer and consumer over a Condvar
+    let shared = Arc::new((Mutex::new(Vec::new()), Condvar::new()));
+    let consuming = Arc::clone(&shared);
+    let consumer = thread::spawn(move || {
+        let (queue, ready) = &*consuming;
+        let mut sum = 0;
+        for _ in 0..10 {
+            let mut items = ready.wait_while(queue.lock(), |items: &mut Vec<usize>| items.is_empty());
+            sum += items.remove(0);
+        }
+        thread::exit(sum);
+    }).unwrap();
+    for i in 1..=10 {
+        shared.0.lock().push(i);
+        shared.1.notify_one();
+        thread::yield_now();
+    }
+    assert!(consumer.join() == 55);
+    // Once and Lazy run their initializer the once
+    let calls = AtomicUsize::new(0);
+    let once = Once::new();
+    assert!(once.get().is_none());
+    assert!(*once.call_once(|| calls.fetch_add(1, Ordering::Relaxed) + 7) == 7);
+    assert!(*once.call_once(|| calls.fetch_add(1, Ordering::Relaxed) + 8) == 7);
+    assert!(once.is_completed() && once.get() == Some(&7) && calls.load(Ordering::Relaxed) == 1);
+    let lazy = Lazy::new(|| {
+        calls.fetch_add(1, Ordering::Relaxed);
+        Vec::from([1, 2, 3])
+    });
+    assert!(calls.load(Ordering::Relaxed) == 1);
+    assert!(lazy.len() == 3 && lazy[2] == 3 && calls.load(Ordering::Relaxed) == 2);
+    // ranked locks in increasing order are fine, try_lock skips the check but still counts
+    let outer = Mutex::ranked(0, LockClass::new("outer", 1));
+    let inner = IrqSpinLock::ranked(0, LockClass::new("inner", 2));
+    {
+        let _outer = outer.lock();
+        let _inner = inner.lock();
+    }
+    {
+        let _inner = inner.lock();
+        let _outer = outer.try_lock().unwrap();
+    }
+    while thread::thread_count() != threads_before {
+        thread::yield_now();
+    }
+    timer::init();
+    println!("[ok]");
+}
+
+pub fn test_preemption() {
+    println!("running test test_preemption:");
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    page::init();
+    malloc::init();
+    thread::init();
+    timer::init();
+    let flag = Arc::new(AtomicBool::new(false));
+    let waiting = Arc::clone(&flag);
+    let spinner = thread::spawn(move || {
+        while !waiting.load(Ordering::Relaxed) {
+            core::hint::spin_loop();
+        }
+    }).unwrap();
+    let setter = thread::spawn(move || flag.store(true, Ordering::Relaxed)).unwrap();
+    spinner.join();
+    setter.join();
+    println!("[ok]");
+}
+
+// Needs real user mode. Every way a process can misbehave has to end in a fault for that process
+// and leave the kernel alone
+pub fn test_process() {
+    println!("running test test_process:");
+    use alloc::vec::Vec;
+    use page::PageTableEntryBits;
+    use process::{Exit, Process, A0};
+    page::init();
+    malloc::init();
+    thread::init();
+    let pages_before = page::allocated_pages();
+    const CODE: usize = 0x1_0000;
+    const STACK_TOP: usize = 0x8_0000;
+    fn start(instructions: &[u32], a0: usize) -> Process {
+        let code: Vec<u8> = instructions.iter().flat_map(|instruction| instruction.to_le_bytes()).collect();
+        let mut process = Process::new().unwrap();
+        process.load(CODE, &code, PageTableEntryBits::ReadExecute.as_i64()).unwrap();
+        process.reserve_stack(STACK_TOP, 2 * page::PAGE_SIZE).unwrap();
+        process.set_entry(CODE);
+        process.frame().regs[A0] = a0;
+        process
+    }
+    const ECALL: u32 = 0x00000073;
+    // li a7, SYS_EXIT
+    const EXIT: u32 = (syscall::SYS_EXIT as u32) << 20 | 0x893;
+    // li a0, 42
+    assert!(start(&[0x02a00513, EXIT, ECALL], 0).run() == Exit::Exited(42));
+    // addi sp, sp, -16; sd a0, 0(sp); ld a0, 0(sp), the stack page gets faulted in on the way
+    assert!(start(&[0xff010113, 0x00a13023, 0x00013503, EXIT, ECALL], 7).run() == Exit::Exited(7));
+    // ld a1, 0(a0) from the frame page, which is mapped but without the user bit
+    let frame = process::FRAME_ADDRESS;
+    assert!(start(&[0x00053583, ECALL], frame).run() == Exit::Fault { cause: trap::LOAD_PAGE_FAULT, tval: frame, epc: CODE });
+    // sd zero, 0(a0) into kernel memory that isn't mapped for the process at all
+    let target = alloc::boxed::Box::new(0x5a5a_usize);
+    let address = &*target as *const usize as usize;
+    let fault = start(&[0x00053023, ECALL], address).run();
+    assert!(fault == Exit::Fault { cause: trap::STORE_PAGE_FAULT, tval: address, epc: CODE });
+    assert!(*target == 0x5a5a);
+    // csrr a0, sstatus is for supervisors only
+    assert!(matches!(start(&[0x10002573, ECALL], 0).run(), Exit::Fault { cause: trap::ILLEGAL_INSTRUCTION, .. }));
+    // fmv.x.d a0, f0 sees the f0 from the frame
+    let mut process = start(&[0xe2000553, EXIT, ECALL], 0);
+    process.frame().fregs[0] = 42;
+    assert!(process.run() == Exit::Exited(42));
+    // and fmv.d.x f1, a0 lands in the frame
+    let mut process = start(&[0xf20500d3, EXIT, ECALL], 0x1234);
+    assert!(process.run() == Exit::Exited(0x1234));
+    assert!(process.frame().fregs[1] == 0x1234);
+    drop(target);
+    // every process above dropped its pages and tables
+    assert!(page::allocated_pages() == pages_before);
+    println!("[ok]");
+}
+
+// The system calls straight from the kernel, then a couple from a real process
+pub fn test_syscalls() {
+    println!("running test test_syscalls:");
+    use page::PageTableEntryBits;
+    use process::{Exit, Process, A0, A1, A2};
+    use syscall::{dispatch, Errno, PROT_READ, PROT_WRITE};
+    page::init();
+    malloc::init();
+    thread::init();
+    let pages_before = page::allocated_pages();
+    {
+        let mut process = Process::new().unwrap();
+        let data = 0x2_0000;
+        process.load(data, b"hello from user mode\n", PageTableEntryBits::ReadWrite.as_i64()).unwrap();
+        assert!(dispatch(&mut process, syscall::SYS_GETPID, [0; 6]) == Ok(process.pid()));
+        assert!(dispatch(&mut process, 1000, [0; 6]) == Err(Errno::NotImplemented));
+        assert!(syscall::encode(Err(Errno::BadAddress)) as isize == -14);
+        // write checks the descriptor and every byte of the buffer
+        assert!(dispatch(&mut process, syscall::SYS_WRITE, [syscall::STDOUT, data, 21, 0, 0, 0]) == Ok(21));
+        assert!(dispatch(&mut process, syscall::SYS_WRITE, [7, data, 21, 0, 0, 0]) == Err(Errno::BadFile));
+        let kernel = &pages_before as *const usize as usize;
+        assert!(dispatch(&mut process, syscall::SYS_WRITE, [syscall::STDOUT, kernel, 8, 0, 0, 0]) == Err(Errno::BadAddress));
+        assert!(dispatch(&mut process, syscall::SYS_WRITE, [syscall::STDOUT, usize::MAX - 4, 8, 0, 0, 0]) == Err(Errno::BadAddress));
+        assert!(dispatch(&mut process, syscall::SYS_READ, [syscall::STDOUT, data, 1, 0, 0, 0]) == Err(Errno::BadFile));
+        // sbrk hands out pages right away and takes them back
+        let heap = dispatch(&mut process, syscall::SYS_SBRK, [5000, 0, 0, 0, 0, 0]).unwrap();
+        assert!(heap == process::HEAP_START);
+        process.copy_to_user(heap + 4096, &[1, 2, 3]).unwrap();
+        assert!(dispatch(&mut process, syscall::SYS_SBRK, [0; 6]) == Ok(heap + 5000));
+        assert!(dispatch(&mut process, syscall::SYS_SBRK, [(-5000isize) as usize, 0, 0, 0, 0, 0]) == Ok(heap + 5000));
+        assert!(process.space().translate(heap).is_none());
+        assert!(dispatch(&mut process, syscall::SYS_SBRK, [(-1isize) as usize, 0, 0, 0, 0, 0]) == Err(Errno::InvalidArgument));
+        // mmap reserves, the first touch backs it, munmap only takes whole mappings
+        let prot = PROT_READ | PROT_WRITE;
+        let mapped = dispatch(&mut process, syscall::SYS_MMAP, [0, 8192, prot, 0, 0, 0]).unwrap();
+        assert!(mapped == process::MMAP_START);
+        assert!(process.space().translate(mapped).is_none());
+        process.copy_to_user(mapped + 4095, &[9, 9]).unwrap();
+        let mut back = [0u8; 2];
+        process.copy_from_user(mapped + 4095, &mut back).unwrap();
+        assert!(back == [9, 9]);
+        assert!(dispatch(&mut process, syscall::SYS_MMAP, [0, 4096, PROT_WRITE, 0, 0, 0]) == Err(Errno::InvalidArgument));
+        assert!(dispatch(&mut process, syscall::SYS_MMAP, [mapped, 4096, prot, 0, 0, 0]) == Err(Errno::InvalidArgument));
+        assert!(dispatch(&mut process, syscall::SYS_MMAP, [0x1000, 4096, prot, 0, 0, 0]) == Err(Errno::InvalidArgument));
+        let read_only = dispatch(&mut process, syscall::SYS_MMAP, [0, 4096, PROT_READ, 0, 0, 0]).unwrap();
+        assert!(process.copy_to_user(read_only, &[1]) == Err(address_space::MapError::PermissionDenied));
+        assert!(dispatch(&mut process, syscall::SYS_MUNMAP, [mapped, 4096, 0, 0, 0, 0]) == Err(Errno::InvalidArgument));
+        assert!(dispatch(&mut process, syscall::SYS_MUNMAP, [mapped, 8192, 0, 0, 0, 0]) == Ok(0));
+        assert!(process.copy_from_user(mapped, &mut back) == Err(address_space::MapError::NotMapped));
+        // a hinted mapping past where mmap(0) had got to moves it along
+        let hinted = read_only + 2 * 4096;
+        assert!(dispatch(&mut process, syscall::SYS_MMAP, [hinted, 4096, prot, 0, 0, 0]) == Ok(hinted));
+        assert!(dispatch(&mut process, syscall::SYS_MMAP, [0, 4096, prot, 0, 0, 0]) == Ok(hinted + 4096));
+        // and mmap(0) steps over whatever is in the way to the first gap big enough
+        process.mmap_next = process::MMAP_START;
+        assert!(dispatch(&mut process, syscall::SYS_MMAP, [0, 8192, prot, 0, 0, 0]) == Ok(mapped));
+        assert!(dispatch(&mut process, syscall::SYS_MMAP, [0, 4096, prot, 0, 0, 0]) == Ok(read_only + 4096));
+        assert!(dispatch(&mut process, syscall::SYS_MMAP, [0, 4096, prot, 0, 0, 0]) == Ok(hinted + 2 * 4096));
+        // a process making the calls itself: write(1, data, 21) and exit with what it returned
+        process.frame().regs[A0] = syscall::STDOUT;
+        process.frame().regs[A1] = data;
+        process.frame().regs[A2] = 21;
+        let code = [
+            (syscall::SYS_WRITE as u32) << 20 | 0x893, 0x00000073,
+            (syscall::SYS_EXIT as u32) << 20 | 0x893, 0x00000073,
+        ];
+        let code: alloc::vec::Vec<u8> = code.iter().flat_map(|instruction| instruction.to_le_bytes()).collect();
+        process.load(0x1_0000, &code, PageTableEntryBits::ReadExecute.as_i64()).unwrap();
+        process.set_entry(0x1_0000);
+        assert!(process.run() == Exit::Exited(21));
+    }
+    assert!(page::allocated_pages() == pages_before);
+    println!("[ok]");
+}
+
+// A hand built executable: parse checks, the segments and their permissions, the stack exec lays
+// out, and running it
+pub fn test_elf() {
+    println!("running test test_elf:");
+    use alloc::vec::Vec;
+    use elf::{ElfError, PF_R, PF_W, PF_X, PT_LOAD};
+    use page::PageTableEntryBits;
+    use process::{Exit, STACK_TOP};
+    page::init();
+    malloc::init();
+    thread::init();
+    let pages_before = page::allocated_pages();
+    // (type, flags, offset, address, file size, memory size) for each program header, then the
+    // bytes to put at file offsets
+    fn build(entry: usize, headers: &[(u32, u32, usize, usize, usize, usize)], data: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut image = alloc::vec![0u8; 0x400];
+        image[..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        image[16..18].copy_from_slice(&2u16.to_le_bytes());
+        image[18..20].copy_from_slice(&243u16.to_le_bytes());
+        image[24..32].copy_from_slice(&entry.to_le_bytes());
+        image[32..40].copy_from_slice(&64usize.to_le_bytes());
+        image[54..56].copy_from_slice(&56u16.to_le_bytes());
+        image[56..58].copy_from_slice(&(headers.len() as u16).to_le_bytes());
+        for (i, (kind, flags, offset, address, file_size, memory_size)) in headers.iter().enumerate() {
+            let at = 64 + i * 56;
+            image[at..at + 4].copy_from_slice(&kind.to_le_bytes());
+            image[at + 4..at + 8].copy_from_slice(&flags.to_le_bytes());
+            image[at + 8..at + 16].copy_from_slice(&offset.to_le_bytes());
+            image[at + 16..at + 24].copy_from_slice(&address.to_le_bytes());
+            image[at + 32..at + 40].copy_from_slice(&file_size.to_le_bytes());
+            image[at + 40..at + 48].copy_from_slice(&memory_size.to_le_bytes());
+        }
+        for (offset, bytes) in data {
+            image[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        }
+        image
+    }
+    // li a7, SYS_EXIT; ecall, exits with argc since that's already in a0
+    let code: Vec<u8> = [(syscall::SYS_EXIT as u32) << 20 | 0x893, 0x00000073].iter()
+        .flat_map(|instruction| instruction.to_le_bytes()).collect();
+    let (text, code_offset) = (0x1_0000, 0x100);
+    let entry = text + code_offset;
+    let headers = [
+        // the headers and the code
+        (PT_LOAD, PF_R | PF_X, 0, text, 0x108, 0x108),
+        // read only data sharing the code's page
+        (PT_LOAD, PF_R, 0x200, text + 0x800, 4, 4),
+        // data not starting on a page boundary, with bss running onto the next page
+        (PT_LOAD, PF_R | PF_W, 0x300, 0x2_0040, 8, 0x1000),
+    ];
+    let data: [(usize, &[u8]); 3] = [(code_offset, &code), (0x200, b"rodt"), (0x300, &[0xaa; 8])];
+    let image = build(entry, &headers, &data);
+    // broken images never get as far as a process
+    assert!(elf::Elf::parse(&image[..40]).err() == Some(ElfError::Truncated));
+    let mut bad = image.clone();
+    bad[1] = b'e';
+    assert!(elf::Elf::parse(&bad).err() == Some(ElfError::BadMagic));
+    let mut bad = image.clone();
+    bad[18] = 62;
+    assert!(elf::Elf::parse(&bad).err() == Some(ElfError::Unsupported));
+    let mut bad = image.clone();
+    bad[56] = 20;
+    assert!(elf::Elf::parse(&bad).err() == Some(ElfError::Truncated));
+    let exec = |headers: &[(u32, u32, usize, usize, usize, usize)], entry| elf::exec(&build(entry, headers, &data), &[], &[]).err();
+    assert!(exec(&[(PT_LOAD, PF_R | PF_X, 0, text, 0x108, 0x100)], entry) == Some(ElfError::BadSegment));
+    assert!(exec(&[(PT_LOAD, PF_R | PF_X, 0, process::HEAP_START, 0x108, 0x108)], entry) == Some(ElfError::BadSegment));
+    // a segment can't claim the trampoline, which is down where programs go, as its own (it'd come
+    // back user read write execute), not even with bss running onto it from the page before
+    let (trampoline, _) = process::trampoline();
+    let over_trampoline = (PT_LOAD, PF_R | PF_W | PF_X, 0, trampoline, 0, 0x1000);
+    assert!(exec(&[over_trampoline], trampoline) == Some(ElfError::BadSegment));
+    let onto_trampoline = (PT_LOAD, PF_R | PF_W | PF_X, 0, trampoline - 0x1000, 0, 0x1008);
+    assert!(exec(&[onto_trampoline], trampoline - 0x1000) == Some(ElfError::BadSegment));
+    assert!(exec(&[(PT_LOAD, 0, 0, text, 0x108, 0x108)], entry) == Some(ElfError::BadSegment));
+    assert!(exec(&[(PT_LOAD, PF_R | PF_X, 0x300, text, 0x108, 0x108)], entry) == Some(ElfError::Truncated));
+    assert!(exec(&[(PT_LOAD, PF_R | PF_W, 0, text, 0x108, 0x108)], entry) == Some(ElfError::NoEntry));
+    assert!(exec(&headers, 0x5_0000) == Some(ElfError::NoEntry));
+    let too_long = [core::str::from_utf8(&[b'x'; 3000]).unwrap(); 6];
+    assert!(elf::exec(&image, &too_long, &[]).err() == Some(ElfError::ArgumentsTooLong));
+    {
+        let mut process = elf::exec(&image, &["prog", "one"], &["HOME=/"]).unwrap();
+        let space = process.space();
+        let permissions = |address| space.flags(address).map(|flags| flags & PageTableEntryBits::UserReadWriteExecute.as_i64());
+        assert!(permissions(text) == Some(PageTableEntryBits::UserReadExecute.as_i64()));
+        assert!(permissions(0x2_0000) == Some(PageTableEntryBits::UserReadWrite.as_i64()));
+        assert!(permissions(0x2_1000) == Some(PageTableEntryBits::UserReadWrite.as_i64()));
+        assert!(permissions(0x2_2000).is_none());
+        let mut bytes = [0u8; 16];
+        process.copy_from_user(text + 0x800, &mut bytes[..4]).unwrap();
+        assert!(&bytes[..4] == b"rodt");
+        process.copy_from_user(0x2_003c, &mut bytes).unwrap();
+        assert!(bytes == [0, 0, 0, 0, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0, 0, 0, 0]);
+        // argc, argv, envp and auxv, in that order from sp up
+        let sp = process.frame().regs[process::SP];
+        assert!(sp.is_multiple_of(16) && sp < STACK_TOP && STACK_TOP - sp < page::PAGE_SIZE);
+        let mut read_word = |address: usize| {
+            let mut word = [0u8; 8];
+            process.copy_from_user(address, &mut word).unwrap();
+            usize::from_le_bytes(word)
+        };
+        assert!(read_word(sp) == 2);
+        assert!(read_word(sp + 24) == 0 && read_word(sp + 40) == 0);
+        let strings = [read_word(sp + 8), read_word(sp + 16), read_word(sp + 32)];
+        let mut auxv = Vec::new();
+        let mut at = sp + 48;
+        while read_word(at) != elf::AT_NULL {
+            auxv.push((read_word(at), read_word(at + 8)));
+            at += 16;
+        }
+        assert!(auxv.contains(&(elf::AT_ENTRY, entry)) && auxv.contains(&(elf::AT_PHNUM, 3)));
+        assert!(auxv.contains(&(elf::AT_PAGESZ, page::PAGE_SIZE)) && auxv.contains(&(elf::AT_PHDR, text + 64)));
+        for (address, expected) in strings.iter().zip(["prog\0", "one\0", "HOME=/\0"]) {
+            let mut string = [0u8; 7];
+            process.copy_from_user(*address, &mut string[..expected.len()]).unwrap();
+            assert!(&string[..expected.len()] == expected.as_bytes());
+        }
+        let frame = process.frame();
+        assert!(frame.regs[process::A0] == 2 && frame.regs[process::A1] == sp + 8 && frame.regs[process::A2] == sp + 32);
+        assert!(frame.epc == entry);
+        assert!(process.run() == Exit::Exited(2));
+    }
+    assert!(elf::exec_program("no such program", &[], &[]).is_none());
+    assert!(page::allocated_pages() == pages_before);
+    println!("[ok]");
+}
+
+// The programs from user/, each one started by name and run to the end
+pub fn test_user_programs() {
+    println!("running test test_user_programs:");
+    use process::Exit;
+    page::init();
+    malloc::init();
+    thread::init();
+    let pages_before = page::allocated_pages();
+    let run = |name, args: &[&str]| elf::exec_program(name, args, &["HOME=/"]).unwrap().unwrap().run();
+    for program in elf::PROGRAMS {
+        assert!(elf::Elf::parse(program.image).is_ok());
+    }
+    assert!(run("hello", &[]) == Exit::Exited(0));
+    assert!(run("echo", &["from", "user", "mode"]) == Exit::Exited(0));
+    assert!(run("memstress", &["2"]) == Exit::Exited(0));
+    assert!(page::allocated_pages() == pages_before);
+    println!("[ok]");
+}
+
+// Processes on threads of their own: wait and its statuses, kill, ps's view of the table and
+// orphans going to the kernel
+pub fn test_process_table() {
+    println!("running test test_process_table:");
+    use process::{Exit, ProcessState, KERNEL_PID};
+    use syscall::Errno;
+    page::init();
+    malloc::init();
+    thread::init();
+    let pages_before = page::allocated_pages();
+    let threads_before = thread::thread_count();
+    let start = |name| {
+        let program = elf::find_program(name).unwrap();
+        let process = elf::exec(program.image, &[program.name], &[]).unwrap();
+        process::spawn(process, program.name, KERNEL_PID).unwrap()
+    };
+    let hello = start("hello");
+    let reaped = process::wait(KERNEL_PID, Some(hello)).unwrap();
+    assert!(reaped.pid == hello && reaped.name == "hello" && reaped.exit == Exit::Exited(0));
+    assert!(process::try_wait(KERNEL_PID, Some(hello)) == Err(Errno::NoChild));
+    assert!(process::try_wait(KERNEL_PID, None) == Err(Errno::NoChild));
+    // a process that never makes a system call still gets stopped
+    let spin = start("spin");
+    assert!(process::try_wait(KERNEL_PID, Some(spin)) == Ok(None));
+    let mut seen = false;
+    process::for_each_process(|process| {
+        if process.pid == spin {
+            assert!(process.parent == KERNEL_PID && process.name == "spin" && process.state == ProcessState::Running);
+            seen = true;
+        }
+    });
+    assert!(seen);
+    // a process can only kill itself and its own children, and spin is the kernel's
+    {
+        let kill = |process: &mut process::Process, pid| syscall::dispatch(process, syscall::SYS_KILL, [pid, 0, 0, 0, 0, 0]);
+        let mut outsider = process::Process::new().unwrap();
+        assert!(kill(&mut outsider, spin) == Err(Errno::PermissionDenied));
+        assert!(kill(&mut outsider, usize::MAX) == Err(Errno::NoSuchProcess));
+        // an empty slot isn't anybody's
+        assert!(process::parent_of(0).is_none());
+        assert!(kill(&mut outsider, 0) == Err(Errno::NoSuchProcess));
+        assert!(syscall::encode(Err(Errno::PermissionDenied)) as isize == -1);
+        let program = elf::find_program("spin").unwrap();
+        let child = process::spawn(elf::exec(program.image, &[program.name], &[]).unwrap(), program.name, outsider.pid()).unwrap();
+        assert!(kill(&mut outsider, child) == Ok(0));
+        assert!(process::wait(outsider.pid(), Some(child)).unwrap().exit == Exit::Killed);
+    }
+    assert!(!process::is_killed(spin));
+    // pgdump <pid> walks the process's own table, user pages and all
+    let snapshot = process::page_table_snapshot(spin).unwrap();
+    assert!(snapshot.ranges().iter().any(|range| range.flags & page::PageTableEntryBits::User.as_i64() != 0));
+    process::kill(spin).unwrap();
+    assert!(process::wait(KERNEL_PID, None).unwrap().exit == Exit::Killed);
+    assert!(process::page_table_snapshot(spin).is_none());
+    assert!(process::kill(spin) == Err(Errno::NoSuchProcess));
+    assert!(process::kill(KERNEL_PID) == Err(Errno::NoSuchProcess));
+    // family waits for its own children and leaves a spin behind, which ends up with the kernel
+    let family = start("family");
+    assert!(process::wait(KERNEL_PID, Some(family)).unwrap().exit == Exit::Exited(0));
+    let mut orphan = None;
+    process::for_each_process(|process| {
+        if process.name == "spin" && process.parent == KERNEL_PID {
+            orphan = Some(process.pid);
+        }
+    });
+    let orphan = orphan.unwrap();
+    process::kill(orphan).unwrap();
+    let reaped = process::wait(KERNEL_PID, None).unwrap();
+    assert!(reaped.pid == orphan && reaped.exit == Exit::Killed);
+    assert!(process::process_count() == 0);
+    assert!(syscall::wait_status(Exit::Exited(3)) == 3 << 8);
+    assert!(syscall::wait_status(Exit::Killed) == syscall::SIGKILL);
+    // a zombie's thread can still be on its way out, its stacks go once it has switched away
+    while thread::thread_count() != threads_before {
+        thread::yield_now();
+    }
+    assert!(page::allocated_pages() == pages_before);
+    println!("[ok]");
+}
+
 /// Eventually want to randomly generate some keyboard inputs and
 /// see if the uart console can handle the inputs properly
 pub fn test_fuzzed_uart_inputs() {}
@@ -153,5 +1769,32 @@ pub fn test_fuzzed_uart_inputs() {}
 pub fn test() {
     test_pages();
     test_alloc();
+    test_address_space();
+    test_pgdump();
+    test_copy_on_write();
+    test_demand_paging();
+    test_asid();
+    test_kernel_stack();
+    test_reserved_regions();
+    test_fdt();
+    test_dma();
+    test_kernel_heap();
+    test_kernel_heap_growth();
+    test_slab();
+    test_heapcheck();
+    test_allocator_stress();
+    test_linear_allocator();
+    test_collections();
+    test_alloctrace();
+    test_page_box();
+    test_threads();
+    test_scheduler();
+    test_sync();
+    test_preemption();
+    test_process();
+    test_syscalls();
+    test_elf();
+    test_user_programs();
+    test_process_table();
     println!("tests succeeded!")
 }
@@ -1,60 +1,160 @@
 // As an alternative to the linked list memory paging system, this simple linear allocator
 // let's us play aorund with a shell to start
+// It's an arena: allocating just bumps an offset forward, nothing gets freed on its own, and the
+// whole thing (or everything since a checkpoint) gets thrown away at once. Good for scratch memory
+// whose lifetime is obvious, like everything a shell command needs while it runs
 
 use core::sync::atomic::{AtomicUsize};
+use crate::page::{self, PAGE_SIZE};
 
 // Contains information about a linearly allocated heap
 pub struct LinearAllocator {
     head: AtomicUsize, // index of the buffer
-    // The index of the buffer is atomic, so threads can only access one at a time
-    // (helping with safety of accesss)
+    // The index of the buffer is atomic, and only ever moved with a compare exchange, so harts
+    // allocating at the same time each get their own piece without a lock
     start: *mut u8,
     end: *mut u8,
+    // pages from page::alloc that go back on drop, 0 if the memory came from somewhere else
+    pages: usize,
 }
 
 unsafe impl Sync for LinearAllocator {}
 
+// Where the head was, to roll back to later
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Checkpoint(usize);
+
 impl LinearAllocator {
     pub const fn empty() -> Self {
         Self {
             head: AtomicUsize::new(0),
             start: core::ptr::null_mut(),
             end: core::ptr::null_mut(),
+            pages: 0,
         }
     }
     pub fn init(&mut self, start: usize, size: usize) {
         self.start = start as *mut u8;
         // dereferencing a raw pointer here
         self.end = unsafe { self.start.add(size) };
+        self.head.store(0, Ordering::Relaxed);
+    }
+
+    // An arena over its own pages from the page allocator, None if there aren't enough
+    pub fn from_pages(pages: usize) -> Option<Self> {
+        let memory = page::alloc(pages);
+        if memory.is_null() {
+            return None;
+        }
+        let mut arena = Self::empty();
+        arena.init(memory as usize, pages * PAGE_SIZE);
+        arena.pages = pages;
+        Some(arena)
+    }
+
+    // Bump allocate size bytes at the alignment (a power of two), null once the arena is full
+    pub fn alloc_bytes(&self, size: usize, align: usize) -> *mut u8 {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            // align the address rather than the index, the start might not be aligned itself
+            let address = self.start as usize + head;
+            let aligned = (address + align - 1) & !(align - 1);
+            // Check for going over end of heap memory
+            let new_head = match (aligned - self.start as usize).checked_add(size) {
+                Some(new_head) if new_head <= self.capacity() => new_head,
+                _ => return core::ptr::null_mut(),
+            };
+            match self.head.compare_exchange_weak(head, new_head, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return aligned as *mut u8,
+                // someone else got in first, try again after them
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    // Move a value into the arena. It never gets dropped, the arena just forgets it on reset.
+    // Every call hands out memory nobody else has, so the &mut can't alias
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_value<T>(&self, value: T) -> Option<&mut T> {
+        let pointer = self.alloc_bytes(size_of::<T>(), align_of::<T>()) as *mut T;
+        if pointer.is_null() {
+            return None;
+        }
+        unsafe {
+            pointer.write(value);
+            Some(&mut *pointer)
+        }
+    }
+
+    // Copy a slice into the arena
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice<T: Copy>(&self, values: &[T]) -> Option<&mut [T]> {
+        let pointer = self.alloc_bytes(size_of_val(values), align_of::<T>()) as *mut T;
+        if pointer.is_null() {
+            return None;
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(values.as_ptr(), pointer, values.len());
+            Some(core::slice::from_raw_parts_mut(pointer, values.len()))
+        }
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint(self.head.load(Ordering::Relaxed))
+    }
+
+    // Throw away everything allocated since the checkpoint. Taking &mut self makes sure nothing
+    // from alloc_value or alloc_slice is still borrowed
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        let head = self.head.get_mut();
+        if checkpoint.0 <= *head {
+            *head = checkpoint.0;
+        }
+    }
+
+    // Throw away everything
+    pub fn reset(&mut self) {
+        self.rollback(Checkpoint(0));
+    }
+
+    // Run f with the arena and roll back whatever it allocated once it's done
+    pub fn scope<R>(&mut self, f: impl FnOnce(&Self) -> R) -> R {
+        let checkpoint = self.checkpoint();
+        let result = f(self);
+        self.rollback(checkpoint);
+        result
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.end as usize - self.start as usize
+    }
+
+    pub fn used(&self) -> usize {
+        self.head.load(Ordering::Relaxed)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.capacity() - self.used()
+    }
+}
+
+impl Drop for LinearAllocator {
+    fn drop(&mut self) {
+        if self.pages != 0 {
+            page::dealloc(self.start);
+        }
     }
 }
 
 use core::alloc::{GlobalAlloc, Layout};
-use core::ptr::NonNull;
+use core::mem::{align_of, size_of, size_of_val};
 use core::sync::atomic::{Ordering};
 // Rust needs to know that this is a global memory allocator, so it can use a shared
 // alloc and dealloc interface
 unsafe impl GlobalAlloc for LinearAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // using the core Layout crate to keep bytes aligned for performance
-        let align = layout.align();
-        // number of bytes to allocate
-        let size = layout.size();
-        let mut head = self.head.load(Ordering::Relaxed);
-        // align the head
-        if head % align != 0 {
-            // e.g. if head is 1 and alignment is 4 bytes, 3 will get added to the head
-            head += align - (head % align);
-        }
-        // Move the head forward by the allocation size
-        let new_head = head + size;
-        // Check for going over end of heap memory
-        if self.start.add(new_head) > self.end {
-            return core::ptr::null_mut();
-        }
-        self.head.store(new_head, Ordering::Relaxed);
-        // This core struct let's us handle errors rather than straight up returning a null pointer
-        NonNull::new_unchecked(self.start.add(head) as *mut u8).as_ptr()
+        self.alloc_bytes(layout.size(), layout.align())
     }
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
         // This doesn't do anything! We can't really free memory, only reset the entire arena for something else
@@ -1,7 +1,15 @@
 // Provides the memory for the kernel, for now also exposes a global allocator for heap memory
 
-use crate::page::{align_value, zalloc, PageTable, PAGE_SIZE};
+use crate::address_space;
+use crate::alloctrace::{self, Event};
+use crate::cpu::{self, MAX_HARTS};
+use crate::page::{self, align_value, zalloc, PageTable, PAGE_SIZE};
+use crate::slab;
+use crate::sync::{SpinLock, HEAP_CLASS};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::{mem::size_of, ptr::null_mut};
+use core::sync::atomic::{AtomicBool, Ordering};
 use crate::println;
 use crate::print;
 
@@ -17,8 +25,15 @@ impl AllocationFlags {
     }
 }
 
+#[repr(C)]
 struct AllocationList {
-    pub flags_size: usize
+    pub flags_size: usize,
+    // with the debug heap every header also remembers how much was asked for and ends in a canary
+    // right in front of the payload, so writes running off either end of a chunk get noticed
+    #[cfg(feature = "debug-heap")]
+    pub requested: usize,
+    #[cfg(feature = "debug-heap")]
+    pub canary: usize,
 }
 
 impl AllocationList {
@@ -44,13 +59,222 @@ impl AllocationList {
     }
 }
 
+// payloads always land on at least an 8 byte boundary
+const MIN_ALIGN: usize = 8;
+// bytes after every payload that the debug heap fills in and checks, nothing without it
+#[cfg(feature = "debug-heap")]
+const REDZONE_SIZE: usize = 8;
+#[cfg(not(feature = "debug-heap"))]
+const REDZONE_SIZE: usize = 0;
+
+// The whole chunk a payload of size bytes takes up. A zero byte request still gets MIN_ALIGN bytes
+// of payload, so every pointer handed out is distinct, non-null and can be freed like any other
+fn chunk_size(size: usize) -> usize {
+    align_value(size.max(MIN_ALIGN), 3) + size_of::<AllocationList>() + REDZONE_SIZE
+}
+
+// What a heap check can find wrong with a chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapCorruption {
+    // the size in the header is nonsense, so the rest of the arena can't be walked
+    BadHeader,
+    // the canary in front of the payload got overwritten
+    Underflow,
+    // something wrote past the requested size into the red zone
+    Overflow,
+    // a free chunk doesn't hold its poison anymore, someone kept writing to it after freeing it
+    UseAfterFree,
+    // the chunk was already free
+    DoubleFree,
+    // the pointer isn't the start of any chunk
+    InvalidPointer,
+}
+
+fn report(kind: HeapCorruption, chunk: *mut AllocationList) {
+    let size = if kind == HeapCorruption::InvalidPointer { 0 } else { unsafe { (*chunk).get_size() } };
+    println!("[ERROR] heap {:?} at chunk {:p} ({} bytes)", kind, chunk, size);
+}
+
+// The debug heap: taken chunks get a canary in the header and a red zone of REDZONE_BYTE after
+// the requested bytes, free chunks get a different canary and their payload filled with
+// POISON_BYTE. Every free checks the chunk it is handed and heapcheck checks the lot
+#[cfg(feature = "debug-heap")]
+mod debug {
+    use super::{AllocationList, Arena, HeapCorruption};
+    use core::mem::size_of;
+
+    pub const CANARY: usize = 0x5afe_c0de_5afe_c0de;
+    pub const FREE_CANARY: usize = 0xf4ee_f4ee_f4ee_f4ee;
+    pub const REDZONE_BYTE: u8 = 0xca;
+    pub const POISON_BYTE: u8 = 0xdd;
+
+    fn payload(head: *mut AllocationList) -> *mut u8 {
+        unsafe { head.add(1) as *mut u8 }
+    }
+
+    fn end(head: *mut AllocationList) -> *mut u8 {
+        unsafe { (head as *mut u8).add((*head).get_size()) }
+    }
+
+    fn all(from: *mut u8, to: *mut u8, value: u8) -> bool {
+        (from as usize..to as usize).all(|address| unsafe { *(address as *const u8) } == value)
+    }
+
+    pub fn fill(from: *mut u8, to: *mut u8, value: u8) {
+        if to > from {
+            unsafe { core::ptr::write_bytes(from, value, to as usize - from as usize) };
+        }
+    }
+
+    pub fn mark_free_header(head: *mut AllocationList) {
+        unsafe {
+            (*head).requested = 0;
+            (*head).canary = FREE_CANARY;
+        }
+    }
+
+    pub fn mark_allocated(head: *mut AllocationList, requested: usize) {
+        unsafe {
+            (*head).requested = requested;
+            (*head).canary = CANARY;
+            fill(payload(head).add(requested), end(head), REDZONE_BYTE);
+        }
+    }
+
+    // a chunk that was just freed, poison all of it past the header
+    pub fn mark_free(head: *mut AllocationList) {
+        mark_free_header(head);
+        fill(payload(head), end(head), POISON_BYTE);
+    }
+
+    // a header that got swallowed by the free chunk in front of it
+    pub fn poison_header(head: *mut AllocationList) {
+        fill(head as *mut u8, payload(head), POISON_BYTE);
+    }
+
+    // poison the bytes between two addresses, they are about to end up in a free chunk
+    pub fn poison(from: *mut u8, to: *mut u8) {
+        fill(from, to, POISON_BYTE);
+    }
+
+    pub fn check(head: *mut AllocationList) -> Result<(), HeapCorruption> {
+        unsafe {
+            if (*head).is_taken() {
+                if (*head).canary != CANARY {
+                    return Err(HeapCorruption::Underflow);
+                }
+                if payload(head) as usize + (*head).requested > end(head) as usize
+                    || !all(payload(head).add((*head).requested), end(head), REDZONE_BYTE) {
+                    return Err(HeapCorruption::Overflow);
+                }
+            } else if (*head).canary != FREE_CANARY || !all(payload(head), end(head), POISON_BYTE) {
+                return Err(HeapCorruption::UseAfterFree);
+            }
+        }
+        Ok(())
+    }
+
+    // Make sure the chunk really is one the arena handed out and still looks intact
+    pub fn check_taken(arena: *mut Arena, head: *mut AllocationList) -> Result<(), HeapCorruption> {
+        unsafe {
+            let mut chunk = (*arena).chunks();
+            let mut previous = chunk;
+            let tail = (*arena).end();
+            while chunk < head {
+                let size = (*chunk).get_size();
+                if size < size_of::<AllocationList>() || chunk as usize + size > tail as usize {
+                    return Err(HeapCorruption::BadHeader);
+                }
+                previous = chunk;
+                chunk = (chunk as *mut u8).add(size) as *mut AllocationList;
+            }
+            if chunk != head {
+                // a chunk freed earlier may have been merged into the free chunk in front of it
+                if !(*previous).is_taken() {
+                    return Err(HeapCorruption::DoubleFree);
+                }
+                return Err(HeapCorruption::InvalidPointer);
+            }
+            if !(*head).is_taken() {
+                return Err(HeapCorruption::DoubleFree);
+            }
+        }
+        check(head)
+    }
+}
+
+// without the debug heap all of the above is free
+#[cfg(not(feature = "debug-heap"))]
+mod debug {
+    use super::{AllocationList, Arena, HeapCorruption};
+
+    pub fn mark_free_header(_head: *mut AllocationList) {}
+    pub fn mark_allocated(_head: *mut AllocationList, _requested: usize) {}
+    pub fn mark_free(_head: *mut AllocationList) {}
+    pub fn poison_header(_head: *mut AllocationList) {}
+    pub fn poison(_from: *mut u8, _to: *mut u8) {}
+    pub fn check(_head: *mut AllocationList) -> Result<(), HeapCorruption> {
+        Ok(())
+    }
+    pub fn check_taken(_arena: *mut Arena, _head: *mut AllocationList) -> Result<(), HeapCorruption> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "debug-heap")]
+pub use debug::{POISON_BYTE, REDZONE_BYTE};
+
+// Stop the kernel over a corrupted chunk, carrying on would only spread the damage
+fn corrupted(kind: HeapCorruption, chunk: *mut AllocationList) -> ! {
+    report(kind, chunk);
+    panic!("kernel heap corrupted");
+}
+
+// The heap is a list of arenas, each one a run of pages from the page allocator with this header
+// at the front and AllocationList chunks filling the rest. The first arena comes from init and
+// stays for good, later ones get added when nothing fits and handed back once they're empty again
+#[repr(C)]
+struct Arena {
+    next: *mut Arena,
+    pages: usize,
+}
+
+impl Arena {
+    // first chunk, right after the header
+    fn chunks(&self) -> *mut AllocationList {
+        unsafe { (self as *const Arena).add(1) as *mut AllocationList }
+    }
+    // one past the last byte of the arena
+    fn end(&self) -> *mut AllocationList {
+        (self as *const Arena as usize + self.pages * PAGE_SIZE) as *mut AllocationList
+    }
+    fn contains(&self, address: usize) -> bool {
+        address >= self.chunks() as usize && address < self.end() as usize
+    }
+    // true if the whole arena has coalesced back into a single free chunk
+    fn is_empty(&self) -> bool {
+        unsafe {
+            let first = self.chunks();
+            !(*first).is_taken() && (first as *mut u8).add((*first).get_size()) as *mut AllocationList == self.end()
+        }
+    }
+}
+
+// Pages the heap starts with, and the least it grows by at a time
+const INITIAL_ARENA_PAGES: usize = 64;
+const MIN_GROWTH_PAGES: usize = 16;
+
 // The head of kernel memory allocation
-static mut KERNEL_MEMORY_HEAD: *mut AllocationList = null_mut();
-//Track memory footprint to see if more pages need to be allocated to the kernel
+static mut KERNEL_ARENAS: *mut Arena = null_mut();
+//Track memory footprint (pages across every arena) to see if more pages need to be allocated to the kernel
 static mut KERNEL_MEMORY_ALLOCATION_SIZE: usize = 0;
 static mut KERNEL_MEMORY_PAGE_TABLE: *mut PageTable = null_mut();
+// Held by whoever is walking or changing the arenas, every hart shares the one heap.
+// Lock order is heap, then page (growing and releasing arenas goes to the page allocator)
+static HEAP_LOCK: SpinLock = SpinLock::ranked(HEAP_CLASS);
+// start of the first arena
 pub fn get_head() -> *mut u8 {
-    unsafe {KERNEL_MEMORY_HEAD as *mut u8}
+    unsafe {KERNEL_ARENAS as *mut u8}
 }
 pub fn get_page_table() -> *mut PageTable {
     unsafe {KERNEL_MEMORY_PAGE_TABLE as *mut PageTable}
@@ -58,93 +282,360 @@ pub fn get_page_table() -> *mut PageTable {
 pub fn get_number_allocations() -> usize {
     unsafe {KERNEL_MEMORY_ALLOCATION_SIZE}
 }
+// how many arenas the heap is spread over right now
+pub fn number_of_arenas() -> usize {
+    let mut count = 0;
+    for_each_arena(|_, _| count += 1);
+    count
+}
+
+// Call f with the start address and page count of every arena, e.g. to map them all.
+// The heap is locked meanwhile, so f mustn't allocate from it
+pub fn for_each_arena<F: FnMut(usize, usize)>(mut f: F) {
+    let _guard = HEAP_LOCK.lock();
+    unsafe {
+        let mut arena = KERNEL_ARENAS;
+        while !arena.is_null() {
+            f(arena as usize, (*arena).pages);
+            arena = (*arena).next;
+        }
+    }
+}
+
+// Set up an arena over freshly allocated pages, all of it one free chunk
+unsafe fn make_arena(memory: *mut u8, pages: usize) -> *mut Arena {
+    unsafe {
+        let arena = memory as *mut Arena;
+        (*arena).next = null_mut();
+        (*arena).pages = pages;
+        make_free_chunk((*arena).chunks(), pages * PAGE_SIZE - size_of::<Arena>());
+        debug::mark_free((*arena).chunks());
+        arena
+    }
+}
+
+// The arena a chunk address falls in, null if it isn't heap memory at all
+fn find_arena(address: usize) -> *mut Arena {
+    unsafe {
+        let mut arena = KERNEL_ARENAS;
+        while !arena.is_null() && !(*arena).contains(address) {
+            arena = (*arena).next;
+        }
+        arena
+    }
+}
 
 // intialize kernel memory. user processes should not be allowed to do this
 pub fn init() {
+    let _guard = HEAP_LOCK.lock();
     unsafe {
         // allocate 64 kernel pages
-        let kernel_allocation = zalloc(64);
+        let kernel_allocation = zalloc(INITIAL_ARENA_PAGES);
         assert!(!kernel_allocation.is_null());
-        KERNEL_MEMORY_ALLOCATION_SIZE = 64;
-        KERNEL_MEMORY_HEAD = kernel_allocation as *mut AllocationList;
-        (*KERNEL_MEMORY_HEAD).set_free();
-        (*KERNEL_MEMORY_HEAD).set_size(KERNEL_MEMORY_ALLOCATION_SIZE * PAGE_SIZE);
+        KERNEL_MEMORY_ALLOCATION_SIZE = INITIAL_ARENA_PAGES;
+        KERNEL_ARENAS = make_arena(kernel_allocation, INITIAL_ARENA_PAGES);
         // since the page table is tracking our memory footprint dynamically it also needs memory allocated for it
         KERNEL_MEMORY_PAGE_TABLE = zalloc(1) as *mut PageTable;
+        // small allocations live in slabs, which go stale along with the heap
+        slab::init();
+
+    }
+}
+
+// Ask the page allocator for a new arena with room for size bytes at the given alignment and
+// add it to the end of the list, so older arenas keep getting tried first
+fn grow(size: usize, align: usize) -> *mut Arena {
+    // worst case the chunk needs align bytes of padding plus a header for the padding chunk
+    let needed = size.saturating_add(align).saturating_add(size_of::<Arena>() + size_of::<AllocationList>());
+    let pages = needed.div_ceil(PAGE_SIZE).max(MIN_GROWTH_PAGES);
+    let memory = page::alloc(pages);
+    if memory.is_null() {
+        return null_mut();
+    }
+    unsafe {
+        // keep the kernel's table covering the whole heap, initialize_kernel_memory mapped the rest.
+        // No pages left for its tables fails the whole grow, map_identity takes back what it built
+        let kernel_root = KERNEL_MEMORY_PAGE_TABLE;
+        if !kernel_root.is_null() {
+            let end = memory as usize + pages * PAGE_SIZE;
+            if address_space::map_identity(kernel_root, memory as usize, end, page::PageTableEntryBits::ReadWrite.as_i64()).is_err() {
+                page::dealloc(memory);
+                return null_mut();
+            }
+        }
+        let arena = make_arena(memory, pages);
+        let mut last = KERNEL_ARENAS;
+        while !(*last).next.is_null() {
+            last = (*last).next;
+        }
+        (*last).next = arena;
+        KERNEL_MEMORY_ALLOCATION_SIZE += pages;
+        arena
+    }
+}
 
+// Give an empty arena back to the page allocator. The first arena is never released
+unsafe fn release(arena: *mut Arena) {
+    unsafe {
+        let mut previous = KERNEL_ARENAS;
+        while !previous.is_null() && (*previous).next != arena {
+            previous = (*previous).next;
+        }
+        if previous.is_null() {
+            return;
+        }
+        (*previous).next = (*arena).next;
+        let pages = (*arena).pages;
+        KERNEL_MEMORY_ALLOCATION_SIZE -= pages;
+        // take it back out of the kernel's table so stray pointers into it fault
+        let kernel_root = KERNEL_MEMORY_PAGE_TABLE;
+        if !kernel_root.is_null() {
+            address_space::unmap_identity(kernel_root, arena as usize, arena as usize + pages * PAGE_SIZE);
+        }
+        page::dealloc(arena as *mut u8);
     }
 }
 
-// allocate memory based on bytes
+// allocate memory based on bytes. 0 bytes gets the smallest chunk there is rather than null
+#[inline(never)]
 pub fn kernel_malloc(size: usize) -> *mut u8 {
+    let caller = cpu::return_address();
+    traced_malloc(size, MIN_ALIGN, caller)
+}
+
+// Write a header for a free chunk of the given size (header included) at the address
+unsafe fn make_free_chunk(address: *mut AllocationList, size: usize) {
+    unsafe {
+        (*address).flags_size = 0;
+        (*address).set_size(size);
+        debug::mark_free_header(address);
+    }
+}
+
+// Shrink a taken chunk to size bytes (header included) and turn the tail into a free chunk,
+// as long as the tail is big enough to hold a header of its own
+unsafe fn split_chunk(head: *mut AllocationList, size: usize) {
     unsafe {
-       let size = align_value(size, 3) + size_of::<AllocationList>();
-       let mut head = KERNEL_MEMORY_HEAD;
-       let tail = (head as *mut u8).add(KERNEL_MEMORY_ALLOCATION_SIZE * PAGE_SIZE) as *mut AllocationList;
+        let remainder = (*head).get_size() - size;
+        if remainder > size_of::<AllocationList>() {
+            make_free_chunk((head as *mut u8).add(size) as *mut AllocationList, remainder);
+            (*head).set_size(size);
+        }
+    }
+}
+
+// First fit inside a single arena, size includes the header
+unsafe fn arena_malloc(arena: *mut Arena, size: usize, align: usize) -> *mut u8 {
+    let header = size_of::<AllocationList>();
+    unsafe {
+       let mut head = (*arena).chunks();
+       let tail = (*arena).end();
         while head < tail {
             // while space in kernel memory left and more space to allocate, allocate chunks chunks
             // by iterating through linked list
-            if !(*head).is_taken() && size <= (*head).get_size() {
-                let chunk_size = (*head).get_size();
-                let remainder = chunk_size - size;
-                (*head).set_taken();
-                if remainder > size_of::<AllocationList>() {
-                    let next = (head as *mut u8).add(size)
-                        as *mut AllocationList;
-                    (*next).set_free();
-                    (*next).set_size(remainder);
-                    (*head).set_size(size);
+            if !(*head).is_taken() {
+                // chunks sit on 8 byte boundaries, so for bigger alignments the header may have to
+                // move up. the gap left in front is a multiple of 8, so it can always become a free
+                // chunk of its own (even if that's just a header)
+                let mut payload = align_value(head.add(1) as usize, align.trailing_zeros() as usize);
+                // (unless the header is bigger than 8 bytes, then the gap may need to grow to fit one)
+                while payload - header - head as usize != 0 && payload - header - (head as usize) < header {
+                    payload += align;
                 }
-                else {
-                    // give the head the whole chunk if the remaining free space is bigger than how much your allocating
-                    (*head).set_size(chunk_size);
+                let padding = payload - header - head as usize;
+                let chunk_size = (*head).get_size();
+                if padding + size <= chunk_size {
+                    if padding > 0 {
+                        make_free_chunk(head, padding);
+                        head = (head as *mut u8).add(padding) as *mut AllocationList;
+                        make_free_chunk(head, chunk_size - padding);
+                    }
+                    (*head).set_taken();
+                    // give the head the whole chunk if the remaining free space is too small for another header
+                    split_chunk(head, size);
+                    return head.add(1) as *mut u8;
                 }
-                return head.add(1) as *mut u8;
-            }
-            else {
-               // since chunk wasn't free, move on to next chunk
-               head = (head as *mut u8).add((*head).get_size()) as *mut AllocationList;
             }
+            // since chunk wasn't free (or big enough), move on to next chunk
+            head = (head as *mut u8).add((*head).get_size()) as *mut AllocationList;
         }
         // If we go through all the addresses and don't find any chunks we can allocate, return a null ptr
         null_mut()
     }
 }
 
+// allocate memory based on bytes, with the returned address a multiple of align (a power of two).
+// When no arena has room the heap grows, so this only gives back null once physical memory runs out
+#[inline(never)]
+pub fn kernel_malloc_aligned(size: usize, align: usize) -> *mut u8 {
+    let caller = cpu::return_address();
+    traced_malloc(size, align, caller)
+}
+
+// the public allocation functions capture the caller the same way page::alloc does
+fn traced_malloc(size: usize, align: usize, caller: usize) -> *mut u8 {
+    assert!(align.is_power_of_two());
+    let pointer = {
+        let _guard = HEAP_LOCK.lock();
+        unsafe { malloc_locked(size, align) }
+    };
+    if !pointer.is_null() {
+        alloctrace::record(Event::Malloc, pointer as usize, size, caller);
+    }
+    pointer
+}
+
+// kernel_malloc_aligned for when the heap lock is already held
+unsafe fn malloc_locked(size: usize, align: usize) -> *mut u8 {
+    let requested = size;
+    let size = chunk_size(size);
+    unsafe {
+        let mut pointer: *mut u8 = null_mut();
+        let mut arena = KERNEL_ARENAS;
+        while !arena.is_null() && pointer.is_null() {
+            pointer = arena_malloc(arena, size, align);
+            arena = (*arena).next;
+        }
+        if pointer.is_null() && !KERNEL_ARENAS.is_null() {
+            let arena = grow(size, align);
+            if !arena.is_null() {
+                pointer = arena_malloc(arena, size, align);
+            }
+        }
+        if !pointer.is_null() {
+            debug::mark_allocated((pointer as *mut AllocationList).offset(-1), requested);
+        }
+        pointer
+    }
+}
+
  // allocate zeroed memory based on number of bytes
+ #[inline(never)]
  pub fn kernel_zmalloc(size: usize) -> *mut u8 {
-     let aligned_size = align_value(size, 3);
-     let ret = kernel_malloc(size);
+     let caller = cpu::return_address();
+     traced_zmalloc(size, MIN_ALIGN, caller)
+ }
+
+ // allocate zeroed memory based on number of bytes, aligned to align
+ #[inline(never)]
+ pub fn kernel_zmalloc_aligned(size: usize, align: usize) -> *mut u8 {
+     let caller = cpu::return_address();
+     traced_zmalloc(size, align, caller)
+ }
+
+ fn traced_zmalloc(size: usize, align: usize, caller: usize) -> *mut u8 {
+     let ret = traced_malloc(size, align, caller);
      if !ret.is_null() {
-         for i in 0..size {
-             unsafe {
-                 (*ret.add(i)) = 0;
-             }
-         }
+         // just the bytes asked for, anything after them may be the debug heap's red zone
+         unsafe { core::ptr::write_bytes(ret, 0, size) };
      }
-     return ret;
+     ret
  }
 
+// Resize an allocation, keeping its contents. Shrinking always happens in place, growing happens
+// in place when the chunk right after is free and big enough, otherwise the data moves to a new chunk
+#[inline(never)]
+pub fn kernel_realloc(address_pointer: *mut u8, align: usize, new_size: usize) -> *mut u8 {
+    let caller = cpu::return_address();
+    if address_pointer.is_null() {
+        return traced_malloc(new_size, align, caller);
+    }
+    let pointer = resize(address_pointer, align, new_size, caller);
+    if !pointer.is_null() {
+        alloctrace::record(Event::Realloc, pointer as usize, new_size, caller);
+    }
+    pointer
+}
+
+// kernel_realloc's insides, it records the free of the old chunk itself when the data moves
+fn resize(address_pointer: *mut u8, align: usize, new_size: usize, caller: usize) -> *mut u8 {
+    let header = size_of::<AllocationList>();
+    let needed = chunk_size(new_size);
+    let _guard = HEAP_LOCK.lock();
+    unsafe {
+        let head = (address_pointer as *mut AllocationList).offset(-1);
+        let arena = find_arena(head as usize);
+        if arena.is_null() {
+            return null_mut();
+        }
+        if let Err(kind) = debug::check_taken(arena, head) {
+            corrupted(kind, head);
+        }
+        let chunk_size = (*head).get_size();
+        if needed <= chunk_size {
+            // the tail may become a free chunk, which the debug heap wants poisoned
+            debug::poison((head as *mut u8).add(needed), (head as *mut u8).add(chunk_size));
+            split_chunk(head, needed);
+            coalesce_arena(arena);
+            debug::mark_allocated(head, new_size);
+            return address_pointer;
+        }
+        let next = (head as *mut u8).add(chunk_size) as *mut AllocationList;
+        if next < (*arena).end() && !(*next).is_taken() && chunk_size + (*next).get_size() >= needed {
+            // swallow the free neighbour, then hand back whatever we didn't need
+            (*head).set_size(chunk_size + (*next).get_size());
+            split_chunk(head, needed);
+            debug::mark_allocated(head, new_size);
+            return address_pointer;
+        }
+        let moved = malloc_locked(new_size, align);
+        if !moved.is_null() {
+            core::ptr::copy_nonoverlapping(head.add(1) as *const u8, moved, (chunk_size - header).min(new_size));
+            let freed = free_locked(head);
+            alloctrace::record(Event::Free, address_pointer as usize, freed, caller);
+        }
+        moved
+    }
+}
+
 // free kernel allocated memory
+#[inline(never)]
 pub fn kernel_free(address_pointer: *mut u8) {
+    let caller = cpu::return_address();
+    if !address_pointer.is_null() {
+        let freed = {
+            let _guard = HEAP_LOCK.lock();
+            unsafe { free_locked((address_pointer as *mut AllocationList).offset(-1)) }
+        };
+        if freed != 0 {
+            alloctrace::record(Event::Free, address_pointer as usize, freed, caller);
+        }
+    }
+}
+
+// kernel_free for when the heap lock is already held, given the chunk's header. Gives back how
+// many bytes the chunk had past its header, 0 if it wasn't something the heap handed out
+unsafe fn free_locked(memory_pointer: *mut AllocationList) -> usize {
     unsafe {
-        if !address_pointer.is_null() {
-            let memory_pointer = (address_pointer as *mut AllocationList).offset(-1);
-            if (*memory_pointer).is_taken() {
-                (*memory_pointer).set_free();
-            // free space pattern: want to coalesce smaller chunks into a bigger chunk of free memory
-            }
-            coalesce();
+        // not something the heap handed out, leave it alone
+        let arena = find_arena(memory_pointer as usize);
+        if arena.is_null() {
+            return 0;
         }
+        // the debug heap refuses double frees, stray pointers and chunks that got written past
+        if let Err(kind) = debug::check_taken(arena, memory_pointer) {
+            corrupted(kind, memory_pointer);
+        }
+        let freed = (*memory_pointer).get_size() - size_of::<AllocationList>();
+        if (*memory_pointer).is_taken() {
+            (*memory_pointer).set_free();
+            debug::mark_free(memory_pointer);
+        // free space pattern: want to coalesce smaller chunks into a bigger chunk of free memory
+        }
+        coalesce_arena(arena);
+        if arena != KERNEL_ARENAS && (*arena).is_empty() {
+            release(arena);
+        }
+        freed
     }
 }
 
-// Take the kernel memory head and traverse it looking for contiguous addresses that are free
+// Take an arena and traverse it looking for contiguous addresses that are free
 // if 2 contiguous addresses are free, coalesce them into one address
-pub fn coalesce() {
+unsafe fn coalesce_arena(arena: *mut Arena) {
     unsafe {
-        let mut head = KERNEL_MEMORY_HEAD;
-        let tail = (head as *mut u8).add(KERNEL_MEMORY_ALLOCATION_SIZE * PAGE_SIZE) as *mut AllocationList;
+        let mut head = (*arena).chunks();
+        let tail = (*arena).end();
         while head < tail {
             // Get the next address
             let next = (head as *mut u8).add((*head).get_size()) as *mut AllocationList;
@@ -157,27 +648,79 @@ pub fn coalesce() {
                 break
             }
             // if they are both free, coalesce them into one address by setting the size of the
-            // head to go over the next addresses's size
+            // head to go over the next addresses's size. stay on the head since whatever comes
+            // after might be free too
             if !(*head).is_taken() && !(*next).is_taken() {
-                (*head).set_size((*head).get_size() + (*next).get_size())
+                let merged = (*head).get_size() + (*next).get_size();
+                debug::poison_header(next);
+                (*head).set_size(merged);
+                continue;
             }
             // go to the next address
-            head = (head as *mut u8).add((*head).get_size()) as *mut AllocationList;
+            head = next;
         }
     }
 }
 
+// coalesce every arena
+pub fn coalesce() {
+    let _guard = HEAP_LOCK.lock();
+    unsafe {
+        let mut arena = KERNEL_ARENAS;
+        while !arena.is_null() {
+            coalesce_arena(arena);
+            arena = (*arena).next;
+        }
+    }
+}
 
 
+
+// Walk every chunk of every arena and report the ones that look corrupted. Without the debug heap
+// only the chunk sizes can be checked. Returns how many problems turned up
+pub fn heapcheck() -> usize {
+    let mut problems = 0;
+    let _guard = HEAP_LOCK.lock();
+    unsafe {
+        let mut arena = KERNEL_ARENAS;
+        while !arena.is_null() {
+            let mut head = (*arena).chunks();
+            let tail = (*arena).end();
+            while head < tail {
+                let size = (*head).get_size();
+                if size < size_of::<AllocationList>() || !size.is_multiple_of(8) || head as usize + size > tail as usize {
+                    // can't trust anything after this in the arena
+                    report(HeapCorruption::BadHeader, head);
+                    problems += 1;
+                    break;
+                }
+                if let Err(kind) = debug::check(head) {
+                    report(kind, head);
+                    problems += 1;
+                }
+                head = (head as *mut u8).add(size) as *mut AllocationList;
+            }
+            arena = (*arena).next;
+        }
+    }
+    problems
+}
+
 // print for debugging ( this is pulled directly from the tutorial )
 pub fn print_kernel_memory_table() {
+    let _guard = HEAP_LOCK.lock();
     unsafe {
-        let mut head = KERNEL_MEMORY_HEAD;
-        let tail = (head as *mut u8).add(KERNEL_MEMORY_ALLOCATION_SIZE * PAGE_SIZE) as *mut AllocationList;
+        let mut arena = KERNEL_ARENAS;
         println!("address, size, free_status");
-        while head < tail {
-            println!("{:p}, {:<10}, {}", head, (*head).get_size(), (*head).is_taken());
-            head = (head as *mut u8).add((*head).get_size()) as *mut AllocationList;
+        while !arena.is_null() {
+            println!("arena {:p}, {} pages", arena, (*arena).pages);
+            let mut head = (*arena).chunks();
+            let tail = (*arena).end();
+            while head < tail {
+                println!("{:p}, {:<10}, {}", head, (*head).get_size(), (*head).is_taken());
+                head = (head as *mut u8).add((*head).get_size()) as *mut AllocationList;
+            }
+            arena = (*arena).next;
         }
     }
 }
@@ -199,27 +742,144 @@ pub fn print_kernel_memory_table() {
 // we may need to edit this or use some other method to provide a syscall interface for
 // processes to ask for heap allocated memory (i.e. to malloc)
 use core::alloc::{GlobalAlloc, Layout};
-struct KernelAllocator;
+pub struct KernelAllocator;
+
+// The size class a layout goes to. The debug heap's red zones and poison only cover heap chunks,
+// so with it on everything goes to the heap instead
+#[cfg(not(feature = "debug-heap"))]
+fn slab_class(size: usize, align: usize) -> Option<slab::SlabCache> {
+    slab::size_class(size, align)
+}
+
+#[cfg(feature = "debug-heap")]
+fn slab_class(_size: usize, _align: usize) -> Option<slab::SlabCache> {
+    None
+}
 
+// Small allocations go to the slab size classes, the rest to the heap. dealloc gets the same
+// layout alloc did, so it can tell which of the two a pointer came from the same way
 unsafe impl GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        kernel_zmalloc(layout.size())
+        let pointer = match slab_class(layout.size(), layout.align()) {
+            Some(cache) => cache.alloc(),
+            None => kernel_malloc_aligned(layout.size(), layout.align().max(MIN_ALIGN)),
+        };
+        if pointer.is_null() {
+            allocation_failed(layout);
+        }
+        pointer
     }
-    unsafe fn dealloc(&self, pointer: *mut u8, _layout: Layout) {
-        kernel_free(pointer);
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if slab_class(layout.size(), layout.align()).is_some() {
+            let pointer = unsafe { self.alloc(layout) };
+            if !pointer.is_null() {
+                unsafe { core::ptr::write_bytes(pointer, 0, layout.size()) };
+            }
+            return pointer;
+        }
+        let pointer = kernel_zmalloc_aligned(layout.size(), layout.align().max(MIN_ALIGN));
+        if pointer.is_null() {
+            allocation_failed(layout);
+        }
+        pointer
+    }
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        match slab_class(layout.size(), layout.align()) {
+            Some(cache) => cache.free(pointer),
+            None => kernel_free(pointer),
+        }
+    }
+    unsafe fn realloc(&self, pointer: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let class = slab_class(layout.size(), layout.align());
+        if class.is_some() && class == slab_class(new_size, layout.align()) {
+            // the object already has room for new_size
+            return pointer;
+        }
+        if slab_class(layout.size(), layout.align()).is_none()
+            && slab_class(new_size, layout.align()).is_none() {
+            let moved = kernel_realloc(pointer, layout.align().max(MIN_ALIGN), new_size);
+            if moved.is_null() {
+                allocation_failed(unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) });
+            }
+            return moved;
+        }
+        // crossing between a slab and the heap or between size classes means a copy
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let moved = unsafe { self.alloc(new_layout) };
+        if !moved.is_null() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(pointer, moved, layout.size().min(new_size));
+                self.dealloc(pointer, layout);
+            }
+        }
+        moved
     }
 }
 
 #[global_allocator]
 static GLOBAL: KernelAllocator = KernelAllocator;
 
-// undefined to call alloc on a null_ptr, since we are doing this in the kernel the entire kernel
-// should panic (would crash anyways) so we can more easily find where this happens
-// okay actually we have to setup traps and errors properly to do this, we will implement this later
+// Running out of memory where nothing is ready for it: GlobalAlloc calls this when an allocation
+// outside of fallible comes up empty (Box::new, Vec::push...), so the panic says what was asked
+// for and how full memory is rather than just that the alloc crate gave up. Kernel code that can't
+// carry on without some allocation can call it itself
 pub fn kernel_alloc_error(layout: Layout) -> ! {
-    panic!(
-        "[ERROR] Kernel failed to allocate {} bytes with {} byte-alignment.",
-        layout.size(),
-        layout.align()
-    )
+    print_alloc_failure(layout);
+    panic!("out of memory")
+}
+
+// Whether each hart is inside fallible
+static FALLIBLE: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+
+fn allocation_failed(layout: Layout) {
+    if !FALLIBLE[cpu::hart_id()].load(Ordering::Relaxed) {
+        kernel_alloc_error(layout);
+    }
+}
+
+// Run f with failed allocations handed back as null, so try_reserve and the like get to report
+// them, instead of going to kernel_alloc_error. Interrupts stay off so the thread can't move to
+// another hart halfway through
+pub fn fallible<R>(f: impl FnOnce() -> R) -> R {
+    let interrupts = cpu::interrupts_off();
+    let hart = cpu::hart_id();
+    let was_fallible = FALLIBLE[hart].swap(true, Ordering::Relaxed);
+    let result = f();
+    FALLIBLE[hart].store(was_fallible, Ordering::Relaxed);
+    cpu::restore_interrupts(interrupts);
+    result
+}
+
+// Say what couldn't be allocated and how full memory is. The heap lock may be held by whoever ran
+// out, so this doesn't take it
+pub fn print_alloc_failure(layout: Layout) {
+    println!("[ERROR] Kernel failed to allocate {} bytes with {} byte-alignment on hart {}.",
+        layout.size(), layout.align(), cpu::hart_id());
+    println!("        heap: {} pages, page allocator: {} of {} pages in use",
+        get_number_allocations(), page::allocated_pages(), page::total_pages());
+}
+
+// For code that has to survive running out of memory, these hand the value back instead of
+// panicking. Vec and String have try_reserve for the same thing, called inside fallible
+pub fn try_box<T>(value: T) -> Result<Box<T>, T> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+    let pointer = fallible(|| unsafe { alloc::alloc::alloc(layout) }) as *mut T;
+    if pointer.is_null() {
+        return Err(value);
+    }
+    unsafe {
+        pointer.write(value);
+        Ok(Box::from_raw(pointer))
+    }
+}
+
+pub fn try_push<T>(vec: &mut Vec<T>, value: T) -> Result<(), T> {
+    if fallible(|| vec.try_reserve(1)).is_err() {
+        return Err(value);
+    }
+    vec.push(value);
+    Ok(())
 }
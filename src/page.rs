@@ -3,18 +3,75 @@
 //! Haven't really decided on whether or not to include partitioned global address space stuff here, or keep that as an abstraction over this
 use core::{mem::size_of, ptr::null_mut};
 use crate::{println, print};
+use crate::sync::{SpinLock, PAGE_CLASS};
+use crate::alloctrace::{self, Event};
+use crate::cpu;
+use crate::fdt::{self, Fdt};
+use crate::tlb;
+use crate::page_box::PageBox;
 
 unsafe extern "C" {
     static HEAP_START: usize;
     static HEAP_SIZE: usize;
+    static TEXT_START: usize;
+    static KERNEL_STACK_END: usize;
 }
 
-// allocated memory start address
-static mut ALLOC_START: usize = 0b0;
 const PAGE_ORDER: usize = 12;
 // size of a page (2**12 bytes or 4096 bytes)
 pub const PAGE_SIZE: usize = 0b1 << 12;
 
+// A range of physical memory, used both for the RAM banks the allocator
+// manages and the reservations carved out of them
+#[derive(Clone, Copy)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub size: usize,
+}
+
+impl MemoryRegion {
+    pub const EMPTY: MemoryRegion = MemoryRegion { start: 0, size: 0 };
+
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.size != 0 && start < self.end() && self.start < end
+    }
+}
+
+// How many discontiguous banks of RAM the allocator can manage (the Orangepi splits its DRAM in two)
+pub const MAX_BANKS: usize = 4;
+
+// One contiguous bank of RAM. Descriptors cover every page of the bank, pages that are
+// reserved (firmware, the kernel, the descriptors themselves...) are just marked that way
+#[derive(Clone, Copy)]
+struct Bank {
+    // physical address of the bank's first page
+    start: usize,
+    pages: usize,
+    descriptors: *mut Page,
+}
+
+impl Bank {
+    const fn empty() -> Self {
+        Bank { start: 0, pages: 0, descriptors: null_mut() }
+    }
+    fn end(&self) -> usize {
+        self.start + self.pages * PAGE_SIZE
+    }
+    fn contains(&self, address: usize) -> bool {
+        address >= self.start && address < self.end()
+    }
+}
+
+static mut BANKS: [Bank; MAX_BANKS] = [Bank::empty(); MAX_BANKS];
+static mut NUM_BANKS: usize = 0;
+
+fn banks() -> &'static [Bank] {
+    unsafe { &(&*core::ptr::addr_of!(BANKS))[..NUM_BANKS] }
+}
+
 // bit repsresentation of a page
 // (first bit sets whether taken or not,
 // second bit sets whether its the last)
@@ -23,6 +80,8 @@ pub enum PageBits {
     Empty = 0b0,
     Taken = 0b1 << 0,
     Last = 0b1 << 1,
+    // never handed out or freed, always set along with taken and last
+    Reserved = 0b1 << 2,
 }
 
 impl PageBits {
@@ -37,8 +96,12 @@ pub const fn align_value(value: usize, order: usize) -> usize {
     (value + order) & !order
 }
 
+#[repr(C)]
 pub struct Page {
     flags: u8,
+    // number of address spaces sharing the page. only kept on the first page of
+    // an allocation, copy on write sharing is done with single page allocations
+    references: u16,
 }
 
 impl Page {
@@ -59,53 +122,109 @@ impl Page {
     pub fn is_free (&self) -> bool {
         !self.is_taken()
     }
+    pub fn is_reserved(&self) -> bool {
+        self.flags & PageBits::Reserved.val() != 0
+    }
     pub fn clear(&mut self) {
         self.flags = PageBits::Empty.val();
+        self.references = 0;
     }
     pub fn set_flag(&mut self, flag: PageBits) {
         // This is how we actually set the bit value of the flags for the pages
         self.flags |= flag.val()
     }
+    pub fn references(&self) -> usize {
+        self.references as usize
+    }
+}
+
+// Held while the descriptors are being changed, any hart can allocate pages
+static PAGE_LOCK: SpinLock = SpinLock::ranked(PAGE_CLASS);
+
+// Get the descriptor of the page holding the address
+fn descriptor(address: usize) -> *mut Page {
+    let bank = banks().iter().find(|bank| bank.contains(address))
+        .expect("address outside the page allocator's memory");
+    unsafe { bank.descriptors.add((address - bank.start) / PAGE_SIZE) }
 }
 
 /// Pages at virtual addresses, without zeroing the start pointer
+#[inline(never)]
 pub fn alloc(pages: usize) -> *mut u8 {
+    let caller = cpu::return_address();
+    traced_alloc(pages, PAGE_SIZE, 0, caller)
+}
+
+/// Like alloc, but the first page's address is a multiple of alignment (a power of two, at least
+/// a page) and, when boundary isn't 0, the pages don't cross a multiple of boundary. Devices doing
+/// DMA tend to need both
+#[inline(never)]
+pub fn alloc_constrained(pages: usize, alignment: usize, boundary: usize) -> *mut u8 {
+    let caller = cpu::return_address();
+    traced_alloc(pages, alignment, boundary, caller)
+}
+
+// The public allocation functions each grab their own caller for the alloc trace, which only works
+// if they stay functions of their own (see cpu::return_address)
+fn traced_alloc(pages: usize, alignment: usize, boundary: usize, caller: usize) -> *mut u8 {
+    let pointer = find_pages(pages, alignment, boundary);
+    if !pointer.is_null() {
+        alloctrace::record(Event::PageAlloc, pointer as usize, pages * PAGE_SIZE, caller);
+    }
+    pointer
+}
+
+fn find_pages(pages: usize, alignment: usize, boundary: usize) -> *mut u8 {
     // Pages must be contiguous
     assert!(pages > 0);
-    unsafe {
-        // create the page structure
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
-        let pointer = HEAP_START as *mut Page;
-        // dfs through the pages
-        for i in 0..num_pages-pages {
-            // look for a free page
-            let mut found = false;
-            if (*pointer.add(i)).is_free() {
-                found = true;
-                for j in i..i+pages {
-                    // check if contiguous allocation for requested pages
-                    if (*pointer.add(j)).is_taken() {
-                        found = false;
-                        break
+    assert!(alignment.is_power_of_two() && alignment >= PAGE_SIZE);
+    let _guard = PAGE_LOCK.lock();
+    // allocations never straddle two banks, so try each one in turn
+    for bank in banks() {
+        if pages > bank.pages {
+            continue;
+        }
+        let pointer = bank.descriptors;
+        unsafe {
+            // dfs through the pages
+            for i in 0..=bank.pages-pages {
+                let address = bank.start + PAGE_SIZE * i;
+                let last_byte = address + PAGE_SIZE * pages - 1;
+                if !address.is_multiple_of(alignment)
+                    || (boundary != 0 && address / boundary != last_byte / boundary) {
+                    continue;
+                }
+                // look for a free page
+                let mut found = false;
+                if (*pointer.add(i)).is_free() {
+                    found = true;
+                    for j in i..i+pages {
+                        // check if contiguous allocation for requested pages
+                        if (*pointer.add(j)).is_taken() {
+                            found = false;
+                            break
+                        }
                     }
                 }
-            }
-            // If we found an available set of contiguous pages,
-            // we can set the flags to say it's taken and return
-            // the pointer. Otherwise, a page fault occurs
-            if found {
-                for k in 1..i+pages-1 {
-                    // Go through and set the contigous pages to taken
-                    (*pointer.add(k)).set_flag(PageBits::Taken);
+                // If we found an available set of contiguous pages,
+                // we can set the flags to say it's taken and return
+                // the pointer. Otherwise, a page fault occurs
+                if found {
+                    for k in i..i+pages-1 {
+                        // Go through and set the contigous pages to taken
+                        (*pointer.add(k)).set_flag(PageBits::Taken);
+                    }
+                    // This lets us know what the last page is
+                    (*pointer.add(i+pages-1)).set_flag(PageBits::Taken);
+                    (*pointer.add(i+pages-1)).set_flag(PageBits::Last);
+                    // whoever asked for the pages holds the only reference to them
+                    (*pointer.add(i)).references = 1;
+                    // Remember the page structure is just an abstraction
+                    // the kernel uses to keep track of memory allocation,
+                    // we return an address at the number of pages after the
+                    // start where we can start using memory
+                    return address as *mut u8;
                 }
-                // This lets us know what the last page is
-                (*pointer.add(i+pages-1)).set_flag(PageBits::Taken);
-                (*pointer.add(i+pages-1)).set_flag(PageBits::Last);
-                // Remember the page structure is just an abstraction
-                // the kernel uses to keep track of memory allocation,
-                // we return an address at the number of pages after the
-                // start where we can start using memory
-                return (ALLOC_START + PAGE_SIZE * i) as *mut u8;
             }
         }
     }
@@ -115,18 +234,27 @@ pub fn alloc(pages: usize) -> *mut u8 {
 
 /// Deallocate the page at the virt address
 /// note deallocating doesn't actually clear the memory, just the descriptor
+#[inline(never)]
 pub fn dealloc(pointer: *mut u8) {
+    let caller = cpu::return_address();
     assert!(!pointer.is_null());
+    let _guard = PAGE_LOCK.lock();
+    let pages = free_pages(pointer);
+    alloctrace::record(Event::PageDealloc, pointer as usize, pages * PAGE_SIZE, caller);
+}
+
+// dealloc for when the lock is already held, giving back how many pages it freed
+fn free_pages(pointer: *mut u8) -> usize {
+    let mut pages = 1;
     unsafe {
-        // grab the address of the first page
-        let address = HEAP_START + (pointer as usize - ALLOC_START) / PAGE_SIZE;
-        // check that page structure makes sense
-        assert!(address >= HEAP_START && address < HEAP_START + HEAP_SIZE);
-        let mut page_instance = address as *mut Page;
+        // grab the descriptor of the first page, this checks that the page structure makes sense
+        let mut page_instance = descriptor(pointer as usize);
+        assert!(!(*page_instance).is_reserved(), "tried to free reserved memory");
         // Loop through the pages and clear them until we hit the last page
         while (*page_instance).is_taken() && !(*page_instance).is_last() {
             (*page_instance).clear();
             page_instance = page_instance.add(1);
+            pages += 1;
         }
         // Try to prevent double frees
         assert!((*page_instance).is_last() == true,
@@ -134,11 +262,14 @@ pub fn dealloc(pointer: *mut u8) {
         // clear the last page
         (*page_instance).clear();
     }
+    pages
 }
 
 /// Allocate and zero one more or pages at virtual addresses, zeroing the start pointer
+#[inline(never)]
 pub fn zalloc(pages: usize) -> *mut u8 {
-    let ret = alloc(pages);
+    let caller = cpu::return_address();
+    let ret = traced_alloc(pages, PAGE_SIZE, 0, caller);
     // If the ALLOC_START pointer is not null, need to zero it
     if !ret.is_null() {
         let size = (PAGE_SIZE * pages) / 8;
@@ -157,6 +288,40 @@ pub fn zalloc(pages: usize) -> *mut u8 {
     ret
 }
 
+// How many holders share the allocation starting at the pointer
+pub fn reference_count(pointer: *mut u8) -> usize {
+    unsafe { (*descriptor(pointer as usize)).references() }
+}
+
+// Take another reference to an allocation, e.g. when a page gets shared copy on write
+pub fn add_reference(pointer: *mut u8) {
+    let _guard = PAGE_LOCK.lock();
+    unsafe {
+        let page_instance = descriptor(pointer as usize);
+        assert!((*page_instance).is_taken(), "reference taken to a free page");
+        (*page_instance).references += 1;
+    }
+}
+
+// Drop a reference to an allocation, deallocating it once nobody holds it.
+// Returns how many references are left
+#[inline(never)]
+pub fn release(pointer: *mut u8) -> usize {
+    let caller = cpu::return_address();
+    let _guard = PAGE_LOCK.lock();
+    unsafe {
+        let page_instance = descriptor(pointer as usize);
+        assert!((*page_instance).references > 0, "page released more times than it was referenced");
+        (*page_instance).references -= 1;
+        let remaining = (*page_instance).references();
+        if remaining == 0 {
+            let pages = free_pages(pointer);
+            alloctrace::record(Event::PageDealloc, pointer as usize, pages * PAGE_SIZE, caller);
+        }
+        remaining
+    }
+}
+
 // Allocate zero or more pages in the partitioned global address space.
 // note that like all page grained allocations, will allocate different
 // physical memory on different physical machines
@@ -168,16 +333,152 @@ pub fn galloc(pages: usize, n_nodes: usize, node_names: *mut u8) -> *mut u8 {
     0 as *mut u8
 }
 
+// How many reservations init takes out of the DTB's banks, past that they get dropped with a warning
+pub const MAX_RESERVED: usize = 32;
+
+// A fixed number of regions, collected before there's any heap to put them on
+struct RegionList<const N: usize> {
+    regions: [MemoryRegion; N],
+    length: usize,
+}
+
+impl<const N: usize> RegionList<N> {
+    const fn new() -> Self {
+        RegionList { regions: [MemoryRegion::EMPTY; N], length: 0 }
+    }
+
+    fn push(&mut self, region: MemoryRegion) {
+        if region.size == 0 {
+            return;
+        }
+        match self.regions.get_mut(self.length) {
+            Some(slot) => {
+                *slot = region;
+                self.length += 1;
+            }
+            None => println!("[WARN] too many memory regions, ignoring 0x{:x} -> 0x{:x}", region.start, region.end()),
+        }
+    }
+
+    fn as_slice(&self) -> &[MemoryRegion] {
+        &self.regions[..self.length]
+    }
+}
+
+// Start the allocator over on the RAM banks the boot DTB describes, minus the kernel and whatever
+// the DTB says is spoken for. Without a DTB the linker's heap is the only bank
 pub fn init() {
-    unsafe {
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
-        let pointer = HEAP_START as *mut Page;
-        // clear all pages
-        for i in 0..num_pages {
-            (*pointer.add(i)).clear();
+    match fdt::boot() {
+        Some(fdt) => init_from_fdt(&fdt),
+        None => init_regions(&[heap()], &[kernel_image()]),
+    }
+}
+
+// Every /memory node's banks, and reserved: the kernel image, the DTB itself, its memreserve
+// entries and /reserved-memory nodes (OpenSBI puts itself there), and the initrd from /chosen.
+// Page 0 goes too, a page there would look just like a failed allocation
+pub fn init_from_fdt(fdt: &Fdt) {
+    let mut memory = RegionList::<MAX_BANKS>::new();
+    let mut reserved = RegionList::<MAX_RESERVED>::new();
+    reserved.push(MemoryRegion { start: 0, size: PAGE_SIZE });
+    reserved.push(kernel_image());
+    reserved.push(MemoryRegion { start: fdt.address(), size: fdt.size() });
+    for (start, size) in fdt.reservations() {
+        reserved.push(MemoryRegion { start, size });
+    }
+    let (mut initrd_start, mut initrd_end) = (0, 0);
+    fdt.walk(|node, property, value| {
+        match property {
+            "reg" if node.is("/memory") => {
+                for (start, size) in fdt::reg(node, value) {
+                    memory.push(MemoryRegion { start, size });
+                }
+            }
+            // children of /reserved-memory without a reg are asking to be allocated somewhere,
+            // which nobody here does
+            "reg" if node.path.len() == 3 && matches!(node.path[1], "reserved-memory") => {
+                for (start, size) in fdt::reg(node, value) {
+                    reserved.push(MemoryRegion { start, size });
+                }
+            }
+            "linux,initrd-start" if node.is("/chosen") => initrd_start = fdt::read_number(value).unwrap_or(0) as usize,
+            "linux,initrd-end" if node.is("/chosen") => initrd_end = fdt::read_number(value).unwrap_or(0) as usize,
+            _ => {}
+        }
+    });
+    if initrd_end > initrd_start {
+        reserved.push(MemoryRegion { start: initrd_start, size: initrd_end - initrd_start });
+    }
+    if memory.length == 0 {
+        println!("[WARN] the DTB has no /memory, falling back on the linker's heap");
+        memory.push(heap());
+    }
+    init_regions(memory.as_slice(), reserved.as_slice());
+}
+
+// Everything between the end of the kernel's boot stack and the end of RAM, per virt.lds
+pub fn heap() -> MemoryRegion {
+    unsafe { MemoryRegion { start: HEAP_START, size: HEAP_SIZE } }
+}
+
+// The kernel's text through the end of its boot stack
+pub fn kernel_image() -> MemoryRegion {
+    unsafe { MemoryRegion { start: TEXT_START, size: KERNEL_STACK_END - TEXT_START } }
+}
+
+// Set the allocator up from the machine's RAM banks minus everything that's already spoken
+// for: firmware (OpenSBI), the DTB, an initrd, the kernel image, mmio holes. Each bank keeps
+// its descriptors in the first stretch of it that isn't reserved
+pub fn init_regions(memory: &[MemoryRegion], reserved: &[MemoryRegion]) {
+    let _guard = PAGE_LOCK.lock();
+    let mut num_banks = 0;
+    for region in memory.iter().take(MAX_BANKS) {
+        let start = align_value(region.start, PAGE_ORDER);
+        let end = region.end() & !(PAGE_SIZE - 1);
+        if end <= start {
+            continue;
+        }
+        let pages = (end - start) / PAGE_SIZE;
+        let descriptor_pages = align_value(pages * size_of::<Page>(), PAGE_ORDER) / PAGE_SIZE;
+        // first run of pages big enough for the descriptors that misses every reservation
+        let mut place = start;
+        while place + descriptor_pages * PAGE_SIZE <= end
+            && reserved.iter().any(|r| r.overlaps(place, place + descriptor_pages * PAGE_SIZE)) {
+            place += PAGE_SIZE;
+        }
+        if place + descriptor_pages * PAGE_SIZE > end {
+            println!("[WARN] no room for page descriptors in bank 0x{:x} -> 0x{:x}, skipping it", start, end);
+            continue;
+        }
+        let bank = Bank { start, pages, descriptors: place as *mut Page };
+        unsafe {
+            for i in 0..pages {
+                (*bank.descriptors.add(i)).clear();
+            }
+        }
+        reserve_pages(&bank, place, place + descriptor_pages * PAGE_SIZE);
+        for r in reserved {
+            if r.overlaps(start, end) {
+                reserve_pages(&bank, r.start.max(start), r.end().min(end));
+            }
+        }
+        unsafe { BANKS[num_banks] = bank };
+        num_banks += 1;
+    }
+    unsafe { NUM_BANKS = num_banks };
+}
+
+// Mark every page touching [start, end) as reserved, each one on its own so dealloc can't run across them
+fn reserve_pages(bank: &Bank, start: usize, end: usize) {
+    let first = (start & !(PAGE_SIZE - 1)) - bank.start;
+    let last = align_value(end, PAGE_ORDER) - bank.start;
+    for i in first / PAGE_SIZE..last / PAGE_SIZE {
+        unsafe {
+            let page_instance = bank.descriptors.add(i);
+            (*page_instance).set_flag(PageBits::Taken);
+            (*page_instance).set_flag(PageBits::Last);
+            (*page_instance).set_flag(PageBits::Reserved);
         }
-        // align  alloc start to the page boundry
-        ALLOC_START = align_value(HEAP_START + num_pages * size_of::<Page,>(), PAGE_ORDER);
     }
 }
 
@@ -203,6 +504,9 @@ pub enum PageTableEntryBits {
     Global = 0b1 << 5,
     Access =  0b1 << 6,
     Dirty = 0b1 << 7,
+    // bits 8 and 9 are reserved for software (RSW), the hardware ignores them
+    CopyOnWrite = 0b1 << 8, // read only for now, gets a private copy on the first store
+    Owned = 0b1 << 9, // the address space holds a page::alloc reference to the leaf's page
     ReadWrite = 0b1 << 1 | 0b1 << 2, // Combos are just bitwise ors
     ReadExecute = 0b1 << 1 | 0b1 << 3,
     ReadWriteExecute = 0b1 << 1 | 0b1 << 2 | 0b1 << 3,
@@ -211,10 +515,10 @@ pub enum PageTableEntryBits {
     UserReadWriteExecute = 0b1 << 1 | 0b1 << 2 | 0b1 << 3 | 0b1 << 4,
 }
 impl PageTableEntryBits {
-    pub fn as_usize(self) -> usize {
+    pub const fn as_usize(self) -> usize {
         self as usize
     }
-    pub fn as_i64(self) -> i64 {
+    pub const fn as_i64(self) -> i64 {
         self as i64
     }
     pub fn val(self) -> u8 {
@@ -234,8 +538,9 @@ impl PageTableEntry {
         self.get_entry() & PageTableEntryBits::Valid.as_i64() != 0b0
     }
     // in riscv an entry is a leaf if any of the read write execute bits are set
+    // (bit 0 is the valid bit, so the mask skips it)
     pub fn is_leaf(&self) -> bool {
-        self.get_entry() & 0b111 != 0b000
+        self.get_entry() & 0b1110 != 0b000
     }
     // getter setter interface makes it so you can have immutable interface for
     // pte i think
@@ -253,7 +558,7 @@ impl PageTableEntry {
 // Map virtual memory onto physical memory in the PageTable
 pub fn map(root: &mut PageTable, virtual_address: usize, physical_address: usize, bits: i64, level: usize) {
     // ensure rwx bits provided otherwise a memory leak will occur
-    assert!(bits & 0b111 != 0b000);
+    assert!(bits & 0b1110 != 0b000);
     // get the the virtual page number fro mthe virtual address
     // page number is 9 bits so we use a 9 bit mask to just get the 9 bits of the page after rotating
     let virtual_page_numbers = [
@@ -272,22 +577,34 @@ pub fn map(root: &mut PageTable, virtual_address: usize, physical_address: usize
     // traverse the pagetable and set bits accordingly
     for i in (level..2).rev() {
         if !moving_pte_reference.is_valid() {
-            let page = zalloc(1);
+            // the table belongs to the tree from here on, unmap frees it
+            let page = PageBox::<PageTable>::table().expect("out of pages for a page table").into_raw();
             // we right shift by 2 places (ig cuz the rsw bits are still there?)
             moving_pte_reference.set_entry((page as i64 >> 2) | PageTableEntryBits::Valid.as_i64());
         }
-        let entry = ((moving_pte_reference.get_entry() & !0b1111111111) as *mut PageTableEntry);
+        // the ppn in the entry was right shifted by 2 when stored, so shift it back to get the table address
+        let entry = ((moving_pte_reference.get_entry() & !0b1111111111) << 2) as *mut PageTableEntry;
         // should we do better error handling than unwrapping here?
-        let moving_pte_reference = unsafe { entry.add(virtual_page_numbers[i]).as_mut().unwrap() };
+        // (address_space::AddressSpace does, prefer that for new code)
+        moving_pte_reference = unsafe { entry.add(virtual_page_numbers[i]).as_mut().unwrap() };
     }
+    // accessed (and dirty if it's writable) from the start, not all hardware sets them itself
+    let accessed_dirty = if bits & PageTableEntryBits::Write.as_i64() != 0 {
+        PageTableEntryBits::Access.as_i64() | PageTableEntryBits::Dirty.as_i64()
+    } else {
+        PageTableEntryBits::Access.as_i64()
+    };
     // After the loop should be at the 0th virtual pagen umber entry
     // set our entry to the expected entry structure
     let entry = (physical_page_numbers[2] << 28) as i64 | //the second entry is bits [53:28]
     (physical_page_numbers[1] << 19) as i64 |
     (physical_page_numbers[0] << 10) as i64 |
     bits | // reminder these are the user read write bits specified in args
+    accessed_dirty |
     PageTableEntryBits::Valid.as_i64();
     moving_pte_reference.set_entry(entry);
+    // we don't know which asid the table runs under, so flush the address for all of them
+    tlb::flush_address(virtual_address);
 }
 
 // Map a range of addresses to the given page table
@@ -312,7 +629,7 @@ pub fn unmap(root: &mut PageTable) {
                 (level_1_memory_address as *mut PageTable).as_mut().unwrap()
             };
             for level_1_table_i in 0..PageTable::len() {
-                let ref level_1_entry = root.entries[level_1_table_i];
+                let ref level_1_entry = level_1_table.entries[level_1_table_i];
                 if level_1_entry.is_valid() && !level_1_entry.is_leaf() {
                     let level_0_memory_address = (level_1_entry.get_entry() & !0b1111111111) << 2;
                     // free level 0, the outermost leaves of the tree
@@ -324,6 +641,7 @@ pub fn unmap(root: &mut PageTable) {
             // note that the level 2 (highest level root) is not freed.
         }
     }
+    tlb::flush_all();
 }
 
 pub fn virtual_to_physical(root: &PageTable, virtual_address: usize) -> Option<usize> {
@@ -378,106 +696,101 @@ pub fn virtual_to_physical(root: &PageTable, virtual_address: usize) -> Option<u
 
 
 
+// Count how many pages are currently handed out (reserved pages don't count)
+pub fn allocated_pages() -> usize {
+    let mut num = 0;
+    for bank in banks() {
+        for i in 0..bank.pages {
+            let page_instance = unsafe { &*bank.descriptors.add(i) };
+            if page_instance.is_taken() && !page_instance.is_reserved() {
+                num += 1;
+            }
+        }
+    }
+    num
+}
+
+// Count how many pages are reserved across all banks
+pub fn reserved_pages() -> usize {
+    let mut num = 0;
+    for bank in banks() {
+        for i in 0..bank.pages {
+            if unsafe { (*bank.descriptors.add(i)).is_reserved() } {
+                num += 1;
+            }
+        }
+    }
+    num
+}
+
+// Total pages across all banks, reserved ones included
+pub fn total_pages() -> usize {
+    banks().iter().map(|bank| bank.pages).sum()
+}
+
 pub fn print_alloc_start() {
-    unsafe {
-        let starting_page = HEAP_START as *const Page;
-        let alloc_beginning = ALLOC_START;
-        println!("pointer to starting page: {:p}", starting_page);
-        println!("pointer to physical starting memory address: 0x{:x}", alloc_beginning);
+    for bank in banks() {
+        println!("pointer to starting page: {:p}", bank.descriptors);
+        println!("pointer to physical starting memory address: 0x{:x}", bank.start);
     }
 }
 
-pub fn deallocate_all_pages() {
-    unsafe {
-		let num_pages = HEAP_SIZE / PAGE_SIZE;
-		let mut beg = HEAP_START as *const Page;
-		let end = beg.add(num_pages);
-		let alloc_beg = ALLOC_START;
-		let alloc_end = ALLOC_START + num_pages * PAGE_SIZE;
-		let mut num = 0;
-		while beg < end {
-			if (*beg).is_taken() {
-				let start = beg as usize;
-				let memaddr = ALLOC_START
-				              + (start - HEAP_START)
-				                * PAGE_SIZE;
-				loop {
-					num += 1;
-					if (*beg).is_last() {
-						let end = beg as usize;
-						let memaddr = ALLOC_START
-						              + (end
-						                 - HEAP_START)
-						                * PAGE_SIZE
-						              + PAGE_SIZE - 1;
-						break;
-					}
-					beg = beg.add(1);
-				}
-                dealloc(memaddr as *mut u8);
-			}
-			beg = beg.add(1);
-		}
+// Call f with the first page address and page count of every allocation (reserved pages are skipped)
+fn for_each_allocation(mut f: impl FnMut(usize, usize)) {
+    for bank in banks() {
+        let mut i = 0;
+        while i < bank.pages {
+            let page_instance = unsafe { &*bank.descriptors.add(i) };
+            if page_instance.is_taken() && !page_instance.is_reserved() {
+                let start = i;
+                while i < bank.pages && !unsafe { (*bank.descriptors.add(i)).is_last() } {
+                    i += 1;
+                }
+                f(bank.start + start * PAGE_SIZE, i - start + 1);
+            }
+            i += 1;
+        }
     }
 }
 
+pub fn deallocate_all_pages() {
+    for_each_allocation(|memaddr, _| dealloc(memaddr as *mut u8));
+}
+
 pub fn print_page_allocations() {
-	unsafe {
-		let num_pages = HEAP_SIZE / PAGE_SIZE;
-		let mut beg = HEAP_START as *const Page;
-		let end = beg.add(num_pages);
-		let alloc_beg = ALLOC_START;
-		let alloc_end = ALLOC_START + num_pages * PAGE_SIZE;
-		println!();
-		println!(" ______________________________________");
-        print!("|");
-		println!(
-		         "page allocation table                 |\r\n|meta: {:p} -> {:p}        |\r\n|physical mem: \
-		          0x{:x} -> 0x{:x}|",
-		         beg, end, alloc_beg, alloc_end
-		);
-		println!(" --------------------------------------");
-		let mut num = 0;
-		while beg < end {
-			if (*beg).is_taken() {
-				let start = beg as usize;
-				let memaddr = ALLOC_START
-				              + (start - HEAP_START)
-				                * PAGE_SIZE;
-				print!("0x{:x} => ", memaddr);
-				loop {
-					num += 1;
-					if (*beg).is_last() {
-						let end = beg as usize;
-						let memaddr = ALLOC_START
-						              + (end
-						                 - HEAP_START)
-						                * PAGE_SIZE
-						              + PAGE_SIZE - 1;
-						print!(
-						       "0x{:x}: {:>3} page(s)",
-						       memaddr,
-						       (end - start + 1)
-						);
-						println!(".");
-						break;
-					}
-					beg = beg.add(1);
-				}
-			}
-			beg = beg.add(1);
-		}
-		println!(" ________________________________________");
-		println!(
-		         "|allocated: {:>5} pages ({:>9} bytes)|",
-		         num,
-		         num * PAGE_SIZE
-		);
-		println!(
-		         "|free     : {:>5} pages ({:>9} bytes)|",
-		         num_pages - num,
-		         (num_pages - num) * PAGE_SIZE
-		);
-		println!(" ----------------------------------------");
+	println!();
+	println!(" ______________________________________");
+	println!("|page allocation table                 |");
+	for bank in banks() {
+		let meta_end = unsafe { bank.descriptors.add(bank.pages) };
+		println!("|meta: {:p} -> {:p}        |", bank.descriptors, meta_end);
+		println!("|physical mem: 0x{:x} -> 0x{:x}|", bank.start, bank.end());
 	}
+	println!(" --------------------------------------");
+	let mut num = 0;
+	for_each_allocation(|memaddr, pages| {
+		num += pages;
+		print!("0x{:x} => ", memaddr);
+		print!("0x{:x}: {:>3} page(s)", memaddr + pages * PAGE_SIZE - 1, pages);
+		println!(".");
+	});
+	let reserved = reserved_pages();
+	let free = total_pages() - reserved - num;
+	println!(" ________________________________________");
+	println!(
+	         "|allocated: {:>5} pages ({:>9} bytes)|",
+	         num,
+	         num * PAGE_SIZE
+	);
+	println!(
+	         "|reserved : {:>5} pages ({:>9} bytes)|",
+	         reserved,
+	         reserved * PAGE_SIZE
+	);
+	println!(
+	         "|free     : {:>5} pages ({:>9} bytes)|",
+	         free,
+	         free * PAGE_SIZE
+	);
+	println!(" ----------------------------------------");
 }
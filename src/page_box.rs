@@ -0,0 +1,167 @@
+//! Owning handles for memory straight from the page allocator.
+//! page::alloc and zalloc hand back a bare pointer that somebody has to remember to dealloc,
+//! and on an early return nobody does. A PageRange (some bytes) or PageBox (one value) frees its
+//! pages when it's dropped instead. Long lived kernel structures, like the tables a page table
+//! hangs off its root, can still leak theirs on purpose with leak or into_raw
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use crate::page::{self, PageTable, PAGE_SIZE};
+
+// One run of contiguous pages
+pub struct PageRange {
+    address: NonNull<u8>,
+    pages: usize,
+}
+
+// Nobody else can reach the pages, so they can go wherever the owner goes
+unsafe impl Send for PageRange {}
+unsafe impl Sync for PageRange {}
+
+impl PageRange {
+    // Whatever was in the pages before is still there, None if the allocator is out of pages
+    pub fn new(pages: usize) -> Option<Self> {
+        let address = NonNull::new(page::alloc(pages))?;
+        Some(PageRange { address, pages })
+    }
+
+    pub fn zeroed(pages: usize) -> Option<Self> {
+        let address = NonNull::new(page::zalloc(pages))?;
+        Some(PageRange { address, pages })
+    }
+
+    /// Take over pages someone got out of page::alloc or zalloc.
+    ///
+    /// # Safety
+    /// The pointer has to be the start of a live allocation nobody else frees. It has to be the
+    /// whole allocation too, the drop frees everything up to the allocator's last page anyways
+    pub unsafe fn from_raw(pointer: *mut u8, pages: usize) -> Self {
+        PageRange { address: NonNull::new(pointer).expect("null page range"), pages }
+    }
+
+    // Give up ownership, the pages stay allocated until someone deallocs the pointer
+    pub fn into_raw(self) -> *mut u8 {
+        let pointer = self.as_ptr();
+        core::mem::forget(self);
+        pointer
+    }
+
+    // For pages that should stay allocated for as long as the kernel runs
+    pub fn leak(self) -> &'static mut [u8] {
+        let size = self.size();
+        unsafe { core::slice::from_raw_parts_mut(self.into_raw(), size) }
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.address.as_ptr()
+    }
+
+    // The kernel maps RAM one to one, so this is the physical address too
+    pub fn address(&self) -> usize {
+        self.as_ptr() as usize
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    // in bytes
+    pub fn size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.size()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_ptr(), self.size()) }
+    }
+}
+
+impl Drop for PageRange {
+    fn drop(&mut self) {
+        page::dealloc(self.as_ptr());
+    }
+}
+
+// A value living in pages of its own, like a Box but from the page allocator, so it's page
+// aligned and doesn't need the kernel heap up
+pub struct PageBox<T> {
+    range: PageRange,
+    value: PhantomData<T>,
+}
+
+impl<T> PageBox<T> {
+    // how many pages a T takes up
+    const PAGES: usize = if size_of::<T>() == 0 { 1 } else { size_of::<T>().div_ceil(PAGE_SIZE) };
+
+    pub fn new(value: T) -> Option<Self> {
+        const { assert!(align_of::<T>() <= PAGE_SIZE) };
+        let range = PageRange::new(Self::PAGES)?;
+        unsafe { (range.as_ptr() as *mut T).write(value) };
+        Some(PageBox { range, value: PhantomData })
+    }
+
+    /// Without building the value on the stack first, which matters for page sized values.
+    ///
+    /// # Safety
+    /// All zeroes has to be a valid T
+    pub unsafe fn zeroed() -> Option<Self> {
+        const { assert!(align_of::<T>() <= PAGE_SIZE) };
+        let range = PageRange::zeroed(Self::PAGES)?;
+        Some(PageBox { range, value: PhantomData })
+    }
+
+    /// Take over a value in pages from page::alloc or zalloc.
+    ///
+    /// # Safety
+    /// The pointer has to come from into_raw (or be set up the same way) and not be freed elsewhere
+    pub unsafe fn from_raw(pointer: *mut T) -> Self {
+        PageBox { range: unsafe { PageRange::from_raw(pointer as *mut u8, Self::PAGES) }, value: PhantomData }
+    }
+
+    // Give up ownership without dropping the value
+    pub fn into_raw(self) -> *mut T {
+        let pointer = self.range.as_ptr() as *mut T;
+        core::mem::forget(self);
+        pointer
+    }
+
+    // For values that should live as long as the kernel does
+    pub fn leak(self) -> &'static mut T {
+        unsafe { &mut *self.into_raw() }
+    }
+
+    pub fn address(&self) -> usize {
+        self.range.address()
+    }
+}
+
+impl PageBox<PageTable> {
+    // An empty page table, every entry invalid
+    pub fn table() -> Option<Self> {
+        unsafe { Self::zeroed() }
+    }
+}
+
+impl<T> Deref for PageBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*(self.range.as_ptr() as *const T) }
+    }
+}
+
+impl<T> DerefMut for PageBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *(self.range.as_ptr() as *mut T) }
+    }
+}
+
+impl<T> Drop for PageBox<T> {
+    fn drop(&mut self) {
+        // the pages themselves go back when the range drops
+        unsafe { core::ptr::drop_in_place(self.range.as_ptr() as *mut T) };
+    }
+}
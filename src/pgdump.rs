@@ -0,0 +1,177 @@
+//! Page table dumping for the `pgdump` and `pgdiff` shell commands.
+//! Walks a Sv39 table and squashes neighbouring leaves with the same flags and page size
+//! into ranges, so an identity mapped kernel heap shows up as one line instead of hundreds
+use alloc::vec::Vec;
+use crate::address_space::{leaf_physical_address, level_page_size, next_table, FLAG_MASK};
+use crate::page::{PageTable, PageTableEntryBits};
+use crate::malloc;
+use crate::{print, println};
+
+// A run of contiguous virtual pages mapped to contiguous physical pages
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub virtual_address: usize,
+    pub physical_address: usize,
+    pub size: usize,
+    pub level: usize,
+    pub flags: i64,
+}
+
+impl MappedRange {
+    // can the leaf be tacked onto the end of this range
+    fn extends_with(&self, other: &MappedRange) -> bool {
+        self.level == other.level
+            && self.flags == other.flags
+            && self.virtual_address + self.size == other.virtual_address
+            && self.physical_address + self.size == other.physical_address
+    }
+}
+
+// The coalesced ranges of a page table at some point in time
+pub struct PageTableSnapshot {
+    ranges: Vec<MappedRange>,
+    // ranges there wasn't heap left to hold, so pgdump still works when we're out of memory
+    dropped: usize,
+}
+
+impl PageTableSnapshot {
+    pub const fn empty() -> Self {
+        PageTableSnapshot { ranges: Vec::new(), dropped: 0 }
+    }
+
+    // Walk the table and record every mapped range
+    pub fn take(root: &PageTable) -> Self {
+        let mut snapshot = PageTableSnapshot::empty();
+        snapshot.walk(root as *const PageTable as *mut PageTable, 2, 0);
+        snapshot
+    }
+
+    pub fn ranges(&self) -> &[MappedRange] {
+        &self.ranges
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    fn walk(&mut self, table: *mut PageTable, level: usize, virtual_base: usize) {
+        for i in 0..PageTable::len() {
+            let entry = unsafe { &(*table).entries[i] };
+            if !entry.is_valid() {
+                continue;
+            }
+            let virtual_address = virtual_base | (i << (12 + 9 * level));
+            if entry.is_leaf() {
+                self.push(MappedRange {
+                    virtual_address: sign_extend(virtual_address),
+                    physical_address: leaf_physical_address(entry),
+                    size: level_page_size(level),
+                    level,
+                    flags: entry.get_entry() & FLAG_MASK,
+                });
+            } else if level > 0 {
+                self.walk(next_table(entry), level - 1, virtual_address);
+            }
+        }
+    }
+
+    fn push(&mut self, range: MappedRange) {
+        match self.ranges.last_mut() {
+            Some(last) if last.extends_with(&range) => last.size += range.size,
+            _ => {
+                if malloc::try_push(&mut self.ranges, range).is_err() {
+                    self.dropped += 1;
+                }
+            }
+        }
+    }
+
+    fn contains(&self, range: &MappedRange) -> bool {
+        self.ranges().iter().any(|r| r == range)
+    }
+}
+
+// Sv39 virtual addresses copy bit 38 into all the upper bits
+fn sign_extend(virtual_address: usize) -> usize {
+    if virtual_address & (1 << 38) != 0 {
+        virtual_address | !((1 << 39) - 1)
+    } else {
+        virtual_address
+    }
+}
+
+fn page_size_name(level: usize) -> &'static str {
+    match level {
+        0 => "4K",
+        1 => "2M",
+        _ => "1G",
+    }
+}
+
+// rwxugad style flag string, '-' for anything unset
+fn print_flags(flags: i64) {
+    let names = [
+        (PageTableEntryBits::Read, 'r'),
+        (PageTableEntryBits::Write, 'w'),
+        (PageTableEntryBits::Execute, 'x'),
+        (PageTableEntryBits::User, 'u'),
+        (PageTableEntryBits::Global, 'g'),
+        (PageTableEntryBits::Access, 'a'),
+        (PageTableEntryBits::Dirty, 'd'),
+    ];
+    for (bit, name) in names {
+        if flags & bit.as_i64() != 0 {
+            print!("{}", name);
+        } else {
+            print!("-");
+        }
+    }
+}
+
+fn print_range(prefix: &str, range: &MappedRange) {
+    print!(
+        "{}0x{:016x} -> 0x{:016x}  0x{:x} -> 0x{:x}  {} x{:<6} ",
+        prefix,
+        range.virtual_address,
+        range.virtual_address + range.size - 1,
+        range.physical_address,
+        range.physical_address + range.size - 1,
+        page_size_name(range.level),
+        range.size / level_page_size(range.level),
+    );
+    print_flags(range.flags);
+    println!();
+}
+
+pub fn print_snapshot(snapshot: &PageTableSnapshot) {
+    println!("virtual                                     physical               size   flags");
+    for range in snapshot.ranges() {
+        print_range("", range);
+    }
+    if snapshot.dropped() > 0 {
+        println!("[WARN] {} more ranges were left out, the kernel heap is full", snapshot.dropped());
+    }
+    println!("{} range(s)", snapshot.ranges().len());
+}
+
+// Print ranges that went away with a '-' and new ones with a '+'
+pub fn print_diff(before: &PageTableSnapshot, after: &PageTableSnapshot) {
+    let mut changes = 0;
+    for range in before.ranges() {
+        if !after.contains(range) {
+            print_range("- ", range);
+            changes += 1;
+        }
+    }
+    for range in after.ranges() {
+        if !before.contains(range) {
+            print_range("+ ", range);
+            changes += 1;
+        }
+    }
+    println!("{} change(s)", changes);
+}
+
+pub fn dump(root: &PageTable) {
+    print_snapshot(&PageTableSnapshot::take(root));
+}
@@ -0,0 +1,643 @@
+//! User mode processes.
+//! A Process owns an address space of its own, with the user bit on everything the program can
+//! touch, plus two pages only the kernel can: the trampoline (asm/trampoline.S) and the UserFrame
+//! its registers get saved into. The trampoline is mapped at its physical address, which is where
+//! the kernel runs it from, so switching satp in the middle of it doesn't pull the code out from
+//! under itself. The frame sits in the top page of the user half, see FRAME_ADDRESS.
+//! run drops into user mode with an sret and comes back through the trampoline on the next trap,
+//! on the stack of whichever kernel thread called it. Timer ticks and page faults the address
+//! space can fix get handled and the process carries on, anything else ends it. A bad pointer or
+//! a privileged instruction only ever hurts the process that made it.
+//! spawn gives a process a kernel thread of its own and a slot in the process table, which is
+//! what ps, kill and wait work from. A process that's done stays in the table as a zombie, holding
+//! its exit status, until its parent waits for it. The kernel itself is pid 0, the parent of
+//! everything the shell starts and of every orphan
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::address_space::{Access, AddressSpace, MapError};
+use crate::cpu;
+use crate::page::{PageTable, PageTableEntryBits, PAGE_SIZE};
+use crate::page_box::PageBox;
+use crate::pgdump::PageTableSnapshot;
+use crate::sync::{SpinLock, PROCESS_CLASS};
+use crate::syscall::Errno;
+use crate::syscall;
+use crate::thread::{self, ThreadId, WaitQueue};
+use crate::timer;
+use crate::tlb;
+use crate::trap;
+use crate::{print, println};
+
+unsafe extern "C" {
+    #[link_name = "trampoline_start"]
+    static TRAMPOLINE_START: u8;
+    #[link_name = "trampoline_end"]
+    static TRAMPOLINE_END: u8;
+    fn user_enter(frame: *mut UserFrame, satp: usize, flush: bool);
+}
+
+// Register numbers for UserFrame::regs
+pub const SP: usize = 2;
+pub const A0: usize = 10;
+pub const A1: usize = 11;
+pub const A2: usize = 12;
+pub const A3: usize = 13;
+pub const A4: usize = 14;
+pub const A5: usize = 15;
+pub const A7: usize = 17;
+
+// Where things go in a process's address space. Sv39 leaves user mode the bottom 256 GiB.
+// Programs are linked low, from 64 KiB up (user/user.ld). The trampoline has to sit at its
+// physical address, which is inside the kernel image and so also down in the low few GiB where
+// RAM is, the loader refuses anything that runs into it. The heap, mmap, the stack and the frame
+// are all placed by the kernel from 64 GiB up, past the RAM of anything we run on
+pub const USER_END: usize = 0b1 << 38;
+// sbrk grows the heap up from here, mmap hands out addresses from MMAP_START
+pub const HEAP_START: usize = 0x10_0000_0000;
+pub const MMAP_START: usize = 0x20_0000_0000;
+pub const MMAP_END: usize = 0x30_0000_0000;
+pub const STACK_TOP: usize = 0x3f_0000_0000;
+// the UserFrame, the last page below USER_END
+pub const FRAME_ADDRESS: usize = USER_END - PAGE_SIZE;
+
+pub type Pid = usize;
+
+// The kernel's pid, see the top of the file
+pub const KERNEL_PID: Pid = 0;
+
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+// sstatus.SPIE and SPP, what sret restores the interrupt enable and privilege from
+const SSTATUS_SPIE: usize = 0b1 << 5;
+const SSTATUS_SPP: usize = 0b1 << 8;
+// sstatus.FS, anything but off lets the trampoline and the process use the float registers
+const SSTATUS_FS_INITIAL: usize = 0b01 << 13;
+
+// Everything the trampoline saves, the user's registers while the kernel runs and the kernel's
+// while the process runs
+#[repr(C)]
+pub struct UserFrame {
+    // x0-x31, x0 is never read or written
+    pub regs: [usize; 32],
+    // where the process continues on the next run
+    pub epc: usize,
+    // scause and stval of the trap that brought us back
+    pub cause: usize,
+    pub tval: usize,
+    // f0-f31 as raw bits, and fcsr
+    pub fregs: [u64; 32],
+    pub fcsr: usize,
+    kernel_satp: usize,
+    kernel_stvec: usize,
+    kernel_sscratch: usize,
+    // whether the trampoline flushes the TLB when it switches satp, see tlb::enter
+    flush: usize,
+    // where the frame itself is in the kernel's satp and in the process's
+    kernel_address: usize,
+    user_address: usize,
+    // ra, sp, gp, tp, s0-s11
+    kernel: [usize; 16],
+    // fs0-fs11 and fcsr, the float state a call has to leave alone
+    kernel_fregs: [u64; 12],
+    kernel_fcsr: usize,
+}
+
+// trampoline.S has these offsets hard coded
+const _: () = assert!(offset_of!(UserFrame, epc) == 32 * 8);
+const _: () = assert!(offset_of!(UserFrame, fregs) == 35 * 8);
+const _: () = assert!(offset_of!(UserFrame, fcsr) == 67 * 8);
+const _: () = assert!(offset_of!(UserFrame, kernel_satp) == 68 * 8);
+const _: () = assert!(offset_of!(UserFrame, flush) == 71 * 8);
+const _: () = assert!(offset_of!(UserFrame, kernel_address) == 72 * 8);
+const _: () = assert!(offset_of!(UserFrame, kernel) == 74 * 8);
+const _: () = assert!(offset_of!(UserFrame, kernel_fregs) == 90 * 8);
+const _: () = assert!(offset_of!(UserFrame, kernel_fcsr) == 102 * 8);
+
+// How a run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    // the process called exit with the code
+    Exited(usize),
+    // a trap the kernel couldn't fix for it, straight from scause, stval and sepc
+    Fault { cause: usize, tval: usize, epc: usize },
+    // stopped by kill
+    Killed,
+}
+
+pub struct Process {
+    pid: Pid,
+    space: AddressSpace,
+    frame: PageBox<UserFrame>,
+    // the end of the sbrk heap, it starts out empty at HEAP_START
+    pub(crate) heap_end: usize,
+    // where the next mmap without an address hint goes
+    pub(crate) mmap_next: usize,
+    // set by the exit system call, run returns once it's there
+    pub(crate) exit_code: Option<usize>,
+    // the hart the process last ran on, see run
+    hart: Option<usize>,
+}
+
+// Nothing in here is shared, so a process can be handed to whatever thread runs it
+unsafe impl Send for Process {}
+
+// Where the trampoline is, and how big
+pub(crate) fn trampoline() -> (usize, usize) {
+    let start = core::ptr::addr_of!(TRAMPOLINE_START) as usize;
+    let end = core::ptr::addr_of!(TRAMPOLINE_END) as usize;
+    (start, end - start)
+}
+
+impl Process {
+    // An empty address space with just the trampoline and the frame in it
+    pub fn new() -> Result<Self, MapError> {
+        let mut space = AddressSpace::new()?;
+        // all zeroes is a fine UserFrame, apart from where it is
+        let mut frame = unsafe { PageBox::<UserFrame>::zeroed() }.ok_or(MapError::OutOfMemory)?;
+        frame.kernel_address = frame.address();
+        frame.user_address = FRAME_ADDRESS;
+        let (trampoline, size) = trampoline();
+        space.map_range(trampoline, trampoline, size, PageTableEntryBits::ReadExecute.as_i64())?;
+        space.map(FRAME_ADDRESS, frame.address(), PageTableEntryBits::ReadWrite.as_i64(), 0)?;
+        let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+        Ok(Process { pid, space, frame, heap_end: HEAP_START, mmap_next: MMAP_START, exit_code: None, hart: None })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn space(&mut self) -> &mut AddressSpace {
+        &mut self.space
+    }
+
+    // The registers the process continues with on the next run
+    pub fn frame(&mut self) -> &mut UserFrame {
+        &mut self.frame
+    }
+
+    // Copy data into fresh pages at the virtual address. bits get the user bit added, and on an
+    // error nothing stays mapped
+    pub fn load(&mut self, virtual_address: usize, data: &[u8], bits: i64) -> Result<(), MapError> {
+        if !virtual_address.is_multiple_of(PAGE_SIZE) {
+            return Err(MapError::Misaligned);
+        }
+        let bits = bits | PageTableEntryBits::User.as_i64();
+        for (i, chunk) in data.chunks(PAGE_SIZE).enumerate() {
+            match self.space.allocate(virtual_address + i * PAGE_SIZE, bits) {
+                // the kernel runs on physical addresses, so the page can be filled in directly
+                Ok(page) => unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), page as *mut u8, chunk.len()) },
+                Err(error) => {
+                    for j in 0..i {
+                        let _ = self.space.unmap(virtual_address + j * PAGE_SIZE);
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Where the next run starts
+    pub fn set_entry(&mut self, pc: usize) {
+        self.frame.epc = pc;
+    }
+
+    // A stack ending at top, backed a page at a time as it's touched. sp starts at top
+    pub fn reserve_stack(&mut self, top: usize, size: usize) -> Result<(), MapError> {
+        let size = crate::page::align_value(size, 12);
+        self.space.reserve(top - size, size, PageTableEntryBits::UserReadWrite.as_i64())?;
+        self.frame.regs[SP] = top;
+        Ok(())
+    }
+
+    // The physical address behind a user address, if the process itself may access it that way.
+    // Lazily backed and copy on write pages get sorted out first, just like a fault from the
+    // process would
+    fn user_page(&mut self, virtual_address: usize, access: Access) -> Result<usize, MapError> {
+        let needed = PageTableEntryBits::User.as_i64() | match access {
+            Access::Load => PageTableEntryBits::Read.as_i64(),
+            Access::Store => PageTableEntryBits::Write.as_i64(),
+            Access::Execute => PageTableEntryBits::Execute.as_i64(),
+        };
+        if virtual_address >= USER_END {
+            return Err(MapError::NotMapped);
+        }
+        let allowed = |space: &AddressSpace| space.flags(virtual_address).is_some_and(|flags| flags & needed == needed);
+        if !allowed(&self.space) {
+            self.space.handle_fault(virtual_address, access)?;
+            if !allowed(&self.space) {
+                return Err(MapError::PermissionDenied);
+            }
+        }
+        self.space.translate(virtual_address).ok_or(MapError::NotMapped)
+    }
+
+    // Call f with the physical address and length of each piece of the user range, split at page
+    // boundaries
+    fn for_each_user_page(&mut self, virtual_address: usize, length: usize, access: Access,
+        mut f: impl FnMut(usize, usize, usize)) -> Result<(), MapError> {
+        let end = virtual_address.checked_add(length).ok_or(MapError::NotMapped)?;
+        let mut address = virtual_address;
+        while address < end {
+            let piece = (PAGE_SIZE - address % PAGE_SIZE).min(end - address);
+            let physical_address = self.user_page(address, access)?;
+            f(physical_address, address - virtual_address, piece);
+            address += piece;
+        }
+        Ok(())
+    }
+
+    // Copy out of the process's memory. Fails if any of it isn't readable from user mode
+    pub fn copy_from_user(&mut self, virtual_address: usize, buffer: &mut [u8]) -> Result<(), MapError> {
+        let length = buffer.len();
+        self.for_each_user_page(virtual_address, length, Access::Load, |physical_address, offset, piece| {
+            // physical addresses are where the kernel sees RAM
+            unsafe { core::ptr::copy_nonoverlapping(physical_address as *const u8, buffer[offset..].as_mut_ptr(), piece) };
+        })
+    }
+
+    // Copy into the process's memory. Fails if any of it isn't writable from user mode, the part
+    // before the bad page still gets written
+    pub fn copy_to_user(&mut self, virtual_address: usize, data: &[u8]) -> Result<(), MapError> {
+        self.for_each_user_page(virtual_address, data.len(), Access::Store, |physical_address, offset, piece| {
+            unsafe { core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), physical_address as *mut u8, piece) };
+        })
+    }
+
+    // Run the process until it exits or faults
+    pub fn run(&mut self) -> Exit {
+        loop {
+            let interrupts = cpu::interrupts_off();
+            // the address space flushes its own asid when it changes a mapping, but only on the
+            // hart doing the change. A hart the process hasn't just run on may still remember
+            // mappings from the last time it ran there
+            let asid = self.space.refresh_asid();
+            let satp = tlb::satp(self.space.root_address(), asid);
+            let (flush, previous) = tlb::enter(self.space.root_address(), asid);
+            let hart = cpu::hart_id();
+            let flush = flush || self.hart.replace(hart) != Some(hart);
+            // sret goes to user mode (SPP clear) with interrupts on (SPIE set), and the float
+            // registers on
+            cpu::set_sstatus((cpu::sstatus() & !SSTATUS_SPP) | SSTATUS_SPIE | SSTATUS_FS_INITIAL);
+            unsafe { user_enter(&mut *self.frame, satp, flush) };
+            tlb::leave(previous);
+            // back from the first trap out of user mode, interrupts are still off
+            let cause = self.frame.cause;
+            if cause == trap::INTERRUPT_BIT | trap::SUPERVISOR_TIMER {
+                // the same tick kernel_trap would handle, just on this thread's stack
+                timer::set_next();
+                thread::tick();
+            }
+            cpu::restore_interrupts(interrupts);
+            if let Some(exit) = self.handle_trap(cause) {
+                return exit;
+            }
+            if let Some(code) = self.exit_code {
+                return Exit::Exited(code);
+            }
+            // a timer tick at the latest brings the process back here, so a kill never waits
+            // long. Processes run outside the table just never get killed
+            if update(self.pid, self.space.owned_pages()) {
+                return Exit::Killed;
+            }
+        }
+    }
+
+    // None when the process can carry on
+    fn handle_trap(&mut self, cause: usize) -> Option<Exit> {
+        let (tval, epc) = (self.frame.tval, self.frame.epc);
+        if cause & trap::INTERRUPT_BIT != 0 {
+            if cause != trap::INTERRUPT_BIT | trap::SUPERVISOR_TIMER {
+                println!("[WARN] unhandled interrupt {} in user mode", cause & !trap::INTERRUPT_BIT);
+            }
+            return None;
+        }
+        let access = match cause {
+            trap::ENVIRONMENT_CALL_FROM_USER => {
+                // carry on after the ecall, with the result in a0
+                self.frame.epc += 4;
+                let regs = &self.frame.regs;
+                let (number, args) = (regs[A7], [regs[A0], regs[A1], regs[A2], regs[A3], regs[A4], regs[A5]]);
+                let result = syscall::dispatch(self, number, args);
+                self.frame.regs[A0] = syscall::encode(result);
+                return None;
+            }
+            trap::INSTRUCTION_PAGE_FAULT => Access::Execute,
+            trap::LOAD_PAGE_FAULT => Access::Load,
+            trap::STORE_PAGE_FAULT => Access::Store,
+            _ => return Some(Exit::Fault { cause, tval, epc }),
+        };
+        // lazily backed regions and copy on write pages, retry the instruction once the page is
+        // there. Pages without the user bit (the trampoline, the frame) never get fixed
+        match self.space.handle_fault(tval, access) {
+            Ok(()) => None,
+            Err(_) => Some(Exit::Fault { cause, tval, epc }),
+        }
+    }
+}
+
+// How many processes, zombies included, can exist at once
+pub const MAX_PROCESSES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    // exited, waiting for its parent to collect the status
+    Zombie,
+}
+
+impl ProcessState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProcessState::Running => "running",
+            ProcessState::Zombie => "zombie",
+        }
+    }
+}
+
+// One slot of the process table, pid 0 is a free one
+#[derive(Clone, Copy)]
+struct Entry {
+    pid: Pid,
+    parent: Pid,
+    name: &'static str,
+    thread: ThreadId,
+    state: ProcessState,
+    killed: bool,
+    // the address space's root table, 0 once the process is done with it
+    root: usize,
+    // owned pages, as of the last time the process came back to the kernel
+    pages: usize,
+    // only kept for zombies, the scheduler knows it for running ones
+    cpu_time: usize,
+    exit: Exit,
+}
+
+impl Entry {
+    const fn empty() -> Self {
+        Entry {
+            pid: 0,
+            parent: KERNEL_PID,
+            name: "",
+            thread: 0,
+            state: ProcessState::Running,
+            killed: false,
+            root: 0,
+            pages: 0,
+            cpu_time: 0,
+            exit: Exit::Exited(0),
+        }
+    }
+}
+
+// Lock order: the process table comes before the scheduler and the heap. Nothing touches a
+// thread with it held, and the only thing that allocates is page_table_snapshot
+static PROCESS_LOCK: SpinLock = SpinLock::ranked(PROCESS_CLASS);
+static mut PROCESSES: [Entry; MAX_PROCESSES] = [const { Entry::empty() }; MAX_PROCESSES];
+
+// Bumped and woken every time a process turns into a zombie or gets killed, see wait_for_exit
+static EXITS: AtomicUsize = AtomicUsize::new(0);
+static EXITED: WaitQueue = WaitQueue::new();
+
+// Run f on the table, locked
+fn with_table<T>(f: impl FnOnce(&mut [Entry; MAX_PROCESSES]) -> T) -> T {
+    let _guard = PROCESS_LOCK.lock();
+    f(unsafe { &mut *core::ptr::addr_of_mut!(PROCESSES) })
+}
+
+// Note the process's memory use, true when it has been killed
+fn update(pid: Pid, pages: usize) -> bool {
+    with_table(|table| match table.iter_mut().find(|entry| entry.pid == pid) {
+        Some(entry) => {
+            entry.pages = pages;
+            entry.killed
+        }
+        None => false,
+    })
+}
+
+// Start the process on a thread of its own, as a child of parent. It runs until it exits, faults
+// or gets killed, then waits in the table for wait
+pub fn spawn(mut process: Process, name: &'static str, parent: Pid) -> Result<Pid, Errno> {
+    let pid = process.pid;
+    let pages = process.space.owned_pages();
+    let root = process.space.root_address();
+    with_table(|table| -> Result<(), Errno> {
+        let slot = table.iter_mut().find(|entry| entry.pid == 0).ok_or(Errno::TryAgain)?;
+        *slot = Entry { pid, parent, name, root, pages, ..Entry::empty() };
+        Ok(())
+    })?;
+    let handle = thread::spawn_named(name, move || {
+        let exit = process.run();
+        // its memory goes back before the parent can see it exited, and nobody can be looking at
+        // its page table while it does
+        with_table(|table| {
+            if let Some(entry) = table.iter_mut().find(|entry| entry.pid == pid) {
+                entry.root = 0;
+            }
+        });
+        drop(process);
+        let mut cpu_time = 0;
+        let current = thread::current();
+        thread::for_each_thread(|thread| {
+            if Some(thread.id) == current {
+                cpu_time = thread.cpu_time;
+            }
+        });
+        with_table(|table| {
+            for entry in table.iter_mut() {
+                if entry.pid == pid {
+                    entry.state = ProcessState::Zombie;
+                    entry.exit = exit;
+                    entry.pages = 0;
+                    entry.cpu_time = cpu_time;
+                } else if entry.parent == pid {
+                    // orphans go to the kernel
+                    entry.parent = KERNEL_PID;
+                }
+            }
+        });
+        exited();
+    });
+    match handle {
+        // detached, wait goes through the table rather than the thread
+        Ok(handle) => {
+            with_table(|table| {
+                if let Some(entry) = table.iter_mut().find(|entry| entry.pid == pid) {
+                    entry.thread = handle.id();
+                }
+            });
+            Ok(pid)
+        }
+        Err(error) => {
+            with_table(|table| {
+                if let Some(entry) = table.iter_mut().find(|entry| entry.pid == pid) {
+                    *entry = Entry::empty();
+                }
+            });
+            Err(match error {
+                thread::SpawnError::OutOfMemory => Errno::OutOfMemory,
+                _ => Errno::TryAgain,
+            })
+        }
+    }
+}
+
+// Ask the process to stop. It does the next time it's back in the kernel, zombies are left be.
+// A process asleep in a system call gets woken up to notice
+pub fn kill(pid: Pid) -> Result<(), Errno> {
+    let thread = with_table(|table| -> Result<Option<ThreadId>, Errno> {
+        let entry = table.iter_mut().find(|entry| entry.pid == pid && pid != 0).ok_or(Errno::NoSuchProcess)?;
+        entry.killed = true;
+        // 0 until spawn has the thread going, and it checks for a kill before it ever sleeps
+        Ok((entry.state == ProcessState::Running && entry.thread != 0).then_some(entry.thread))
+    })?;
+    if let Some(thread) = thread {
+        thread::wake(thread);
+    }
+    // a process killed while it waits in waitpid has to notice
+    exited();
+    Ok(())
+}
+
+pub fn is_killed(pid: Pid) -> bool {
+    with_table(|table| table.iter().any(|entry| entry.pid == pid && entry.killed))
+}
+
+// The parent of a process in the table
+pub fn parent_of(pid: Pid) -> Option<Pid> {
+    with_table(|table| table.iter().find(|entry| entry.pid == pid && pid != 0).map(|entry| entry.parent))
+}
+
+// A child wait collected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reaped {
+    pub pid: Pid,
+    pub name: &'static str,
+    pub exit: Exit,
+}
+
+// Collect a zombie child of parent, pid picks one or any child does. The slot is freed and the
+// pid gone for good. None while the children are all still running, NoChild when there's nothing
+// to wait for at all
+pub fn try_wait(parent: Pid, pid: Option<Pid>) -> Result<Option<Reaped>, Errno> {
+    with_table(|table| {
+        let mut children = table.iter_mut()
+            .filter(|entry| entry.pid != 0 && entry.parent == parent && pid.is_none_or(|pid| entry.pid == pid))
+            .peekable();
+        if children.peek().is_none() {
+            return Err(Errno::NoChild);
+        }
+        Ok(children.find(|entry| entry.state == ProcessState::Zombie).map(|entry| {
+            let reaped = Reaped { pid: entry.pid, name: entry.name, exit: entry.exit };
+            *entry = Entry::empty();
+            reaped
+        }))
+    })
+}
+
+// try_wait until a child exits, asleep on EXITED in between
+pub fn wait(parent: Pid, pid: Option<Pid>) -> Result<Reaped, Errno> {
+    loop {
+        let seen = exit_count();
+        if let Some(reaped) = try_wait(parent, pid)? {
+            return Ok(reaped);
+        }
+        wait_for_exit(seen);
+    }
+}
+
+// How many times a process has turned into a zombie or been killed so far. Read it before looking
+// for a zombie child and hand it to wait_for_exit
+pub fn exit_count() -> usize {
+    EXITS.load(Ordering::Acquire)
+}
+
+// Sleep until a process turns into a zombie or gets killed, unless one has since exit_count gave
+// back seen. The table can't be looked at from wait_if's condition (the scheduler is locked by
+// then, and the table comes first), so "no zombie child yet" is "nothing exited since we looked"
+pub fn wait_for_exit(seen: usize) {
+    EXITED.wait_if(|| EXITS.load(Ordering::Acquire) == seen);
+}
+
+// Something wait_for_exit is waiting on happened
+fn exited() {
+    EXITS.fetch_add(1, Ordering::Release);
+    EXITED.wake_all();
+}
+
+// What for_each_process hands out about each process
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub name: &'static str,
+    pub state: ProcessState,
+    // None for zombies
+    pub hart: Option<usize>,
+    // in time CSR ticks
+    pub cpu_time: usize,
+    // bytes of memory the process owns
+    pub memory: usize,
+    pub exit: Option<Exit>,
+}
+
+// Every process in the table, in no particular order. Takes a copy of the table first, so f can
+// do whatever it likes
+pub fn for_each_process(mut f: impl FnMut(&ProcessInfo)) {
+    let table = with_table(|table| *table);
+    for entry in table.iter().filter(|entry| entry.pid != 0) {
+        let mut info = ProcessInfo {
+            pid: entry.pid,
+            parent: entry.parent,
+            name: entry.name,
+            state: entry.state,
+            hart: None,
+            cpu_time: entry.cpu_time,
+            memory: entry.pages * PAGE_SIZE,
+            exit: (entry.state == ProcessState::Zombie).then_some(entry.exit),
+        };
+        if entry.state == ProcessState::Running {
+            thread::for_each_thread(|thread| {
+                if thread.id == entry.thread {
+                    info.hart = Some(thread.hart);
+                    info.cpu_time = thread.cpu_time;
+                }
+            });
+        }
+        f(&info);
+    }
+}
+
+// A dump of a running process's page table, for pgdump. Taken with the table locked, so the
+// process can't free the table halfway through
+pub fn page_table_snapshot(pid: Pid) -> Option<PageTableSnapshot> {
+    with_table(|table| {
+        let entry = table.iter().find(|entry| entry.pid == pid && entry.root != 0)?;
+        Some(PageTableSnapshot::take(unsafe { &*(entry.root as *const PageTable) }))
+    })
+}
+
+pub fn process_count() -> usize {
+    with_table(|table| table.iter().filter(|entry| entry.pid != 0).count())
+}
+
+// A hart number for ps, or - for a zombie. Padding carries over to either
+struct Hart(Option<usize>);
+
+impl core::fmt::Display for Hart {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.0 {
+            Some(hart) => hart.fmt(f),
+            None => "-".fmt(f),
+        }
+    }
+}
+
+pub fn print_processes() {
+    println!("{:>6} {:>6} {:<8} {:>4} {:>10} {:>8} {:<16}", "pid", "ppid", "state", "hart", "cpu ms", "mem KiB", "name");
+    for_each_process(|process| {
+        println!("{:>6} {:>6} {:<8} {:>4} {:>10} {:>8} {:<16}", process.pid, process.parent, process.state.name(),
+            Hart(process.hart), process.cpu_time / (cpu::timebase_frequency() / 1000), process.memory / 1024, process.name);
+    });
+}
@@ -36,6 +36,14 @@ pub fn shfetch() {
 
 use crate::page;
 use crate::malloc;
+use crate::linear_allocator::LinearAllocator;
+use crate::pgdump;
+use crate::slab;
+use crate::alloctrace;
+use crate::thread;
+use crate::elf;
+use crate::process::{self, Exit, Reaped, KERNEL_PID};
+use crate::syscall::Errno;
 
 // Remember the page tables are just an abstraction, pages need to be
 // mapped properly onto real physical memory locations. This function but
@@ -55,18 +63,19 @@ pub fn initialize_kernel_memory() {
     let kernel_root = malloc::get_page_table();
     let root_u = kernel_root as usize;
     let mut root = unsafe { kernel_root.as_mut().unwrap() };
-    let kernel_heap_head = malloc::get_head() as usize;
-    let total_pages = malloc::get_number_allocations();
     unsafe {
         println!("TEXT:   0x{:x} -> 0x{:x}", TEXT_START, TEXT_END);
         println!("RODATA: 0x{:x} -> 0x{:x}", RODATA_START, RODATA_END);
         println!("DATA:   0x{:x} -> 0x{:x}", DATA_START, DATA_END);
         println!("BSS:    0x{:x} -> 0x{:x}", BSS_START, BSS_END);
         println!("KERNEL STACK:  0x{:x} -> 0x{:x}", KERNEL_STACK_START, KERNEL_STACK_END);
-        println!("KERNEL HEAP:   0x{:x} -> 0x{:x}", kernel_heap_head, kernel_heap_head + total_pages * 4096);
     }
-    // this should map the kernel's heap
-    page::map_range(&mut root, kernel_heap_head, kernel_heap_head + total_pages * 4096, page::PageTableEntryBits::ReadWrite.as_i64());
+    // this should map the kernel's heap. it can be spread over several arenas by now, and any it
+    // grows later get mapped by malloc itself
+    malloc::for_each_arena(|arena, pages| {
+        println!("KERNEL HEAP:   0x{:x} -> 0x{:x}", arena, arena + pages * 4096);
+        page::map_range(&mut root, arena, arena + pages * 4096, page::PageTableEntryBits::ReadWrite.as_i64());
+    });
     // before mapping all the other stuff let's check this worked. we could map the mmio allocated memory now, but let's wait until
     // we set up a filesystem so we can use a .dtb file to this and have a better interface for block drivers too
 }
@@ -77,9 +86,222 @@ pub fn ptable() {
     malloc::print_kernel_memory_table();
 }
 pub fn pkmemtable() {
+    if memory_in_use("pkmem") {
+        return;
+    }
     initialize_kernel_memory();
     // malloc::print_kernel_memory_table();
 }
+// pkmem and test start memory over with page::init and malloc::init. A process or thread that's
+// still around has its page tables, stacks and Thread on that memory, so they have to wait until
+// everything has been killed and reaped
+fn memory_in_use(command: &str) -> bool {
+    let processes = process::process_count();
+    let threads = thread::spawned_thread_count();
+    if processes == 0 && threads == 0 {
+        return false;
+    }
+    println!("{}: {} processes and {} threads still around, kill and wait for them first", command, processes, threads);
+    true
+}
+
+// Keep the last table dump around so pgdiff has something to compare against
+static mut LAST_PGDUMP: pgdump::PageTableSnapshot = pgdump::PageTableSnapshot::empty();
+
+// The table pgdump and pgdiff look at: the kernel's, or the process's when the line has a pid
+fn pgdump_snapshot(line: &str) -> Option<pgdump::PageTableSnapshot> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or("pgdump");
+    let Some(pid) = words.next() else {
+        let kernel_root = malloc::get_page_table();
+        if kernel_root.is_null() {
+            println!("[WARN] kernel page table not initialized, run pkmem first");
+            return None;
+        }
+        return Some(pgdump::PageTableSnapshot::take(unsafe { &*kernel_root }));
+    };
+    let Ok(pid) = pid.parse() else {
+        println!("usage: {} [pid]", command);
+        return None;
+    };
+    let snapshot = process::page_table_snapshot(pid);
+    if snapshot.is_none() {
+        println!("{}: no running process {}", command, pid);
+    }
+    snapshot
+}
+
+// pgdump [pid]: dump the kernel's page table, or a process's, as coalesced ranges
+pub fn pgdump(line: &str) {
+    let Some(snapshot) = pgdump_snapshot(line) else {
+        return;
+    };
+    pgdump::print_snapshot(&snapshot);
+    unsafe { *core::ptr::addr_of_mut!(LAST_PGDUMP) = snapshot };
+}
+
+// pgdiff [pid]: show what changed in the page table since the last pgdump or pgdiff
+pub fn pgdiff(line: &str) {
+    let Some(snapshot) = pgdump_snapshot(line) else {
+        return;
+    };
+    unsafe {
+        pgdump::print_diff(&*core::ptr::addr_of!(LAST_PGDUMP), &snapshot);
+        *core::ptr::addr_of_mut!(LAST_PGDUMP) = snapshot;
+    }
+}
+
+// Check every chunk in the kernel heap, see the debug-heap feature for the thorough version
+pub fn heapcheck() {
+    if malloc::get_head().is_null() {
+        println!("[WARN] kernel heap not initialized, run pkmem first");
+        return;
+    }
+    let problems = malloc::heapcheck();
+    println!("heapcheck: {} problems in {} arenas", problems, malloc::number_of_arenas());
+}
+
+// alloctrace [on|off|page|heap|h<hart>]: turn the allocation trace on or off, or print it
+// (everything, just page allocator events, just kernel heap events or just one hart's)
+pub fn alloctrace(line: &str) {
+    let argument = line.split_whitespace().nth(1).unwrap_or("");
+    let filter = match argument {
+        "on" => {
+            alloctrace::enable();
+            println!("alloctrace: tracing the page allocator and kernel heap");
+            return;
+        }
+        "off" => {
+            alloctrace::disable();
+            println!("alloctrace: off, {} events recorded", alloctrace::recorded());
+            return;
+        }
+        "" => alloctrace::Filter::All,
+        "page" => alloctrace::Filter::Pages,
+        "heap" => alloctrace::Filter::Heap,
+        hart => match hart.strip_prefix('h').and_then(|hart| hart.parse().ok()) {
+            Some(hart) => alloctrace::Filter::Hart(hart),
+            None => {
+                println!("usage: alloctrace [on|off|page|heap|h<hart>]");
+                return;
+            }
+        },
+    };
+    if !alloctrace::is_enabled() && alloctrace::recorded() == 0 {
+        println!("[WARN] nothing traced, run alloctrace on first");
+        return;
+    }
+    alloctrace::print_trace(filter);
+}
+
+// How a process the shell started ended
+fn report(process: Reaped) {
+    let (pid, name) = (process.pid, process.name);
+    match process.exit {
+        Exit::Exited(code) => println!("[{}] {} exited with {}", pid, name, code),
+        Exit::Fault { cause, tval, epc } => {
+            println!("[{}] {} killed by trap {} at {:#x}, tval {:#x}", pid, name, cause, epc, tval)
+        }
+        Exit::Killed => println!("[{}] {} killed", pid, name),
+    }
+}
+
+// exec <program> [args] [&]: run one of the programs built into the kernel and wait for it to
+// exit, or leave it running in the background with a trailing &
+pub fn exec(line: &str) {
+    if malloc::get_head().is_null() {
+        println!("[WARN] kernel heap not initialized, run pkmem first");
+        return;
+    }
+    let mut words = line.split_whitespace().skip(1);
+    let Some(name) = words.next() else {
+        print!("usage: exec <program> [args] [&], programs:");
+        for program in elf::PROGRAMS {
+            print!(" {}", program.name);
+        }
+        println!();
+        return;
+    };
+    let Some(program) = elf::find_program(name) else {
+        println!("exec: no program called {}", name);
+        return;
+    };
+    // the command line is short enough that this always fits
+    let mut args = [""; INPUT_LENGTH / 2];
+    args[0] = program.name;
+    let mut count = 1;
+    let mut background = false;
+    for word in words.take(args.len() - 1) {
+        if word == "&" {
+            background = true;
+            break;
+        }
+        args[count] = word;
+        count += 1;
+    }
+    let process = match elf::exec(program.image, &args[..count], &[]) {
+        Ok(process) => process,
+        Err(error) => {
+            println!("exec: {} won't start: {:?}", name, error);
+            return;
+        }
+    };
+    thread::init();
+    let pid = match process::spawn(process, program.name, KERNEL_PID) {
+        Ok(pid) => pid,
+        Err(error) => {
+            println!("exec: {} won't start: {:?}", name, error);
+            return;
+        }
+    };
+    if background {
+        println!("[{}] {}", pid, program.name);
+        return;
+    }
+    // stdin is the process's until it's done
+    console::set_foreground(pid);
+    let reaped = wait_foreground(pid);
+    console::set_foreground(KERNEL_PID);
+    if let Ok(process) = reaped {
+        report(process);
+    }
+}
+
+// process::wait for the foreground process, watching the console for a Ctrl-C meanwhile (see
+// console::poll). Nothing says when a key comes in, so it looks once a tick
+fn wait_foreground(pid: process::Pid) -> Result<Reaped, Errno> {
+    loop {
+        if let Some(reaped) = process::try_wait(KERNEL_PID, Some(pid))? {
+            return Ok(reaped);
+        }
+        console::poll();
+        thread::sleep_until(crate::cpu::time().saturating_add(crate::timer::tick_interval()));
+    }
+}
+
+// kill <pid>
+pub fn kill(line: &str) {
+    let Some(pid) = line.split_whitespace().nth(1).and_then(|pid| pid.parse().ok()) else {
+        println!("usage: kill <pid>");
+        return;
+    };
+    if process::kill(pid).is_err() {
+        println!("kill: no process {}", pid);
+    }
+}
+
+// wait: collect every background process that has exited
+pub fn wait() {
+    let mut reaped = 0;
+    while let Ok(Some(process)) = process::try_wait(KERNEL_PID, None) {
+        report(process);
+        reaped += 1;
+    }
+    if reaped == 0 {
+        println!("wait: nothing has exited");
+    }
+}
+
 pub fn clear() {
     for i in 0..200 {
         println!();
@@ -87,13 +309,51 @@ pub fn clear() {
 }
 
 use crate::test;
+// how many characters of a command line we keep, the last slot always stays a space
+pub const INPUT_LENGTH: usize = 16;
+// every command the shell knows, anything else gets an "unknown command"
+const COMMANDS: [&str; 15] = ["shfetch", "ptable", "clear", "test", "pkmem", "pgdump", "pgdiff", "slabinfo", "heapcheck", "alloctrace", "threads", "exec", "ps", "kill", "wait"];
+
+// Scratch memory for whichever command is running, all of it thrown away once the command is done.
+// It lives in .bss rather than coming from page::alloc so a page::init (pkmem, test) can't pull it out from under us
+const SCRATCH_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct ScratchBuffer([u8; SCRATCH_SIZE]);
+
+static mut SCRATCH_BUFFER: ScratchBuffer = ScratchBuffer([0; SCRATCH_SIZE]);
+static mut SCRATCH: LinearAllocator = LinearAllocator::empty();
+
+// The running command's scratch arena
+pub fn scratch() -> &'static LinearAllocator {
+    unsafe { &*core::ptr::addr_of!(SCRATCH) }
+}
+
+// The input as a str (trailing spaces gone) in the arena
+fn command_line<'a>(arena: &'a LinearAllocator, input_array: &[char; INPUT_LENGTH]) -> &'a str {
+    // a char is at most 4 bytes of utf-8
+    let Some(bytes) = arena.alloc_slice(&[0u8; INPUT_LENGTH * 4]) else {
+        return "";
+    };
+    let mut length = 0;
+    for c in input_array {
+        length += c.encode_utf8(&mut bytes[length..]).len();
+    }
+    core::str::from_utf8(&bytes[..length]).unwrap_or("").trim()
+}
+
 /// These use stack allocated memory to check for commands, but this is very limited!
 /// shows why we have a need for heap allocated memory
 /// obviously, we could just allocate from one global heap (arena allocation),
 /// although this would still require letting rust know about the heap
 /// we could also make a macro for this, since the length of the command name is actually known at compile time, but
 /// we will want heap allocation anyways
-pub fn basic_command_process (input_array: &[char; 8]) {
+pub fn basic_command_process (input_array: &[char; INPUT_LENGTH]) {
+    let line = command_line(scratch(), input_array);
+    // commands match on their first few characters, so anything starting with a command name counts
+    if !line.is_empty() && !COMMANDS.iter().any(|command| line.starts_with(command)) {
+        println!("unknown command: {}", line);
+    }
     let shfetch_arr: [char; 7] = ['s', 'h', 'f', 'e', 't', 'c', 'h'];
     let mut shfetch_command: bool = true;
     for i in 0..6 {
@@ -134,7 +394,7 @@ pub fn basic_command_process (input_array: &[char; 8]) {
             test_command = false;
         }
     }
-    if test_command {
+    if test_command && !memory_in_use("test") {
         test();
     }
 
@@ -148,6 +408,116 @@ pub fn basic_command_process (input_array: &[char; 8]) {
     if kmem_command {
         pkmemtable();
     }
+
+    let pgdump_arr: [char; 6] = ['p', 'g', 'd', 'u', 'm', 'p'];
+    let mut pgdump_command: bool = true;
+    for i in 0..6 {
+        if input_array[i] != pgdump_arr[i] {
+            pgdump_command = false;
+        }
+    }
+    if pgdump_command {
+        pgdump(line);
+    }
+
+    let pgdiff_arr: [char; 6] = ['p', 'g', 'd', 'i', 'f', 'f'];
+    let mut pgdiff_command: bool = true;
+    for i in 0..6 {
+        if input_array[i] != pgdiff_arr[i] {
+            pgdiff_command = false;
+        }
+    }
+    if pgdiff_command {
+        pgdiff(line);
+    }
+
+    let slabinfo_arr: [char; 8] = ['s', 'l', 'a', 'b', 'i', 'n', 'f', 'o'];
+    let mut slabinfo_command: bool = true;
+    for i in 0..8 {
+        if input_array[i] != slabinfo_arr[i] {
+            slabinfo_command = false;
+        }
+    }
+    if slabinfo_command {
+        slab::print_slabinfo();
+    }
+
+    let heapcheck_arr: [char; 9] = ['h', 'e', 'a', 'p', 'c', 'h', 'e', 'c', 'k'];
+    let mut heapcheck_command: bool = true;
+    for i in 0..9 {
+        if input_array[i] != heapcheck_arr[i] {
+            heapcheck_command = false;
+        }
+    }
+    if heapcheck_command {
+        heapcheck();
+    }
+
+    let alloctrace_arr: [char; 10] = ['a', 'l', 'l', 'o', 'c', 't', 'r', 'a', 'c', 'e'];
+    let mut alloctrace_command: bool = true;
+    for i in 0..10 {
+        if input_array[i] != alloctrace_arr[i] {
+            alloctrace_command = false;
+        }
+    }
+    if alloctrace_command {
+        alloctrace(line);
+    }
+
+    let threads_arr: [char; 7] = ['t', 'h', 'r', 'e', 'a', 'd', 's'];
+    let mut threads_command: bool = true;
+    for i in 0..7 {
+        if input_array[i] != threads_arr[i] {
+            threads_command = false;
+        }
+    }
+    if threads_command {
+        thread::print_threads();
+    }
+
+    let exec_arr: [char; 4] = ['e', 'x', 'e', 'c'];
+    let mut exec_command: bool = true;
+    for i in 0..4 {
+        if input_array[i] != exec_arr[i] {
+            exec_command = false;
+        }
+    }
+    if exec_command {
+        exec(line);
+    }
+
+    let ps_arr: [char; 2] = ['p', 's'];
+    let mut ps_command: bool = true;
+    for i in 0..2 {
+        if input_array[i] != ps_arr[i] {
+            ps_command = false;
+        }
+    }
+    if ps_command {
+        process::print_processes();
+    }
+
+    let kill_arr: [char; 4] = ['k', 'i', 'l', 'l'];
+    let mut kill_command: bool = true;
+    for i in 0..4 {
+        if input_array[i] != kill_arr[i] {
+            kill_command = false;
+        }
+    }
+    if kill_command {
+        kill(line);
+    }
+
+    let wait_arr: [char; 4] = ['w', 'a', 'i', 't'];
+    let mut wait_command: bool = true;
+    for i in 0..4 {
+        if input_array[i] != wait_arr[i] {
+            wait_command = false;
+        }
+    }
+    if wait_command {
+        wait();
+    }
 }
 
 
@@ -157,14 +527,24 @@ unsafe extern "C" {
 }
 
 use crate::println;
-use crate::uart::Uart;
+use crate::console;
 use crate::print;
 
 // Initializes the process loop and uses arena allocaiton to allocate
 // a heap
 pub fn shmage_init() -> ! {
-    let mut uart_instance = Uart::new(0xD4017000);
-    // uart_instance.init();
+    crate::trap::init();
+    crate::tlb::init();
+    crate::stack::init();
+    crate::cpu::init_timebase();
+    // the shell loop becomes hart 0's boot thread, and gives other threads a turn while it waits for input
+    thread::init();
+    // and the timer takes turns away from threads that don't
+    crate::timer::init();
+    crate::cpu::restore_interrupts(true);
+    unsafe {
+        (*core::ptr::addr_of_mut!(SCRATCH)).init(core::ptr::addr_of_mut!(SCRATCH_BUFFER) as usize, SCRATCH_SIZE);
+    }
     shfetch();
    // page::init();
    // unsafe {
@@ -172,7 +552,7 @@ pub fn shmage_init() -> ! {
    // println!("heap size = {:#x}", HEAP_SIZE);
    // }
     //malloc::init();
-    let mut input_array: [char; 8] = [' ',' ',' ',' ',' ',' ',' ',' '];
+    let mut input_array: [char; INPUT_LENGTH] = [' '; INPUT_LENGTH];
     // single character input process loop
     let mut input_i: usize = 0;
     // prob eventually want to represent shell state in an enum
@@ -183,7 +563,7 @@ pub fn shmage_init() -> ! {
             prompt_active = false;
         }
         // Get the character
-        if let Some(c) = uart_instance.get() {
+        if let Some(c) = console::get(KERNEL_PID) {
             match c {
                 0x08b => {
                     // 8 is the backspace character, need to replace the
@@ -198,15 +578,17 @@ pub fn shmage_init() -> ! {
                     // carriage returns
                     println!();
                     basic_command_process(&input_array);
-                    input_array = [' ',' ',' ',' ',' ',' ',' ',' '];
+                    // whatever the command put in scratch memory is done with
+                    unsafe { (*core::ptr::addr_of_mut!(SCRATCH)).reset() };
+                    input_array = [' '; INPUT_LENGTH];
                     input_i = 0;
                     prompt_active = true;
                 },
                 0x1b => {
                     //ANSI escape sequences
-                    if let Some(next_byte) = uart_instance.get() {
+                    if let Some(next_byte) = console::get(KERNEL_PID) {
                         if next_byte == 91 {
-                            if let Some(b) = uart_instance.get() {
+                            if let Some(b) = console::get(KERNEL_PID) {
                                 match b as char {
                                         'A' => {
                                             println!("up arrow press");
@@ -231,12 +613,15 @@ pub fn shmage_init() -> ! {
                     }
                     _ => {
                         print!("{}", c as char);
-                        if input_i < 7 {
+                        if input_i < INPUT_LENGTH - 1 {
                             input_array[input_i] = c as char;
                             input_i += 1;
                         }
                 },
                 }
+            } else {
+                // nothing typed yet, give other threads a turn
+                thread::yield_now();
             }
             // Try to sleep the processor, i think this would work but
             // qemu uses a whole core
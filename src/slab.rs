@@ -0,0 +1,496 @@
+//! Slab allocator for fixed size kernel objects.
+//! Every cache hands out objects of one size. Its memory comes from the page allocator a slab at a
+//! time: a Slab header at the front, then as many objects as fit, with the free ones threaded into
+//! a list through a link in each. A slab is one page, or a few when that's what it takes to fit
+//! MIN_OBJECTS_PER_SLAB, and it's aligned to its size, so the slab an object lives in is just its
+//! address rounded down to that.
+//! A cache with a constructor runs it once per object, when the slab is carved up, and expects
+//! objects back in that state. Their link goes right after the object so it doesn't clobber it,
+//! other caches keep it in the object's first 8 bytes.
+//! Caches live in a fixed table so slabinfo can walk them. The first few are the kmalloc-N size
+//! classes the global allocator sends small allocations to, the rest get made with create_cache.
+//! Each hart keeps a magazine of objects per cache and allocates from and frees into it without
+//! locking anything. Only when a magazine runs empty (or full) does the hart take the cache's
+//! lock and move half a magazine of objects between it and the slabs
+use crate::cpu::{self, MAX_HARTS};
+use crate::page::{self, PAGE_SIZE};
+use crate::sync::{SpinLock, SLAB_CACHES_CLASS, SLAB_CACHE_CLASS};
+use crate::{println, print};
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlabError {
+    // the cache table is full
+    TooManyCaches,
+    // fewer than one object fits in a slab next to the header
+    ObjectTooLarge,
+    // objects have to be at least a byte
+    ZeroSize,
+    // alignment has to be a power of two
+    InvalidAlignment,
+}
+
+// How many caches can exist at once, size classes included
+pub const MAX_CACHES: usize = 32;
+// Object sizes the global allocator serves from slabs, anything bigger goes to the heap
+pub const SIZE_CLASSES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+pub const MAX_SLAB_OBJECT: usize = SIZE_CLASSES[SIZE_CLASSES.len() - 1];
+const SIZE_CLASS_NAMES: [&str; 7] = ["kmalloc-16", "kmalloc-32", "kmalloc-64", "kmalloc-128", "kmalloc-256", "kmalloc-512", "kmalloc-1024"];
+// How many objects a hart keeps on hand per cache
+pub const MAGAZINE_SIZE: usize = 16;
+// Slabs grow past a page until at least this many objects fit, up to MAX_SLAB_PAGES
+pub const MIN_OBJECTS_PER_SLAB: usize = 8;
+pub const MAX_SLAB_PAGES: usize = 8;
+
+// The link a free object keeps to the next one
+const LINK_SIZE: usize = size_of::<*mut u8>();
+
+// Sits at the start of every slab
+#[repr(C)]
+struct Slab {
+    next: *mut Slab,
+    // the first free object
+    free: *mut u8,
+    in_use: usize,
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+// How many objects fit in a slab of some pages
+const fn capacity(pages: usize, first_object: usize, stride: usize) -> usize {
+    let slab_size = pages * PAGE_SIZE;
+    if first_object < slab_size { (slab_size - first_object) / stride } else { 0 }
+}
+
+struct Cache {
+    name: &'static str,
+    // distance between objects, the requested size (and link) rounded up to the alignment
+    stride: usize,
+    object_size: usize,
+    // where a free object keeps its link, 0 unless there's a constructor
+    link_offset: usize,
+    // offset of the first object from the start of the slab
+    first_object: usize,
+    // pages in a slab, a power of two
+    pages: usize,
+    capacity: usize,
+    constructor: Option<fn(*mut u8)>,
+    // slabs with at least one free object, and slabs with none
+    partial: *mut Slab,
+    full: *mut Slab,
+    // slabs with nothing handed out, one of them is kept as a spare instead of going back to the page allocator
+    empty_slabs: usize,
+    slabs: usize,
+    // held while the slabs and the fields above change, the counters below get bumped without it
+    lock: SpinLock,
+    active_objects: AtomicUsize,
+    allocations: AtomicUsize,
+    frees: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+impl Cache {
+    const fn empty() -> Self {
+        Cache {
+            name: "",
+            stride: 0,
+            object_size: 0,
+            link_offset: 0,
+            first_object: 0,
+            pages: 1,
+            capacity: 0,
+            constructor: None,
+            partial: null_mut(),
+            full: null_mut(),
+            empty_slabs: 0,
+            slabs: 0,
+            lock: SpinLock::ranked(SLAB_CACHE_CLASS),
+            active_objects: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    const fn new(name: &'static str, size: usize, align: usize, constructor: Option<fn(*mut u8)>) -> Self {
+        let align = if align < LINK_SIZE { LINK_SIZE } else { align };
+        // free objects have to hold a link, constructed ones next to them rather than inside
+        let (link_offset, footprint) = match constructor {
+            Some(_) => (align_up(size, LINK_SIZE), align_up(size, LINK_SIZE) + LINK_SIZE),
+            None => (0, if size < LINK_SIZE { LINK_SIZE } else { size }),
+        };
+        let stride = align_up(footprint, align);
+        let first_object = align_up(size_of::<Slab>(), align);
+        let mut pages = 1;
+        while pages < MAX_SLAB_PAGES && capacity(pages, first_object, stride) < MIN_OBJECTS_PER_SLAB {
+            pages *= 2;
+        }
+        let mut cache = Cache::empty();
+        cache.name = name;
+        cache.stride = stride;
+        cache.object_size = size;
+        cache.link_offset = link_offset;
+        cache.first_object = first_object;
+        cache.pages = pages;
+        cache.capacity = capacity(pages, first_object, stride);
+        cache.constructor = constructor;
+        cache
+    }
+
+    // forget every slab, the pages they were on are gone after a page::init
+    fn reset(&mut self) {
+        self.partial = null_mut();
+        self.full = null_mut();
+        self.empty_slabs = 0;
+        self.slabs = 0;
+        self.active_objects.store(0, Ordering::Relaxed);
+        self.allocations.store(0, Ordering::Relaxed);
+        self.frees.store(0, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    fn slab_size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    // Where a free object keeps the next one
+    fn link(&self, object: *mut u8) -> *mut *mut u8 {
+        unsafe { object.add(self.link_offset) as *mut *mut u8 }
+    }
+
+    // Carve a fresh slab into objects, constructing each one, and put it on the partial list
+    fn grow(&mut self) -> bool {
+        let memory = page::alloc_constrained(self.pages, self.slab_size(), 0);
+        if memory.is_null() {
+            return false;
+        }
+        unsafe {
+            let slab = memory as *mut Slab;
+            (*slab).in_use = 0;
+            (*slab).free = null_mut();
+            // thread the free list back to front so objects go out in address order
+            for i in (0..self.capacity).rev() {
+                let object = memory.add(self.first_object + i * self.stride);
+                if let Some(constructor) = self.constructor {
+                    constructor(object);
+                }
+                *self.link(object) = (*slab).free;
+                (*slab).free = object;
+            }
+            (*slab).next = self.partial;
+            self.partial = slab;
+        }
+        self.slabs += 1;
+        self.empty_slabs += 1;
+        true
+    }
+
+    // Take an object out of the slabs, the lock has to be held
+    fn take_object(&mut self) -> *mut u8 {
+        if self.partial.is_null() && !self.grow() {
+            return null_mut();
+        }
+        unsafe {
+            let slab = self.partial;
+            let object = (*slab).free;
+            (*slab).free = *self.link(object);
+            if (*slab).in_use == 0 {
+                self.empty_slabs -= 1;
+            }
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                // out of objects, move it over to the full list
+                self.partial = (*slab).next;
+                (*slab).next = self.full;
+                self.full = slab;
+            }
+            object
+        }
+    }
+
+    // Put an object back in its slab, the lock has to be held
+    fn return_object(&mut self, object: *mut u8) {
+        let slab = (object as usize & !(self.slab_size() - 1)) as *mut Slab;
+        unsafe {
+            let was_full = (*slab).free.is_null();
+            *self.link(object) = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+            if was_full {
+                unlink(&mut self.full, slab);
+                (*slab).next = self.partial;
+                self.partial = slab;
+            }
+            if (*slab).in_use == 0 {
+                if self.empty_slabs > 0 {
+                    // already have a spare, give this one back
+                    unlink(&mut self.partial, slab);
+                    self.slabs -= 1;
+                    page::dealloc(slab as *mut u8);
+                } else {
+                    self.empty_slabs += 1;
+                }
+            }
+        }
+    }
+}
+
+// Take a slab out of a singly linked slab list
+unsafe fn unlink(list: &mut *mut Slab, slab: *mut Slab) {
+    unsafe {
+        if *list == slab {
+            *list = (*slab).next;
+            return;
+        }
+        let mut previous = *list;
+        while !previous.is_null() && (*previous).next != slab {
+            previous = (*previous).next;
+        }
+        if !previous.is_null() {
+            (*previous).next = (*slab).next;
+        }
+    }
+}
+
+static mut CACHES: [Cache; MAX_CACHES] = {
+    let mut caches = [const { Cache::empty() }; MAX_CACHES];
+    let mut i = 0;
+    while i < SIZE_CLASSES.len() {
+        caches[i] = Cache::new(SIZE_CLASS_NAMES[i], SIZE_CLASSES[i], SIZE_CLASSES[i], None);
+        i += 1;
+    }
+    caches
+};
+// Only goes up once the new entry is written, so whoever loads the count can read that many
+// entries without the lock
+static NUM_CACHES: AtomicUsize = AtomicUsize::new(SIZE_CLASSES.len());
+// Held while a cache is being added to the table
+static CACHES_LOCK: SpinLock = SpinLock::ranked(SLAB_CACHES_CLASS);
+
+fn number_of_caches() -> usize {
+    NUM_CACHES.load(Ordering::Acquire)
+}
+
+// Only touch the slab side of a cache with its lock held
+fn cache(index: usize) -> *mut Cache {
+    unsafe { core::ptr::addr_of_mut!(CACHES[index]) }
+}
+
+// A hart's private stash of objects from one cache
+struct Magazine {
+    count: usize,
+    objects: [*mut u8; MAGAZINE_SIZE],
+}
+
+static mut MAGAZINES: [[Magazine; MAX_HARTS]; MAX_CACHES] =
+    [const { [const { Magazine { count: 0, objects: [null_mut(); MAGAZINE_SIZE] } }; MAX_HARTS] }; MAX_CACHES];
+
+// This hart's magazine for a cache. Interrupts have to stay off while it's in use, otherwise
+// something else on the hart could grab the same magazine halfway through
+fn magazine(index: usize) -> &'static mut Magazine {
+    unsafe { &mut (*core::ptr::addr_of_mut!(MAGAZINES))[index][cpu::hart_id()] }
+}
+
+// Drop every slab and empty every magazine. page::init hands all pages out again, so this has to
+// run after it (malloc::init takes care of that)
+pub fn init() {
+    for index in 0..number_of_caches() {
+        let cache = cache(index);
+        unsafe {
+            let _guard = (*cache).lock.lock();
+            (*cache).reset();
+            for magazine in (*core::ptr::addr_of_mut!(MAGAZINES))[index].iter_mut() {
+                magazine.count = 0;
+            }
+        }
+    }
+}
+
+// A handle to one of the caches, cheap to copy around
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SlabCache(usize);
+
+// Statistics for one cache, as slabinfo prints them
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub pages_per_slab: usize,
+    pub active_objects: usize,
+    pub total_objects: usize,
+    pub slabs: usize,
+    // sitting in the harts' magazines, neither handed out nor in a slab
+    pub cached_objects: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub failures: usize,
+}
+
+// Make a named cache for objects of size bytes at the given alignment. When there is a
+// constructor it runs once on each object as its slab gets carved up, and objects have to be freed
+// back in the state it left them in
+pub fn create_cache(name: &'static str, size: usize, align: usize, constructor: Option<fn(*mut u8)>) -> Result<SlabCache, SlabError> {
+    if !align.is_power_of_two() {
+        return Err(SlabError::InvalidAlignment);
+    }
+    if size == 0 {
+        return Err(SlabError::ZeroSize);
+    }
+    let cache = Cache::new(name, size, align, constructor);
+    if cache.capacity == 0 {
+        return Err(SlabError::ObjectTooLarge);
+    }
+    let _guard = CACHES_LOCK.lock();
+    let index = NUM_CACHES.load(Ordering::Relaxed);
+    if index == MAX_CACHES {
+        return Err(SlabError::TooManyCaches);
+    }
+    unsafe { (*core::ptr::addr_of_mut!(CACHES))[index] = cache };
+    NUM_CACHES.store(index + 1, Ordering::Release);
+    Ok(SlabCache(index))
+}
+
+impl SlabCache {
+    // An object from the cache, null if the page allocator is out of pages
+    pub fn alloc(self) -> *mut u8 {
+        let interrupts = cpu::interrupts_off();
+        let magazine = magazine(self.0);
+        if magazine.count == 0 {
+            // fill half the magazine up from the slabs
+            let cache = cache(self.0);
+            unsafe {
+                let _guard = (*cache).lock.lock();
+                while magazine.count < MAGAZINE_SIZE / 2 {
+                    let object = (*cache).take_object();
+                    if object.is_null() {
+                        break;
+                    }
+                    magazine.objects[magazine.count] = object;
+                    magazine.count += 1;
+                }
+            }
+        }
+        let object = if magazine.count > 0 {
+            magazine.count -= 1;
+            magazine.objects[magazine.count]
+        } else {
+            null_mut()
+        };
+        cpu::restore_interrupts(interrupts);
+        let cache = unsafe { &*cache(self.0) };
+        if object.is_null() {
+            cache.failures.fetch_add(1, Ordering::Relaxed);
+            return object;
+        }
+        cache.allocations.fetch_add(1, Ordering::Relaxed);
+        cache.active_objects.fetch_add(1, Ordering::Relaxed);
+        object
+    }
+
+    // Hand an object back, it has to have come from this cache
+    pub fn free(self, object: *mut u8) {
+        if object.is_null() {
+            return;
+        }
+        let interrupts = cpu::interrupts_off();
+        let magazine = magazine(self.0);
+        if magazine.count == MAGAZINE_SIZE {
+            // full, send half of it back to the slabs
+            self.flush(magazine, MAGAZINE_SIZE / 2);
+        }
+        magazine.objects[magazine.count] = object;
+        magazine.count += 1;
+        cpu::restore_interrupts(interrupts);
+        let cache = unsafe { &*cache(self.0) };
+        cache.frees.fetch_add(1, Ordering::Relaxed);
+        cache.active_objects.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Move objects from the top of a magazine back into the slabs
+    fn flush(self, magazine: &mut Magazine, objects: usize) {
+        let cache = cache(self.0);
+        unsafe {
+            let _guard = (*cache).lock.lock();
+            for _ in 0..objects.min(magazine.count) {
+                magazine.count -= 1;
+                (*cache).return_object(magazine.objects[magazine.count]);
+            }
+        }
+    }
+
+    // Give everything in this hart's magazine back to the slabs, so empty slabs can go back to
+    // the page allocator
+    pub fn drain(self) {
+        let interrupts = cpu::interrupts_off();
+        let magazine = magazine(self.0);
+        self.flush(magazine, MAGAZINE_SIZE);
+        cpu::restore_interrupts(interrupts);
+    }
+
+    pub fn stats(self) -> CacheStats {
+        let cache = unsafe { &*cache(self.0) };
+        let (slabs, free_in_slabs) = {
+            let _guard = cache.lock.lock();
+            let mut free_in_slabs = 0;
+            let mut slab = cache.partial;
+            while !slab.is_null() {
+                unsafe {
+                    free_in_slabs += cache.capacity - (*slab).in_use;
+                    slab = (*slab).next;
+                }
+            }
+            (cache.slabs, free_in_slabs)
+        };
+        let active_objects = cache.active_objects.load(Ordering::Relaxed);
+        let total_objects = slabs * cache.capacity;
+        CacheStats {
+            name: cache.name,
+            object_size: cache.object_size,
+            objects_per_slab: cache.capacity,
+            pages_per_slab: cache.pages,
+            active_objects,
+            total_objects,
+            slabs,
+            // the counters aren't updated under the lock, so this can be a little off while harts are busy
+            cached_objects: (total_objects - free_in_slabs).saturating_sub(active_objects),
+            allocations: cache.allocations.load(Ordering::Relaxed),
+            frees: cache.frees.load(Ordering::Relaxed),
+            failures: cache.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Drain this hart's magazines in every cache
+pub fn drain_all() {
+    for_each_cache(|cache| cache.drain());
+}
+
+// The size class cache for an allocation, if it's small enough for one
+pub fn size_class(size: usize, align: usize) -> Option<SlabCache> {
+    SIZE_CLASSES.iter()
+        .position(|&class| size <= class && align <= class)
+        .map(SlabCache)
+}
+
+pub fn for_each_cache<F: FnMut(SlabCache)>(mut f: F) {
+    for i in 0..number_of_caches() {
+        f(SlabCache(i));
+    }
+}
+
+// print every cache's statistics, for the slabinfo command
+pub fn print_slabinfo() {
+    println!("{:<16} {:>6} {:>8} {:>8} {:>8} {:>6} {:>6} {:>10} {:>10} {:>6}",
+        "name", "size", "active", "cached", "total", "slabs", "per", "allocs", "frees", "fails");
+    for_each_cache(|cache| {
+        let stats = cache.stats();
+        println!("{:<16} {:>6} {:>8} {:>8} {:>8} {:>6} {:>6} {:>10} {:>10} {:>6}",
+            stats.name, stats.object_size, stats.active_objects, stats.cached_objects, stats.total_objects,
+            stats.slabs, stats.objects_per_slab, stats.allocations, stats.frees, stats.failures);
+    });
+}
@@ -0,0 +1,187 @@
+//! Kernel stacks with a guard page underneath.
+//! A stack is page::alloc'd with one extra page at the bottom. The ones that have to outlive
+//! page::init (the boot stack, each hart's trap stack and idle thread stacks) are GuardedStacks in
+//! .bss, laid out the same way. The kernel runs without translation, so nothing faults when a
+//! stack runs into that page. Instead it's filled with a canary, and every trap and every context
+//! switch checks the stack it's leaving: sp sitting in a guard page or a guard page with its
+//! canary scribbled over means the stack overflowed. Every guard is registered along with who
+//! owns the stack, so that turns into "stack overflow on hart N / task T".
+//! This only notices an overflow after the fact: by the time the check runs the guard page (and
+//! whatever the overflow wrote through it) is already damaged. A frame big enough to jump right
+//! over the guard page and leave the canary alone isn't noticed at all
+use crate::cpu;
+use crate::page::PAGE_SIZE;
+use crate::page_box::PageRange;
+use crate::sync::{SpinLock, STACK_CLASS};
+
+unsafe extern "C" {
+    static KERNEL_STACK_GUARD: usize;
+    static KERNEL_STACK_END: usize;
+}
+
+// 16 KiB of usable stack per kernel thread
+pub const KERNEL_STACK_PAGES: usize = 4;
+// How many guard pages can be registered at once (one per kernel stack, threads have two, and
+// each hart has its trap and idle stacks)
+pub const MAX_GUARDS: usize = 128;
+// What an untouched guard page holds, word after word
+const CANARY: usize = 0x57ac_c0de_57ac_c0de;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StackError {
+    // no pages for the stack
+    OutOfMemory,
+    // every guard slot is registered already, see MAX_GUARDS
+    TooManyGuards,
+}
+
+// Who a guard page belongs to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StackOwner {
+    pub hart: usize,
+    pub task: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Guard {
+    // 0 for a free slot
+    address: usize,
+    // where the stack above the guard starts
+    top: usize,
+    owner: StackOwner,
+}
+
+// Only touched with GUARDS_LOCK held, stacks come and go on every hart
+static GUARDS_LOCK: SpinLock = SpinLock::ranked(STACK_CLASS);
+static mut GUARDS: [Guard; MAX_GUARDS] = [Guard { address: 0, top: 0, owner: StackOwner { hart: 0, task: 0 } }; MAX_GUARDS];
+
+// Fill the guard page with the canary and remember whose it is. A guard that's registered already
+// just gets the new owner, the static stacks get registered again every time their hart comes up
+fn register_guard(address: usize, top: usize, owner: StackOwner) -> Result<(), StackError> {
+    let words = unsafe { core::slice::from_raw_parts_mut(address as *mut usize, PAGE_SIZE / size_of::<usize>()) };
+    words.fill(CANARY);
+    let _guard = GUARDS_LOCK.lock();
+    let guards = unsafe { &mut *core::ptr::addr_of_mut!(GUARDS) };
+    let slot = match guards.iter().position(|guard| guard.address == address) {
+        Some(i) => &mut guards[i],
+        None => guards.iter_mut().find(|guard| guard.address == 0).ok_or(StackError::TooManyGuards)?,
+    };
+    *slot = Guard { address, top, owner };
+    Ok(())
+}
+
+fn unregister_guard(address: usize) {
+    let _guard = GUARDS_LOCK.lock();
+    let guards = unsafe { &mut *core::ptr::addr_of_mut!(GUARDS) };
+    if let Some(guard) = guards.iter_mut().find(|guard| guard.address == address) {
+        guard.address = 0;
+    }
+}
+
+fn canary_intact(address: usize) -> bool {
+    let words = unsafe { core::slice::from_raw_parts(address as *const usize, PAGE_SIZE / size_of::<usize>()) };
+    words.iter().all(|&word| word == CANARY)
+}
+
+// If the address falls inside a registered guard page, say whose stack overflowed
+pub fn guard_owner(address: usize) -> Option<StackOwner> {
+    let _guard = GUARDS_LOCK.lock();
+    let guards = unsafe { &*core::ptr::addr_of!(GUARDS) };
+    guards.iter()
+        .find(|guard| guard.address != 0 && address >= guard.address && address < guard.address + PAGE_SIZE)
+        .map(|guard| guard.owner)
+}
+
+// Whose stack overflowed, if sp is on a registered stack (guard included) that did
+pub fn overflowed(sp: usize) -> Option<StackOwner> {
+    let _guard = GUARDS_LOCK.lock();
+    let guards = unsafe { &*core::ptr::addr_of!(GUARDS) };
+    let guard = guards.iter().find(|guard| guard.address != 0 && sp >= guard.address && sp < guard.top)?;
+    (sp < guard.address + PAGE_SIZE || !canary_intact(guard.address)).then_some(guard.owner)
+}
+
+// Panic if the stack sp is on has run into its guard page
+pub fn check(sp: usize) {
+    if let Some(owner) = overflowed(sp) {
+        panic!("stack overflow on hart {} / task {} (sp 0x{:x})", owner.hart, owner.task, sp);
+    }
+}
+
+// Same for the stack this is running on
+#[inline(always)]
+pub fn check_current() {
+    let marker = 0u8;
+    check(core::ptr::addr_of!(marker) as usize);
+}
+
+// The boot stack from virt.lds already has its guard page below it, just register it for the
+// hart that booted on it. Harts started later run on whatever stack start_hart was handed, which
+// should be a KernelStack
+pub fn init() {
+    let (guard, top) = unsafe { (KERNEL_STACK_GUARD, KERNEL_STACK_END) };
+    let hart = cpu::hart_id();
+    let _ = register_guard(guard, top, StackOwner { hart, task: hart });
+}
+
+// A stack in .bss, its guard page at the bottom, for the ones page::init mustn't take back.
+// Page aligned so the guard is a whole page of its own
+#[repr(C, align(4096))]
+pub struct GuardedStack<const SIZE: usize> {
+    guard: [u8; PAGE_SIZE],
+    stack: [u8; SIZE],
+}
+
+impl<const SIZE: usize> GuardedStack<SIZE> {
+    pub const fn new() -> Self {
+        GuardedStack { guard: [0; PAGE_SIZE], stack: [0; SIZE] }
+    }
+}
+
+impl<const SIZE: usize> Default for GuardedStack<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Register the stack's guard page for owner and give back where sp starts. Done each time the
+// hart using it comes up, registering it again only changes the owner. If every guard slot is
+// taken the stack still works, it just isn't checked
+pub fn register_static<const SIZE: usize>(stack: *mut GuardedStack<SIZE>, owner: StackOwner) -> usize {
+    let guard = stack as usize;
+    let top = guard + size_of::<GuardedStack<SIZE>>();
+    let _ = register_guard(guard, top, owner);
+    top
+}
+
+pub struct KernelStack {
+    // the guard page and then the usable stack, freed when the stack drops
+    allocation: PageRange,
+}
+
+impl KernelStack {
+    pub fn new(pages: usize, owner: StackOwner) -> Result<Self, StackError> {
+        let allocation = PageRange::zeroed(pages + 1).ok_or(StackError::OutOfMemory)?;
+        register_guard(allocation.address(), allocation.address() + allocation.size(), owner)?;
+        Ok(KernelStack { allocation })
+    }
+
+    pub fn guard(&self) -> usize {
+        self.allocation.address()
+    }
+
+    // lowest usable address
+    pub fn bottom(&self) -> usize {
+        self.guard() + PAGE_SIZE
+    }
+
+    // where sp starts, the stack grows down from here (page aligned so 16 byte aligned too)
+    pub fn top(&self) -> usize {
+        self.guard() + self.allocation.size()
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        unregister_guard(self.guard());
+    }
+}
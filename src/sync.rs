@@ -0,0 +1,728 @@
+//! Locks and the other ways threads and harts wait on each other.
+//! SpinLock is the bare test and set lock the allocators and the scheduler use: no data of its
+//! own, and it can be held across a context switch with lock_handoff. Everything else owns
+//! the data it protects and hands it out through a guard:
+//! - IrqSpinLock and TicketLock spin, with interrupts off on the hart while they're held. The
+//!   ticket lock serves whoever asked first, so nobody starves when it's contended
+//! - Mutex and RwLock put a waiting thread to sleep on a thread::WaitQueue instead, for anything
+//!   held a while or across something that blocks. They're for threads, not interrupt handlers.
+//!   On a hart that doesn't schedule threads they spin
+//! - Semaphore counts, Condvar waits for a Mutex protected condition
+//! - Once and Lazy run an initializer exactly once, for statics that can't be const
+//!
+//! Every lock can have a LockClass with a rank. With the debug-locks feature each thread keeps
+//! track of the ranked locks it holds, and taking one whose rank isn't above every one of them
+//! panics with both names, long before the two orders meet on two harts and deadlock. The
+//! kernel's own locks are ranked below (PROCESS_CLASS and on). The one exception is the
+//! scheduler's lock across a context switch: the thread that takes it isn't the one that lets it
+//! go, so lock_handoff checks the order going in and then doesn't count it as held
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use crate::cpu;
+use crate::thread::{self, WaitQueue};
+
+pub struct SpinLock {
+    locked: AtomicBool,
+    class: LockClass,
+}
+
+// Dropping it unlocks, and turns interrupts back on if they were on before
+pub struct SpinLockGuard<'a> {
+    lock: &'a SpinLock,
+    interrupts: bool,
+}
+
+impl SpinLock {
+    pub const fn new() -> Self {
+        Self::ranked(UNRANKED)
+    }
+
+    pub const fn ranked(class: LockClass) -> Self {
+        SpinLock { locked: AtomicBool::new(false), class }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_> {
+        let interrupts = cpu::interrupts_off();
+        order_acquire(&self.class, true);
+        self.spin();
+        SpinLockGuard { lock: self, interrupts }
+    }
+
+    fn spin(&self) {
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            // spin on a plain load so we aren't hammering the cache line with writes
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_>> {
+        let interrupts = cpu::interrupts_off();
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            order_acquire(&self.class, false);
+            Some(SpinLockGuard { lock: self, interrupts })
+        } else {
+            cpu::restore_interrupts(interrupts);
+            None
+        }
+    }
+
+    /// Lock to hand over to another thread, there's no guard. The scheduler takes its lock like
+    /// this before switch_context and whichever thread runs next unlocks it with force_unlock.
+    /// The lock order is checked same as lock, but the lock isn't counted as held afterwards,
+    /// since the thread that took it won't be the one letting it go. Interrupts are left alone,
+    /// the caller turns them off first
+    pub fn lock_handoff(&self) {
+        order_acquire(&self.class, true);
+        order_release(&self.class);
+        self.spin();
+    }
+
+    /// Unlock without a guard. The thread scheduler holds its lock across a context switch by
+    /// taking it with lock_handoff, and whichever thread runs next unlocks it with this.
+    ///
+    /// # Safety
+    /// The lock has to be held, and nobody else may still think they hold it. Interrupts stay
+    /// however they are
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for SpinLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for SpinLockGuard<'_> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        order_release(&self.lock.class);
+        cpu::restore_interrupts(self.interrupts);
+    }
+}
+
+// Where a lock sits in the lock order. Rank 0 is unranked and never checked, otherwise a lock
+// can only be taken while everything ranked that's held has a lower rank
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockClass {
+    pub name: &'static str,
+    pub rank: usize,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str, rank: usize) -> Self {
+        LockClass { name, rank }
+    }
+}
+
+pub const UNRANKED: LockClass = LockClass::new("unranked", 0);
+
+// The kernel's own locks, outermost first. The process table allocates with it held, the
+// scheduler frees dead threads, the heap and the slab caches grow from the page allocator, and
+// the page allocator records into the trace. The asid and stack guard locks nest nothing, and the
+// guards get checked on every trap and switch, under whatever else is held. Ranks leave room below
+// for anything else that wants to be checked
+pub const PROCESS_CLASS: LockClass = LockClass::new("process table", 100);
+pub const SCHEDULER_CLASS: LockClass = LockClass::new("scheduler", 200);
+pub const HEAP_CLASS: LockClass = LockClass::new("heap", 300);
+pub const SLAB_CACHES_CLASS: LockClass = LockClass::new("slab caches", 400);
+// every slab cache shares the one, no cache is taken while holding another
+pub const SLAB_CACHE_CLASS: LockClass = LockClass::new("slab cache", 500);
+pub const PAGE_CLASS: LockClass = LockClass::new("page", 600);
+pub const ASID_CLASS: LockClass = LockClass::new("asid", 700);
+pub const TRACE_CLASS: LockClass = LockClass::new("alloc trace", 800);
+pub const STACK_CLASS: LockClass = LockClass::new("stack guards", 900);
+
+// How many ranked locks one thread can hold at once before the checks give up
+#[cfg(feature = "debug-locks")]
+const MAX_HELD: usize = 16;
+
+// The ranked locks a thread (or a hart outside of threads) holds, in the order it took them
+#[cfg(feature = "debug-locks")]
+pub struct HeldLocks {
+    classes: [LockClass; MAX_HELD],
+    depth: usize,
+}
+
+#[cfg(feature = "debug-locks")]
+impl HeldLocks {
+    pub const fn new() -> Self {
+        HeldLocks { classes: [UNRANKED; MAX_HELD], depth: 0 }
+    }
+}
+
+#[cfg(feature = "debug-locks")]
+impl Default for HeldLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// For harts that aren't running a thread, their boot code before thread::init say
+#[cfg(feature = "debug-locks")]
+static mut HART_HELD_LOCKS: [HeldLocks; cpu::MAX_HARTS] = [const { HeldLocks::new() }; cpu::MAX_HARTS];
+
+#[cfg(feature = "debug-locks")]
+fn with_held_locks<T>(f: impl FnOnce(&mut HeldLocks) -> T) -> T {
+    // interrupts off so a handler taking a lock can't get in halfway through
+    let interrupts = cpu::interrupts_off();
+    let held = match thread::held_locks() {
+        Some(held) => unsafe { &mut *held },
+        None => unsafe { &mut (*core::ptr::addr_of_mut!(HART_HELD_LOCKS))[cpu::hart_id()] },
+    };
+    let result = f(held);
+    cpu::restore_interrupts(interrupts);
+    result
+}
+
+// About to take a lock of the class. check is false for try_lock, which can't deadlock, but the
+// lock still counts for whatever gets taken after it
+#[cfg(feature = "debug-locks")]
+fn order_acquire(class: &LockClass, check: bool) {
+    if class.rank == 0 {
+        return;
+    }
+    with_held_locks(|held| {
+        if check && let Some(top) = held.classes[..held.depth].iter().max_by_key(|held| held.rank)
+            && top.rank >= class.rank {
+            panic!("lock order: taking {} (rank {}) while holding {} (rank {})", class.name, class.rank, top.name, top.rank);
+        }
+        assert!(held.depth < MAX_HELD, "lock order: more than {} ranked locks held", MAX_HELD);
+        held.classes[held.depth] = *class;
+        held.depth += 1;
+    });
+}
+
+// Done with a lock of the class, locks don't have to be let go in the order they were taken
+#[cfg(feature = "debug-locks")]
+fn order_release(class: &LockClass) {
+    if class.rank == 0 {
+        return;
+    }
+    with_held_locks(|held| {
+        if let Some(i) = held.classes[..held.depth].iter().rposition(|held| held == class) {
+            held.classes.copy_within(i + 1..held.depth, i);
+            held.depth -= 1;
+        }
+    });
+}
+
+#[cfg(not(feature = "debug-locks"))]
+fn order_acquire(_class: &LockClass, _check: bool) {}
+
+#[cfg(not(feature = "debug-locks"))]
+fn order_release(_class: &LockClass) {}
+
+// A SpinLock around the data, interrupts are off on the hart while it's held
+pub struct IrqSpinLock<T> {
+    lock: SpinLock,
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for IrqSpinLock<T> {}
+unsafe impl<T: Send> Sync for IrqSpinLock<T> {}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    lock: &'a IrqSpinLock<T>,
+    _guard: SpinLockGuard<'a>,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self::ranked(data, UNRANKED)
+    }
+
+    pub const fn ranked(data: T, class: LockClass) -> Self {
+        IrqSpinLock { lock: SpinLock::new(), class, data: UnsafeCell::new(data) }
+    }
+
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        order_acquire(&self.class, true);
+        IrqSpinLockGuard { lock: self, _guard: self.lock.lock() }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let guard = self.lock.try_lock()?;
+        order_acquire(&self.class, false);
+        Some(IrqSpinLockGuard { lock: self, _guard: guard })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.lock.is_locked()
+    }
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T> {
+    // the SpinLockGuard unlocks right after
+    fn drop(&mut self) {
+        order_release(&self.lock.class);
+    }
+}
+
+// A spinlock that goes to whoever asked first: lock takes a ticket and waits for it to come up.
+// Interrupts are off on the hart while it's held
+pub struct TicketLock<T> {
+    next: AtomicUsize,
+    serving: AtomicUsize,
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for TicketLock<T> {}
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+
+pub struct TicketLockGuard<'a, T> {
+    lock: &'a TicketLock<T>,
+    interrupts: bool,
+}
+
+impl<T> TicketLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self::ranked(data, UNRANKED)
+    }
+
+    pub const fn ranked(data: T, class: LockClass) -> Self {
+        TicketLock { next: AtomicUsize::new(0), serving: AtomicUsize::new(0), class, data: UnsafeCell::new(data) }
+    }
+
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        order_acquire(&self.class, true);
+        let interrupts = cpu::interrupts_off();
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            core::hint::spin_loop();
+        }
+        TicketLockGuard { lock: self, interrupts }
+    }
+
+    // Only takes a ticket if it would come up straight away
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        let interrupts = cpu::interrupts_off();
+        let serving = self.serving.load(Ordering::Relaxed);
+        if self.next.compare_exchange(serving, serving + 1, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            cpu::restore_interrupts(interrupts);
+            return None;
+        }
+        order_acquire(&self.class, false);
+        Some(TicketLockGuard { lock: self, interrupts })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        order_release(&self.lock.class);
+        self.lock.serving.fetch_add(1, Ordering::Release);
+        cpu::restore_interrupts(self.interrupts);
+    }
+}
+
+// A lock that sleeps instead of spinning. Waiters queue up and unlock wakes the one that has
+// waited longest, which then tries again
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self::ranked(data, UNRANKED)
+    }
+
+    pub const fn ranked(data: T, class: LockClass) -> Self {
+        Mutex { locked: AtomicBool::new(false), waiters: WaitQueue::new(), class, data: UnsafeCell::new(data) }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        order_acquire(&self.class, true);
+        while self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            self.waiters.wait_if(|| self.locked.load(Ordering::Relaxed));
+        }
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        order_acquire(&self.class, false);
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    // No locking needed, the borrow says nobody else has it
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        order_release(&self.mutex.class);
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
+
+// The writer bit of RwLock::state, the rest counts readers
+const WRITER: usize = 0b1 << (usize::BITS - 1);
+
+// Any number of readers or one writer, sleeping while they wait. Writers go first: once one is
+// waiting no new readers get in, so a steady stream of them can't starve it
+pub struct RwLock<T> {
+    state: AtomicUsize,
+    waiting_writers: AtomicUsize,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self::ranked(data, UNRANKED)
+    }
+
+    pub const fn ranked(data: T, class: LockClass) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            waiting_writers: AtomicUsize::new(0),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            class,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // A reader gets in when nobody's writing or waiting to
+    fn reader_blocked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & WRITER != 0 || self.waiting_writers.load(Ordering::Relaxed) != 0
+    }
+
+    fn try_read_once(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        !self.reader_blocked()
+            && self.state.compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        order_acquire(&self.class, true);
+        while !self.try_read_once() {
+            self.readers.wait_if(|| self.reader_blocked());
+        }
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        if !self.try_read_once() {
+            return None;
+        }
+        order_acquire(&self.class, false);
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        order_acquire(&self.class, true);
+        self.waiting_writers.fetch_add(1, Ordering::Relaxed);
+        while self.state.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            self.writers.wait_if(|| self.state.load(Ordering::Relaxed) != 0);
+        }
+        self.waiting_writers.fetch_sub(1, Ordering::Relaxed);
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        order_acquire(&self.class, false);
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    // How many readers are in, for debugging
+    pub fn readers(&self) -> usize {
+        self.state.load(Ordering::Relaxed) & !WRITER
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        order_release(&self.lock.class);
+        // the last reader out lets a writer in
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.writers.wake_one();
+        }
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        order_release(&self.lock.class);
+        self.lock.state.store(0, Ordering::Release);
+        // another writer if there is one, the readers check for themselves whether it's their turn
+        self.lock.writers.wake_one();
+        self.lock.readers.wake_all();
+    }
+}
+
+// A counting semaphore: acquire takes one of count, sleeping until there is one, release gives
+// one back
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore { count: AtomicUsize::new(count), waiters: WaitQueue::new() }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.count.fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| count.checked_sub(1)).is_ok()
+    }
+
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            self.waiters.wait_if(|| self.count.load(Ordering::Relaxed) == 0);
+        }
+    }
+
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+// Waits for a condition on the data behind a Mutex. Wakeups can be spurious (a notify_one can
+// wake more than one waiter), so wait in a loop or use wait_while
+pub struct Condvar {
+    // bumped by every notify, a waiter only sleeps if it hasn't moved since it let go of the mutex
+    sequence: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar { sequence: AtomicUsize::new(0), waiters: WaitQueue::new() }
+    }
+
+    // Let go of the mutex, sleep until notified, and take it again
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        drop(guard);
+        self.waiters.wait_if(|| self.sequence.load(Ordering::Relaxed) == sequence);
+        mutex.lock()
+    }
+
+    // wait for as long as condition holds
+    pub fn wait_while<'a, T>(&self, mut guard: MutexGuard<'a, T>, mut condition: impl FnMut(&mut T) -> bool) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+// A value that gets set exactly once. The first call_once runs its closure, everyone else (on any
+// hart) waits for it to finish and gets the same value
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for Once<T> {}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Once { state: AtomicU8::new(INCOMPLETE), value: UnsafeCell::new(MaybeUninit::uninit()) }
+    }
+
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        if self.state.compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire).is_ok() {
+            unsafe { (*self.value.get()).write(f()) };
+            self.state.store(COMPLETE, Ordering::Release);
+        }
+        while self.state.load(Ordering::Acquire) != COMPLETE {
+            // whoever is running f might be on this hart
+            thread::yield_now();
+            core::hint::spin_loop();
+        }
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.is_completed().then(|| unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+// A value built by init the first time it's used, for statics that need more than a const fn
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: UnsafeCell<Option<F>>,
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Lazy { once: Once::new(), init: UnsafeCell::new(Some(init)) }
+    }
+
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            // only the one call_once that runs gets here
+            let init = unsafe { (*this.init.get()).take() };
+            init.expect("Lazy initializer ran twice")()
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
@@ -0,0 +1,352 @@
+//! System calls.
+//! A process makes one with ecall: the call number goes in a7, up to six arguments in a0-a5, and
+//! the result comes back in a0. Failures come back as a negative errno in a0 (so -4095..=-1 is
+//! always an error), the same convention Linux uses. Every pointer, length and flag a process
+//! passes in gets checked here before the kernel acts on it, a bad one is an error for the
+//! process and never a fault in the kernel.
+//! The numbers are the ABI user programs are built against: a number never changes its meaning,
+//! new calls get new numbers
+use crate::address_space::MapError;
+use crate::page::{self, PageTableEntryBits, PAGE_SIZE};
+use crate::console;
+use crate::cpu;
+use crate::elf;
+use crate::process::{self, Exit, Process, HEAP_START, MMAP_END, MMAP_START};
+use crate::thread;
+use crate::timer;
+use crate::trap;
+use crate::uart::Uart;
+
+pub const SYS_EXIT: usize = 1;
+pub const SYS_WRITE: usize = 2;
+pub const SYS_READ: usize = 3;
+pub const SYS_YIELD: usize = 4;
+pub const SYS_GETPID: usize = 5;
+pub const SYS_SLEEP: usize = 6;
+pub const SYS_SBRK: usize = 7;
+pub const SYS_MMAP: usize = 8;
+pub const SYS_MUNMAP: usize = 9;
+pub const SYS_WAITPID: usize = 10;
+pub const SYS_KILL: usize = 11;
+pub const SYS_SPAWN: usize = 12;
+pub const SYS_GETPPID: usize = 13;
+
+// File descriptors every process starts with, all of them the uart for now
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+// mmap protection bits
+pub const PROT_READ: usize = 0b1;
+pub const PROT_WRITE: usize = 0b1 << 1;
+pub const PROT_EXEC: usize = 0b1 << 2;
+
+// waitpid options: come back with 0 instead of waiting when no child has exited yet
+pub const WNOHANG: usize = 0b1;
+// waitpid's pid for any child, -1
+pub const WAIT_ANY: usize = usize::MAX;
+
+// The signal numbers a wait status reports for processes that didn't exit on their own
+pub const SIGILL: usize = 4;
+pub const SIGKILL: usize = 9;
+pub const SIGSEGV: usize = 11;
+
+// The values match Linux's errno numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Errno {
+    // EPERM, the process isn't allowed to do that to another one
+    PermissionDenied = 1,
+    // ENOENT, no program by that name
+    NoEntry = 2,
+    // ESRCH, no process with that pid
+    NoSuchProcess = 3,
+    // EINTR, the process got killed while the call waited
+    Interrupted = 4,
+    // EBADF, the file descriptor isn't open
+    BadFile = 9,
+    // ECHILD, nothing to wait for
+    NoChild = 10,
+    // EAGAIN, the process table is full
+    TryAgain = 11,
+    // ENOMEM
+    OutOfMemory = 12,
+    // EFAULT, a pointer into memory the process can't access
+    BadAddress = 14,
+    // EINVAL
+    InvalidArgument = 22,
+    // ENOSYS, no system call with that number
+    NotImplemented = 38,
+}
+
+impl From<MapError> for Errno {
+    fn from(error: MapError) -> Self {
+        match error {
+            MapError::OutOfMemory | MapError::TooManyRegions => Errno::OutOfMemory,
+            MapError::NotMapped | MapError::PermissionDenied | MapError::NotOwned => Errno::BadAddress,
+            _ => Errno::InvalidArgument,
+        }
+    }
+}
+
+// What goes back in a0
+pub fn encode(result: Result<usize, Errno>) -> usize {
+    match result {
+        Ok(value) => value,
+        Err(errno) => (errno as usize).wrapping_neg(),
+    }
+}
+
+// Run system call number for the process. Exit only marks the process, Process::run does the
+// rest once the call returns
+pub fn dispatch(process: &mut Process, number: usize, args: [usize; 6]) -> Result<usize, Errno> {
+    match number {
+        SYS_EXIT => {
+            process.exit_code = Some(args[0]);
+            Ok(0)
+        }
+        SYS_WRITE => write(process, args[0], args[1], args[2]),
+        SYS_READ => read(process, args[0], args[1], args[2]),
+        SYS_YIELD => {
+            thread::yield_now();
+            Ok(0)
+        }
+        SYS_GETPID => Ok(process.pid()),
+        SYS_SLEEP => sleep(process, args[0]),
+        SYS_SBRK => sbrk(process, args[0] as isize),
+        SYS_MMAP => mmap(process, args[0], args[1], args[2]),
+        SYS_MUNMAP => munmap(process, args[0], args[1]),
+        SYS_WAITPID => waitpid(process, args[0], args[1], args[2]),
+        SYS_KILL => kill(process, args[0]),
+        SYS_SPAWN => spawn(process, args[0], args[1]),
+        SYS_GETPPID => Ok(process::parent_of(process.pid()).unwrap_or(process::KERNEL_PID)),
+        _ => Err(Errno::NotImplemented),
+    }
+}
+
+// length rounded up to whole pages, None if that doesn't fit in a usize
+fn page_size_of(length: usize) -> Option<usize> {
+    Some(length.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1))
+}
+
+// How much gets copied in or out of a process at a time
+const CHUNK_SIZE: usize = 128;
+
+// write(fd, buffer, length) -> bytes written
+fn write(process: &mut Process, fd: usize, buffer: usize, length: usize) -> Result<usize, Errno> {
+    if fd != STDOUT && fd != STDERR {
+        return Err(Errno::BadFile);
+    }
+    // the same uart print! goes to
+    let mut uart = Uart::new(0xD4017000);
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut written = 0;
+    while written < length {
+        let piece = (length - written).min(CHUNK_SIZE);
+        if let Err(error) = process.copy_from_user(buffer.wrapping_add(written), &mut chunk[..piece]) {
+            // whatever made it out before the bad page still counts
+            return if written == 0 { Err(error.into()) } else { Ok(written) };
+        }
+        for byte in &chunk[..piece] {
+            uart.put(*byte);
+        }
+        written += piece;
+    }
+    Ok(written)
+}
+
+// read(fd, buffer, length) -> bytes read. Waits for at least one byte, then takes whatever else
+// has already come in. A process only gets input while it's in the foreground (see console.rs),
+// in the background it waits until the shell hands it over
+fn read(process: &mut Process, fd: usize, buffer: usize, length: usize) -> Result<usize, Errno> {
+    if fd != STDIN {
+        return Err(Errno::BadFile);
+    }
+    if length == 0 {
+        return Ok(0);
+    }
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let piece = length.min(CHUNK_SIZE);
+    let mut count = 0;
+    while count == 0 {
+        while count < piece && let Some(byte) = console::get(process.pid()) {
+            chunk[count] = byte;
+            count += 1;
+        }
+        if count == 0 {
+            if process::is_killed(process.pid()) {
+                return Err(Errno::Interrupted);
+            }
+            // nothing tells us when a byte comes in, so look again next tick
+            thread::sleep_until(cpu::time().saturating_add(timer::tick_interval()));
+        }
+    }
+    process.copy_to_user(buffer, &chunk[..count])?;
+    Ok(count)
+}
+
+// sbrk(increment) -> where the heap ended before. The pages come straight away, a negative
+// increment gives them back
+fn sbrk(process: &mut Process, increment: isize) -> Result<usize, Errno> {
+    let old_end = process.heap_end;
+    let new_end = old_end.checked_add_signed(increment).ok_or(Errno::InvalidArgument)?;
+    if new_end < HEAP_START {
+        return Err(Errno::InvalidArgument);
+    }
+    if new_end > MMAP_START {
+        return Err(Errno::OutOfMemory);
+    }
+    let old_top = page::align_value(old_end, 12);
+    let new_top = page::align_value(new_end, 12);
+    let read_write = PageTableEntryBits::UserReadWrite.as_i64();
+    for address in (old_top..new_top).step_by(PAGE_SIZE) {
+        if let Err(error) = process.space().allocate(address, read_write) {
+            for mapped in (old_top..address).step_by(PAGE_SIZE) {
+                let _ = process.space().unmap(mapped);
+            }
+            return Err(error.into());
+        }
+    }
+    for address in (new_top..old_top).step_by(PAGE_SIZE) {
+        let _ = process.space().unmap(address);
+    }
+    process.heap_end = new_end;
+    Ok(old_end)
+}
+
+// The first spot at or above from that has size bytes clear of every region, None once that runs
+// past MMAP_END
+fn free_gap(process: &mut Process, from: usize, size: usize) -> Option<usize> {
+    let mut start = from;
+    loop {
+        let end = start.checked_add(size).filter(|&end| end <= MMAP_END)?;
+        // skip past everything in the way and look again from there
+        match process.space().regions().filter(|region| region.start < end && region.end > start).map(|region| region.end).max() {
+            Some(next) => start = next,
+            None => return Some(start),
+        }
+    }
+}
+
+// mmap(address, length, prot) -> address. Anonymous memory only, zeroed and backed a page at a
+// time on first touch. address 0 lets the kernel pick the first free spot from mmap_next up,
+// anything else has to be a free, page aligned spot between MMAP_START and MMAP_END
+fn mmap(process: &mut Process, address: usize, length: usize, prot: usize) -> Result<usize, Errno> {
+    if length == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 || prot & PROT_READ == 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    let size = page_size_of(length).ok_or(Errno::InvalidArgument)?;
+    let start = if address == 0 {
+        free_gap(process, process.mmap_next, size).ok_or(Errno::OutOfMemory)?
+    } else {
+        address
+    };
+    let end = start.checked_add(size).ok_or(Errno::InvalidArgument)?;
+    if !start.is_multiple_of(PAGE_SIZE) || start < MMAP_START || end > MMAP_END {
+        return Err(Errno::InvalidArgument);
+    }
+    let mut bits = PageTableEntryBits::User.as_i64() | PageTableEntryBits::Read.as_i64();
+    if prot & PROT_WRITE != 0 {
+        bits |= PageTableEntryBits::Write.as_i64();
+    }
+    if prot & PROT_EXEC != 0 {
+        bits |= PageTableEntryBits::Execute.as_i64();
+    }
+    process.space().reserve(start, size, bits)?;
+    // the next mmap(0) starts above anything handed out so far, hinted or not
+    process.mmap_next = process.mmap_next.max(end);
+    Ok(start)
+}
+
+// munmap(address, length). Only whole mappings, exactly as mmap handed them out
+fn munmap(process: &mut Process, address: usize, length: usize) -> Result<usize, Errno> {
+    let size = page_size_of(length).ok_or(Errno::InvalidArgument)?;
+    let mapped = process.space().regions()
+        .any(|region| region.start == address && region.end - region.start == size && address >= MMAP_START);
+    if !mapped {
+        return Err(Errno::InvalidArgument);
+    }
+    process.space().unreserve(address)?;
+    Ok(0)
+}
+
+// sleep(milliseconds), cut short by a kill (process::kill wakes the thread)
+fn sleep(process: &mut Process, milliseconds: usize) -> Result<usize, Errno> {
+    let deadline = cpu::time().saturating_add(milliseconds.saturating_mul(cpu::timebase_frequency() / 1000));
+    while cpu::time() < deadline {
+        if process::is_killed(process.pid()) {
+            return Err(Errno::Interrupted);
+        }
+        thread::sleep_until(deadline);
+    }
+    Ok(0)
+}
+
+// The status word waitpid stores, laid out the way Linux does it: the exit code in bits 8-15, or
+// the signal that would have ended it in the low bits
+pub fn wait_status(exit: Exit) -> usize {
+    match exit {
+        Exit::Exited(code) => (code & 0xff) << 8,
+        Exit::Killed => SIGKILL,
+        Exit::Fault { cause: trap::ILLEGAL_INSTRUCTION, .. } => SIGILL,
+        Exit::Fault { .. } => SIGSEGV,
+    }
+}
+
+// waitpid(pid, status, options) -> the pid collected. pid WAIT_ANY takes any child, status is
+// where the wait status goes (0 for nowhere), WNOHANG returns 0 straight away if nothing's ready
+fn waitpid(process: &mut Process, pid: usize, status: usize, options: usize) -> Result<usize, Errno> {
+    if options & !WNOHANG != 0 || pid == process::KERNEL_PID {
+        return Err(Errno::InvalidArgument);
+    }
+    let pid = if pid == WAIT_ANY { None } else { Some(pid) };
+    // the status has to be somewhere it can go before any child gets reaped
+    if status != 0 {
+        process.copy_to_user(status, &0u32.to_le_bytes())?;
+    }
+    loop {
+        let seen = process::exit_count();
+        if let Some(child) = process::try_wait(process.pid(), pid)? {
+            if status != 0 {
+                process.copy_to_user(status, &(wait_status(child.exit) as u32).to_le_bytes())?;
+            }
+            return Ok(child.pid);
+        }
+        if options & WNOHANG != 0 {
+            return Ok(0);
+        }
+        if process::is_killed(process.pid()) {
+            return Err(Errno::Interrupted);
+        }
+        process::wait_for_exit(seen);
+    }
+}
+
+// kill(pid). Only the process itself and its own children, anyone else's is EPERM
+fn kill(process: &mut Process, pid: usize) -> Result<usize, Errno> {
+    let parent = process::parent_of(pid).ok_or(Errno::NoSuchProcess)?;
+    if pid != process.pid() && parent != process.pid() {
+        return Err(Errno::PermissionDenied);
+    }
+    process::kill(pid).map(|_| 0)
+}
+
+// The longest program name spawn looks up
+const NAME_MAX: usize = 32;
+
+// spawn(name, length) -> the child's pid. Starts one of the programs built into the kernel as a
+// child, with just its name for argv
+fn spawn(process: &mut Process, name: usize, length: usize) -> Result<usize, Errno> {
+    if length > NAME_MAX {
+        return Err(Errno::NoEntry);
+    }
+    let mut buffer = [0u8; NAME_MAX];
+    process.copy_from_user(name, &mut buffer[..length])?;
+    let name = core::str::from_utf8(&buffer[..length]).map_err(|_| Errno::NoEntry)?;
+    let program = elf::find_program(name).ok_or(Errno::NoEntry)?;
+    let child = elf::exec(program.image, &[program.name], &[]).map_err(|error| match error {
+        elf::ElfError::Map(error) => error.into(),
+        _ => Errno::InvalidArgument,
+    })?;
+    process::spawn(child, program.name, process.pid())
+}
@@ -0,0 +1,917 @@
+//! Kernel threads and the scheduler.
+//! Every thread gets its own kernel stack and trap stack (guard pages included) and a Context
+//! holding the callee saved registers it had when it last switched away, switch_context
+//! (asm/switch.S) swaps one Context for another. Whatever a hart was running when it called init
+//! (the shell loop on hart 0) becomes that hart's boot thread and takes its turn like everything
+//! else.
+//! Scheduling is round robin within a priority, higher priorities first. Each hart has its own run
+//! queue: threads stay where they last ran unless a hart with nothing to do steals them, or a
+//! timer tick finds its hart a lot less busy than another. The timer (see timer.rs) preempts
+//! whatever is running every tick, so a thread that never yields can't starve the shell. A hart
+//! with nothing at all to run switches to its idle thread, which sleeps in wfi.
+//! Sleeping threads sit on a list of their own, and whichever hart schedules next after their
+//! time is up puts them back on a run queue
+use alloc::boxed::Box;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use crate::cpu::{self, MAX_HARTS};
+use crate::malloc;
+use crate::sync::{SpinLock, SCHEDULER_CLASS};
+use crate::stack::{self, GuardedStack, KernelStack, StackError, StackOwner};
+use crate::{print, println};
+
+unsafe extern "C" {
+    fn switch_context(save: *mut Context, load: *const Context);
+}
+
+pub type ThreadId = usize;
+
+// The registers a thread keeps across switch_context, in the order switch.S stores them
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Context {
+    pub ra: usize,
+    pub sp: usize,
+    pub s: [usize; 12],
+    // the thread's trap stack top, or 0 if it was switched away from inside a trap
+    pub sscratch: usize,
+}
+
+impl Context {
+    const fn empty() -> Self {
+        Context { ra: 0, sp: 0, s: [0; 12], sscratch: 0 }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    // waiting on a run queue
+    Ready,
+    Running,
+    // waiting in join for another thread to exit, on a WaitQueue, or asleep
+    Blocked,
+    // done, waiting for a join to pick up the exit code
+    Exited,
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Ready => "ready",
+            State::Running => "running",
+            State::Blocked => "blocked",
+            State::Exited => "exited",
+        }
+    }
+}
+
+// A ready thread only runs when nothing of a higher priority is ready on its hart
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+const NUM_PRIORITIES: usize = 3;
+
+impl Priority {
+    fn name(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SpawnError {
+    // no pages for the stacks or no heap for the thread
+    OutOfMemory,
+    // every guard page slot is taken, see stack::MAX_GUARDS
+    TooManyThreads,
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: State,
+    priority: Priority,
+    // the hart it's running on, or whose run queue it's waiting on
+    hart: usize,
+    // boot and idle threads belong to their hart and never get moved to another
+    pinned: bool,
+    context: Context,
+    // None for boot and idle threads, they have stacks from somewhere else
+    stack: Option<KernelStack>,
+    trap_stack: Option<KernelStack>,
+    // what the thread runs, taken out when it starts
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    exit_code: usize,
+    // nobody is going to join it, so it gets freed as soon as it exits
+    detached: bool,
+    // the thread blocked in join waiting for this one
+    joiner: *mut Thread,
+    // time spent running, in time CSR ticks, and when it last got switched to
+    cpu_time: usize,
+    switched_in: usize,
+    // when a sleeping thread is due to wake up, in time CSR ticks
+    wake_at: usize,
+    // a wake that came while the thread wasn't asleep, its next sleep ends straight away
+    woken: bool,
+    // next on the run queue or a wait queue, or on the dead list once a detached thread exits
+    next_ready: *mut Thread,
+    // next in the list of every thread
+    next: *mut Thread,
+    // the ranked locks the thread holds, for the lock order checks in sync.rs
+    #[cfg(feature = "debug-locks")]
+    held_locks: crate::sync::HeldLocks,
+}
+
+impl Thread {
+    const fn new(id: ThreadId, name: &'static str) -> Self {
+        Thread {
+            id,
+            name,
+            state: State::Ready,
+            priority: Priority::Normal,
+            hart: 0,
+            pinned: false,
+            context: Context::empty(),
+            stack: None,
+            trap_stack: None,
+            entry: None,
+            exit_code: 0,
+            detached: false,
+            joiner: null_mut(),
+            cpu_time: 0,
+            switched_in: 0,
+            wake_at: 0,
+            woken: false,
+            next_ready: null_mut(),
+            next: null_mut(),
+            #[cfg(feature = "debug-locks")]
+            held_locks: crate::sync::HeldLocks::new(),
+        }
+    }
+}
+
+// A FIFO of ready (or blocked, on a WaitQueue or asleep) threads strung through next_ready
+#[derive(Clone, Copy)]
+struct Queue {
+    head: *mut Thread,
+    tail: *mut Thread,
+}
+
+impl Queue {
+    const fn empty() -> Self {
+        Queue { head: null_mut(), tail: null_mut() }
+    }
+
+    unsafe fn push(&mut self, thread: *mut Thread) {
+        unsafe {
+            (*thread).next_ready = null_mut();
+            if self.tail.is_null() {
+                self.head = thread;
+            } else {
+                (*self.tail).next_ready = thread;
+            }
+            self.tail = thread;
+        }
+    }
+
+    // Take out the first thread that fits, null if none does
+    unsafe fn take(&mut self, fits: impl Fn(*mut Thread) -> bool) -> *mut Thread {
+        unsafe {
+            let mut previous: *mut Thread = null_mut();
+            let mut thread = self.head;
+            while !thread.is_null() && !fits(thread) {
+                previous = thread;
+                thread = (*thread).next_ready;
+            }
+            if thread.is_null() {
+                return thread;
+            }
+            if previous.is_null() {
+                self.head = (*thread).next_ready;
+            } else {
+                (*previous).next_ready = (*thread).next_ready;
+            }
+            if self.tail == thread {
+                self.tail = previous;
+            }
+            (*thread).next_ready = null_mut();
+            thread
+        }
+    }
+}
+
+struct RunQueue {
+    // one queue per priority
+    levels: [Queue; NUM_PRIORITIES],
+    // ready threads over all the levels
+    length: usize,
+    // whether the hart runs threads at all (it called init and hasn't stopped)
+    online: bool,
+    // timer ticks seen, for deciding when to balance
+    ticks: usize,
+}
+
+impl RunQueue {
+    const fn empty() -> Self {
+        RunQueue { levels: [Queue::empty(); NUM_PRIORITIES], length: 0, online: false, ticks: 0 }
+    }
+
+    unsafe fn push(&mut self, thread: *mut Thread) {
+        unsafe { self.levels[(*thread).priority as usize].push(thread) };
+        self.length += 1;
+    }
+
+    // The first thread that fits from the highest priority that has one
+    unsafe fn take(&mut self, fits: impl Fn(*mut Thread) -> bool) -> *mut Thread {
+        for level in self.levels.iter_mut().rev() {
+            let thread = unsafe { level.take(&fits) };
+            if !thread.is_null() {
+                self.length -= 1;
+                return thread;
+            }
+        }
+        null_mut()
+    }
+}
+
+// Everything below is only touched with the lock held. A thread switching away takes it with
+// lock_handoff, holds it across switch_context and the thread that runs next unlocks it
+static SCHEDULER_LOCK: SpinLock = SpinLock::ranked(SCHEDULER_CLASS);
+static mut RUN_QUEUES: [RunQueue; MAX_HARTS] = [const { RunQueue::empty() }; MAX_HARTS];
+static mut ALL_THREADS: *mut Thread = null_mut();
+// detached threads that exited, freed by whoever runs after them
+static mut DEAD: *mut Thread = null_mut();
+// threads in sleep_until, in no particular order
+static mut SLEEPING: Queue = Queue::empty();
+// Boot and idle threads don't live on the heap or in page::alloc'd stacks, those get reset by the
+// kernel tests. Boot thread ids are the hart's, idle ones come right after
+static mut BOOT_THREADS: [Thread; MAX_HARTS] = [const { Thread::new(0, "boot") }; MAX_HARTS];
+static mut IDLE_THREADS: [Thread; MAX_HARTS] = [const { Thread::new(0, "idle") }; MAX_HARTS];
+static NEXT_ID: AtomicUsize = AtomicUsize::new(2 * MAX_HARTS);
+// the thread each hart is running, null until the hart calls init
+static CURRENT: [AtomicPtr<Thread>; MAX_HARTS] = [const { AtomicPtr::new(null_mut()) }; MAX_HARTS];
+
+// The idle thread only ever runs wfi and the scheduler, so it gets by with small stacks in .bss
+const IDLE_STACK_SIZE: usize = 8 * 1024;
+
+static mut IDLE_STACKS: [GuardedStack<IDLE_STACK_SIZE>; MAX_HARTS] = [const { GuardedStack::new() }; MAX_HARTS];
+static mut IDLE_TRAP_STACKS: [GuardedStack<IDLE_STACK_SIZE>; MAX_HARTS] = [const { GuardedStack::new() }; MAX_HARTS];
+
+// How often (in ticks) a hart checks whether it should take work off a busier one
+const BALANCE_TICKS: usize = 10;
+
+// Turn whatever this hart is running into its boot thread, give the hart an idle thread and start
+// scheduling threads on it. Calling it again does nothing
+pub fn init() {
+    let hart = cpu::hart_id();
+    if !CURRENT[hart].load(Ordering::Relaxed).is_null() {
+        return;
+    }
+    // the lock gets let go before the hart has a current thread, so debug-locks finds it where it
+    // was recorded, and interrupts stay off until there is one
+    let interrupts = cpu::interrupts_off();
+    let boot = unsafe { core::ptr::addr_of_mut!(BOOT_THREADS[hart]) };
+    let owner = StackOwner { hart, task: MAX_HARTS + hart };
+    let idle_stack = stack::register_static(unsafe { core::ptr::addr_of_mut!(IDLE_STACKS[hart]) }, owner);
+    let idle_trap_stack = stack::register_static(unsafe { core::ptr::addr_of_mut!(IDLE_TRAP_STACKS[hart]) }, owner);
+    let guard = SCHEDULER_LOCK.lock();
+    unsafe {
+        *boot = Thread::new(hart, "boot");
+        (*boot).state = State::Running;
+        (*boot).hart = hart;
+        (*boot).pinned = true;
+        (*boot).switched_in = cpu::time();
+        link(boot);
+        let idle = core::ptr::addr_of_mut!(IDLE_THREADS[hart]);
+        *idle = Thread::new(MAX_HARTS + hart, "idle");
+        (*idle).priority = Priority::Low;
+        (*idle).hart = hart;
+        (*idle).pinned = true;
+        (*idle).context.ra = idle_start as *const () as usize;
+        (*idle).context.sp = idle_stack;
+        (*idle).context.sscratch = idle_trap_stack;
+        link(idle);
+        (*core::ptr::addr_of_mut!(RUN_QUEUES[hart])).online = true;
+    }
+    drop(guard);
+    CURRENT[hart].store(boot, Ordering::Relaxed);
+    cpu::restore_interrupts(interrupts);
+}
+
+// Take this hart out of scheduling before it gets parked. Only its boot thread can call this, and
+// anything waiting on its run queue moves to another hart. init brings it back
+pub fn stop() {
+    let hart = cpu::hart_id();
+    let current = CURRENT[hart].load(Ordering::Relaxed);
+    if current.is_null() {
+        return;
+    }
+    // same as init, the lock is let go while the boot thread is still current
+    let interrupts = cpu::interrupts_off();
+    let guard = SCHEDULER_LOCK.lock();
+    unsafe {
+        assert!(current == core::ptr::addr_of_mut!(BOOT_THREADS[hart]), "only a hart's boot thread can stop it");
+        let queue = &mut *core::ptr::addr_of_mut!(RUN_QUEUES[hart]);
+        queue.online = false;
+        loop {
+            let thread = queue.take(|_| true);
+            if thread.is_null() {
+                break;
+            }
+            enqueue(thread);
+        }
+        unlink(current);
+        unlink(core::ptr::addr_of_mut!(IDLE_THREADS[hart]));
+    }
+    drop(guard);
+    CURRENT[hart].store(null_mut(), Ordering::Relaxed);
+    cpu::restore_interrupts(interrupts);
+}
+
+fn current_thread() -> *mut Thread {
+    CURRENT[cpu::hart_id()].load(Ordering::Relaxed)
+}
+
+// The running thread's id, None before init
+pub fn current() -> Option<ThreadId> {
+    let thread = current_thread();
+    if thread.is_null() {
+        return None;
+    }
+    Some(unsafe { (*thread).id })
+}
+
+// Start a thread running f. It goes on the least busy hart's run queue
+pub fn spawn(f: impl FnOnce() + Send + 'static) -> Result<JoinHandle, SpawnError> {
+    spawn_named("thread", f)
+}
+
+pub fn spawn_named(name: &'static str, f: impl FnOnce() + Send + 'static) -> Result<JoinHandle, SpawnError> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let owner = StackOwner { hart: cpu::hart_id(), task: id };
+    let stack_error = |error| match error {
+        StackError::OutOfMemory => SpawnError::OutOfMemory,
+        StackError::TooManyGuards => SpawnError::TooManyThreads,
+    };
+    let stack = KernelStack::new(stack::KERNEL_STACK_PAGES, owner).map_err(stack_error)?;
+    let trap_stack = KernelStack::new(stack::KERNEL_STACK_PAGES, owner).map_err(stack_error)?;
+    let entry: Box<dyn FnOnce() + Send> = malloc::try_box(f).map_err(|_| SpawnError::OutOfMemory)?;
+    let mut thread = Thread::new(id, name);
+    // the first switch to the thread returns into thread_start on the new stack
+    thread.context.ra = thread_start as *const () as usize;
+    thread.context.sp = stack.top();
+    thread.context.sscratch = trap_stack.top();
+    thread.stack = Some(stack);
+    thread.trap_stack = Some(trap_stack);
+    thread.entry = Some(entry);
+    let thread = Box::into_raw(malloc::try_box(thread).map_err(|_| SpawnError::OutOfMemory)?);
+    let _guard = SCHEDULER_LOCK.lock();
+    unsafe {
+        (*thread).hart = least_loaded_hart(cpu::hart_id());
+        link(thread);
+        enqueue(thread);
+    }
+    Ok(JoinHandle { thread, id })
+}
+
+// Let the next ready thread run, this one goes to the back of its priority's queue. Returns
+// straight away when nothing of the same or a higher priority is ready
+pub fn yield_now() {
+    if current_thread().is_null() {
+        return;
+    }
+    let interrupts = cpu::interrupts_off();
+    SCHEDULER_LOCK.lock_handoff();
+    unsafe {
+        let current = current_thread();
+        (*current).state = State::Ready;
+        if !is_idle(current) {
+            enqueue(current);
+        }
+        schedule(current);
+        SCHEDULER_LOCK.force_unlock();
+    }
+    cpu::restore_interrupts(interrupts);
+}
+
+// Park the running thread until the time CSR reaches deadline, or until wake ends it early. It
+// only gets looked at when a hart schedules, so it can oversleep by up to a tick. A hart that
+// doesn't schedule threads has nothing to park and spins instead
+pub fn sleep_until(deadline: usize) {
+    let current = current_thread();
+    if current.is_null() {
+        while cpu::time() < deadline {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+    let interrupts = cpu::interrupts_off();
+    SCHEDULER_LOCK.lock_handoff();
+    unsafe {
+        if !core::mem::take(&mut (*current).woken) && cpu::time() < deadline {
+            (*current).state = State::Blocked;
+            (*current).wake_at = deadline;
+            (*core::ptr::addr_of_mut!(SLEEPING)).push(current);
+            schedule(current);
+        }
+        SCHEDULER_LOCK.force_unlock();
+    }
+    cpu::restore_interrupts(interrupts);
+}
+
+// Give the hart to other threads for at least that long
+pub fn sleep_ms(milliseconds: usize) {
+    sleep_until(cpu::time().saturating_add(milliseconds.saturating_mul(cpu::timebase_frequency() / 1000)));
+}
+
+// Cut the thread's sleep short. If it isn't asleep, its next sleep ends straight away instead, so
+// a wake can't get lost between the sleeper deciding to sleep and actually going to sleep. False
+// if there's no such thread
+pub fn wake(id: ThreadId) -> bool {
+    let _guard = SCHEDULER_LOCK.lock();
+    unsafe {
+        let thread = (*core::ptr::addr_of_mut!(SLEEPING)).take(|thread| (*thread).id == id);
+        if !thread.is_null() {
+            (*thread).state = State::Ready;
+            enqueue(thread);
+            return true;
+        }
+        let mut thread = ALL_THREADS;
+        while !thread.is_null() {
+            if (*thread).id == id {
+                (*thread).woken = true;
+                return true;
+            }
+            thread = (*thread).next;
+        }
+    }
+    false
+}
+
+// Called on every timer interrupt: now and then pull work over from a busier hart, then preempt
+// whatever is running
+pub fn tick() {
+    let hart = cpu::hart_id();
+    if CURRENT[hart].load(Ordering::Relaxed).is_null() {
+        return;
+    }
+    {
+        let _guard = SCHEDULER_LOCK.lock();
+        unsafe {
+            let queue = &mut *core::ptr::addr_of_mut!(RUN_QUEUES[hart]);
+            queue.ticks += 1;
+            if queue.ticks.is_multiple_of(BALANCE_TICKS) {
+                balance(hart);
+            }
+        }
+    }
+    yield_now();
+}
+
+// Change the running thread's priority, it takes effect the next time it's queued
+pub fn set_priority(priority: Priority) {
+    let current = current_thread();
+    if !current.is_null() {
+        let _guard = SCHEDULER_LOCK.lock();
+        unsafe { (*current).priority = priority };
+    }
+}
+
+// Stop the running thread, handing code to whoever joins it
+pub fn exit(code: usize) -> ! {
+    let current = current_thread();
+    assert!(!current.is_null(), "exit outside of a thread");
+    assert!(unsafe { !(*current).pinned }, "a hart's boot or idle thread can't exit");
+    cpu::interrupts_off();
+    SCHEDULER_LOCK.lock_handoff();
+    unsafe {
+        (*current).exit_code = code;
+        (*current).state = State::Exited;
+        let joiner = (*current).joiner;
+        if !joiner.is_null() {
+            (*joiner).state = State::Ready;
+            enqueue(joiner);
+        }
+        if (*current).detached {
+            (*current).next_ready = DEAD;
+            DEAD = current;
+        }
+        schedule(current);
+    }
+    unreachable!("exited thread got switched back to")
+}
+
+// Where every new thread starts, switch_context returns here on its fresh stack
+#[unsafe(no_mangle)]
+extern "C" fn thread_start() -> ! {
+    let entry = unsafe {
+        // the thread that switched to us left the lock held
+        reap_dead();
+        (*current_thread()).entry.take()
+    };
+    unsafe { SCHEDULER_LOCK.force_unlock() };
+    cpu::restore_interrupts(true);
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit(0)
+}
+
+// Where a hart's idle thread starts. It runs whenever nothing else is ready on the hart,
+// the timer (or an interrupt from a device) wakes it up to check again
+#[unsafe(no_mangle)]
+extern "C" fn idle_start() -> ! {
+    unsafe {
+        reap_dead();
+        SCHEDULER_LOCK.force_unlock();
+    }
+    cpu::restore_interrupts(true);
+    loop {
+        yield_now();
+        cpu::wait_for_interrupt();
+    }
+}
+
+// Lock held, current isn't on a run queue. Switch to whatever should run next on this hart: the
+// highest priority ready thread here, else one stolen from a busier hart, else the idle thread.
+// Comes back once something switches to current again (maybe on another hart)
+unsafe fn schedule(current: *mut Thread) {
+    let hart = cpu::hart_id();
+    unsafe {
+        let now = cpu::time();
+        wake_sleepers(now);
+        let queue = &mut *core::ptr::addr_of_mut!(RUN_QUEUES[hart]);
+        let mut next = queue.take(|_| true);
+        if next.is_null() {
+            next = steal(hart);
+        }
+        if next.is_null() {
+            next = core::ptr::addr_of_mut!(IDLE_THREADS[hart]);
+        }
+        (*current).cpu_time += now - (*current).switched_in;
+        (*next).switched_in = now;
+        (*next).state = State::Running;
+        (*next).hart = hart;
+        if next == current {
+            return;
+        }
+        CURRENT[hart].store(next, Ordering::Relaxed);
+        // running into a guard page doesn't fault (see stack.rs), so look before leaving the stack
+        stack::check_current();
+        switch_context(&mut (*current).context, &(*next).context);
+        reap_dead();
+    }
+}
+
+// Lock held. Back on their run queues with the sleepers whose time is up
+unsafe fn wake_sleepers(now: usize) {
+    unsafe {
+        loop {
+            let thread = (*core::ptr::addr_of_mut!(SLEEPING)).take(|thread| (*thread).wake_at <= now);
+            if thread.is_null() {
+                return;
+            }
+            (*thread).state = State::Ready;
+            enqueue(thread);
+        }
+    }
+}
+
+fn is_idle(thread: *mut Thread) -> bool {
+    let idle = core::ptr::addr_of_mut!(IDLE_THREADS) as *mut Thread;
+    thread >= idle && thread < idle.wrapping_add(MAX_HARTS)
+}
+
+// Lock held. Back on the queue of the hart it last ran on, or another one if that hart stopped
+unsafe fn enqueue(thread: *mut Thread) {
+    unsafe {
+        if !(*core::ptr::addr_of!(RUN_QUEUES[(*thread).hart])).online && !(*thread).pinned {
+            (*thread).hart = least_loaded_hart((*thread).hart);
+        }
+        (*core::ptr::addr_of_mut!(RUN_QUEUES[(*thread).hart])).push(thread);
+    }
+}
+
+// Lock held. The online hart with the shortest run queue, preferring the given one on a tie
+unsafe fn least_loaded_hart(preferred: usize) -> usize {
+    let queues = unsafe { &*core::ptr::addr_of!(RUN_QUEUES) };
+    let mut best = preferred;
+    for (hart, queue) in queues.iter().enumerate() {
+        if queue.online && (!queues[best].online || queue.length < queues[best].length) {
+            best = hart;
+        }
+    }
+    best
+}
+
+// Lock held. The hart (other than this one) with the most threads that could move, if any
+unsafe fn busiest_hart(hart: usize) -> Option<usize> {
+    let queues = unsafe { &*core::ptr::addr_of!(RUN_QUEUES) };
+    (0..MAX_HARTS)
+        .filter(|other| *other != hart && queues[*other].length > 0)
+        .max_by_key(|other| queues[*other].length)
+}
+
+// Lock held. Take a thread that isn't pinned off the busiest other hart, null if there's none
+unsafe fn steal(hart: usize) -> *mut Thread {
+    unsafe {
+        let Some(busiest) = busiest_hart(hart) else {
+            return null_mut();
+        };
+        let thread = (*core::ptr::addr_of_mut!(RUN_QUEUES[busiest])).take(|thread| !(*thread).pinned);
+        if !thread.is_null() {
+            (*thread).hart = hart;
+        }
+        thread
+    }
+}
+
+// Lock held. Move a thread over from the busiest hart if it has at least two more waiting
+unsafe fn balance(hart: usize) {
+    unsafe {
+        let Some(busiest) = busiest_hart(hart) else {
+            return;
+        };
+        let queues = &*core::ptr::addr_of!(RUN_QUEUES);
+        if queues[busiest].length >= queues[hart].length + 2 {
+            let thread = steal(hart);
+            if !thread.is_null() {
+                enqueue(thread);
+            }
+        }
+    }
+}
+
+unsafe fn link(thread: *mut Thread) {
+    unsafe {
+        (*thread).next = ALL_THREADS;
+        ALL_THREADS = thread;
+    }
+}
+
+unsafe fn unlink(thread: *mut Thread) {
+    unsafe {
+        let mut link = core::ptr::addr_of_mut!(ALL_THREADS);
+        while !(*link).is_null() {
+            if *link == thread {
+                *link = (*thread).next;
+                break;
+            }
+            link = core::ptr::addr_of_mut!((**link).next);
+        }
+    }
+}
+
+// Lock held. Take an exited thread out of the list and free it along with its stacks
+unsafe fn reap(thread: *mut Thread) {
+    unsafe {
+        unlink(thread);
+        drop(Box::from_raw(thread));
+    }
+}
+
+// Lock held. Free the detached threads that exited, they've all switched away by now
+unsafe fn reap_dead() {
+    unsafe {
+        while !DEAD.is_null() {
+            let thread = DEAD;
+            DEAD = (*thread).next_ready;
+            reap(thread);
+        }
+    }
+}
+
+// Threads blocked until something wakes them, what the sleeping locks in sync.rs are built on.
+// The queue itself is guarded by the scheduler lock
+pub struct WaitQueue {
+    queue: core::cell::UnsafeCell<Queue>,
+}
+
+unsafe impl Send for WaitQueue {}
+unsafe impl Sync for WaitQueue {}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { queue: core::cell::UnsafeCell::new(Queue::empty()) }
+    }
+
+    // Block the running thread until it's woken, but only if condition still holds once the
+    // scheduler is locked. Whoever makes condition false and then wakes the queue can't slip in
+    // between the check and the block, so no wakeup gets lost. condition runs with the scheduler
+    // locked and interrupts off, so it can't allocate or touch threads. Callers check their
+    // condition again afterwards: a thread can get woken for a change somebody else got to first.
+    // A hart that doesn't schedule threads has nothing to block and just comes back
+    pub fn wait_if(&self, condition: impl FnOnce() -> bool) {
+        let current = current_thread();
+        if current.is_null() {
+            core::hint::spin_loop();
+            return;
+        }
+        let interrupts = cpu::interrupts_off();
+        SCHEDULER_LOCK.lock_handoff();
+        unsafe {
+            if condition() {
+                (*current).state = State::Blocked;
+                (*self.queue.get()).push(current);
+                schedule(current);
+            }
+            SCHEDULER_LOCK.force_unlock();
+        }
+        cpu::restore_interrupts(interrupts);
+    }
+
+    // Wake the thread that has waited longest, false if nobody was waiting
+    pub fn wake_one(&self) -> bool {
+        let _guard = SCHEDULER_LOCK.lock();
+        unsafe {
+            let thread = (*self.queue.get()).take(|_| true);
+            if thread.is_null() {
+                return false;
+            }
+            (*thread).state = State::Ready;
+            enqueue(thread);
+        }
+        true
+    }
+
+    // Wake everybody, returns how many that was
+    pub fn wake_all(&self) -> usize {
+        let _guard = SCHEDULER_LOCK.lock();
+        let mut woken = 0;
+        unsafe {
+            loop {
+                let thread = (*self.queue.get()).take(|_| true);
+                if thread.is_null() {
+                    return woken;
+                }
+                (*thread).state = State::Ready;
+                enqueue(thread);
+                woken += 1;
+            }
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The running thread's ranked locks, None outside of a thread. Only the thread itself ever looks
+// at them, so there's no locking
+#[cfg(feature = "debug-locks")]
+pub(crate) fn held_locks() -> Option<*mut crate::sync::HeldLocks> {
+    let current = current_thread();
+    (!current.is_null()).then(|| unsafe { core::ptr::addr_of_mut!((*current).held_locks) })
+}
+
+// Owns a spawned thread. Dropping it without joining detaches the thread
+pub struct JoinHandle {
+    thread: *mut Thread,
+    id: ThreadId,
+}
+
+unsafe impl Send for JoinHandle {}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        let _guard = SCHEDULER_LOCK.lock();
+        unsafe { (*self.thread).state == State::Exited }
+    }
+
+    // A waiting thread moves to its new priority's queue straight away
+    pub fn set_priority(&self, priority: Priority) {
+        let _guard = SCHEDULER_LOCK.lock();
+        unsafe {
+            let thread = self.thread;
+            let queue = &mut *core::ptr::addr_of_mut!(RUN_QUEUES[(*thread).hart]);
+            if (*thread).state == State::Ready && !queue.take(|other| other == thread).is_null() {
+                (*thread).priority = priority;
+                queue.push(thread);
+            } else {
+                (*thread).priority = priority;
+            }
+        }
+    }
+
+    // Wait for the thread to exit and give back its exit code
+    pub fn join(self) -> usize {
+        let thread = self.thread;
+        core::mem::forget(self);
+        let interrupts = cpu::interrupts_off();
+        SCHEDULER_LOCK.lock_handoff();
+        unsafe {
+            let current = current_thread();
+            while (*thread).state != State::Exited {
+                if current.is_null() {
+                    // this hart doesn't schedule threads, so there's nothing to block, just wait
+                    SCHEDULER_LOCK.force_unlock();
+                    core::hint::spin_loop();
+                    SCHEDULER_LOCK.lock_handoff();
+                    continue;
+                }
+                // exit puts us back on a run queue
+                (*thread).joiner = current;
+                (*current).state = State::Blocked;
+                schedule(current);
+            }
+            let code = (*thread).exit_code;
+            reap(thread);
+            SCHEDULER_LOCK.force_unlock();
+            cpu::restore_interrupts(interrupts);
+            code
+        }
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let _guard = SCHEDULER_LOCK.lock();
+        unsafe {
+            if (*self.thread).state == State::Exited {
+                reap(self.thread);
+            } else {
+                (*self.thread).detached = true;
+            }
+        }
+    }
+}
+
+// What for_each_thread hands out about each thread
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: State,
+    pub priority: Priority,
+    pub hart: usize,
+    // in time CSR ticks
+    pub cpu_time: usize,
+}
+
+// Every thread, boot and idle ones included. The scheduler is locked the whole time, so f can't
+// spawn, join or yield
+pub fn for_each_thread(mut f: impl FnMut(&ThreadInfo)) {
+    let _guard = SCHEDULER_LOCK.lock();
+    let now = cpu::time();
+    let mut thread = unsafe { ALL_THREADS };
+    while !thread.is_null() {
+        unsafe {
+            // the running ones haven't been charged for their current turn yet
+            let running = if (*thread).state == State::Running { now - (*thread).switched_in } else { 0 };
+            f(&ThreadInfo {
+                id: (*thread).id,
+                name: (*thread).name,
+                state: (*thread).state,
+                priority: (*thread).priority,
+                hart: (*thread).hart,
+                cpu_time: (*thread).cpu_time + running,
+            });
+            thread = (*thread).next;
+        }
+    }
+}
+
+// How many threads exist, boot and idle threads included
+pub fn thread_count() -> usize {
+    let mut count = 0;
+    for_each_thread(|_| count += 1);
+    count
+}
+
+// How many threads were spawned and haven't been reaped yet, leaving out every hart's boot and
+// idle threads
+pub fn spawned_thread_count() -> usize {
+    let mut count = 0;
+    for_each_thread(|thread| {
+        if thread.id >= 2 * MAX_HARTS {
+            count += 1;
+        }
+    });
+    count
+}
+
+// How many threads are waiting to run on the hart
+pub fn run_queue_length(hart: usize) -> usize {
+    let _guard = SCHEDULER_LOCK.lock();
+    unsafe { (*core::ptr::addr_of!(RUN_QUEUES[hart])).length }
+}
+
+pub fn print_threads() {
+    println!("{:>6} {:<16} {:<8} {:<8} {:>4} {:>10}", "id", "name", "state", "priority", "hart", "cpu ms");
+    for_each_thread(|thread| {
+        println!("{:>6} {:<16} {:<8} {:<8} {:>4} {:>10}", thread.id, thread.name, thread.state.name(),
+            thread.priority.name(), thread.hart, thread.cpu_time / (cpu::timebase_frequency() / 1000));
+    });
+}
@@ -0,0 +1,34 @@
+//! The supervisor timer.
+//! Each hart asks the SBI for an interrupt one tick from now, and every tick that comes in
+//! rearms the timer and preempts whatever thread was running (see thread::tick)
+use crate::cpu;
+
+// The SBI timer extension
+const SBI_EXT_TIME: usize = 0x54494d45;
+const SBI_TIME_SET_TIMER: usize = 0;
+// sie.STIE, supervisor timer interrupts
+const SIE_STIE: usize = 0b1 << 5;
+
+// The scheduler's time slice is one tick, 10ms
+pub const TICKS_PER_SECOND: usize = 100;
+
+// The tick in time CSR ticks
+pub fn tick_interval() -> usize {
+    cpu::timebase_frequency() / TICKS_PER_SECOND
+}
+
+// Start ticking on this hart
+pub fn init() {
+    set_next();
+    unsafe { core::arch::asm!("csrs sie, {}", in(reg) SIE_STIE) };
+}
+
+// Stop ticking on this hart, anything running keeps going until it yields
+pub fn stop() {
+    unsafe { core::arch::asm!("csrc sie, {}", in(reg) SIE_STIE) };
+}
+
+// Ask for the next tick. Setting the timer is also what clears the pending interrupt
+pub fn set_next() {
+    cpu::sbi_call(SBI_EXT_TIME, SBI_TIME_SET_TIMER, cpu::time() + tick_interval(), 0, 0);
+}
@@ -0,0 +1,205 @@
+//! TLB maintenance, ASIDs and satp switching.
+//! Every address space gets an ASID so switching satp doesn't throw the whole TLB away.
+//! ASIDs are handed out in generations: once a generation runs out of numbers the counter
+//! starts over, the generation goes up, and each hart flushes its whole TLB the first time it
+//! switches in the new generation. Numbers still loaded on another hart are skipped so a
+//! space that is running right now never has its ASID handed to someone else
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::cpu::{self, MAX_HARTS};
+use crate::sync::{SpinLock, ASID_CLASS};
+
+// satp MODE field value for Sv39
+const SATP_SV39: usize = 8 << 60;
+const SATP_PPN_MASK: usize = (0b1 << 44) - 1;
+const SATP_ASID_SHIFT: usize = 44;
+const SATP_ASID_MASK: usize = 0xffff;
+// an asid handed out by next_asid is generation << ASID_GENERATION_SHIFT | number
+const ASID_GENERATION_SHIFT: usize = 16;
+
+// How many ASID bits the hart implements (0 to 16), found by init
+static ASID_BITS: AtomicUsize = AtomicUsize::new(0);
+// Generation 1, number 1 next. number 0 stays with the kernel
+static ASID_GENERATION: AtomicUsize = AtomicUsize::new(1);
+static ASID_NEXT: AtomicUsize = AtomicUsize::new(1);
+// held while handing out an asid, so two spaces can't both get the same number
+static ASID_LOCK: SpinLock = SpinLock::ranked(ASID_CLASS);
+// The root table and asid each hart has loaded in satp (root 0 for bare), and the generation it
+// last flushed for
+static HART_ROOT: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+static HART_ASID: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+static HART_GENERATION: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+// Just enough of a table for init to turn Sv39 on with: the gigabyte the kernel runs in, mapped
+// onto itself
+#[repr(C, align(4096))]
+struct ProbeTable([usize; 512]);
+
+static mut PROBE_TABLE: ProbeTable = ProbeTable([0; 512]);
+
+// Find out how many ASID bits are implemented: the field is WARL, so write all ones and see which
+// ones stick, then put satp back the way it was. What the field does in bare mode is up to the
+// hart, so the probe goes through Sv39 on a real table
+pub fn init() {
+    // a gigapage leaf: read write execute, accessed and dirty, valid
+    let gigabyte = (init as *const () as usize) >> 30;
+    let table = unsafe { &mut *core::ptr::addr_of_mut!(PROBE_TABLE) };
+    table.0[gigabyte] = gigabyte << 28 | 0b11001111;
+    let probe = satp(table as *const ProbeTable as usize, SATP_ASID_MASK);
+    let interrupts = cpu::interrupts_off();
+    let probed: usize;
+    // one asm block, so nothing the compiler puts in between runs translated. A hart without
+    // Sv39 ignores the write and the probe reads back bare
+    unsafe {
+        asm!(
+            "csrr {original}, satp",
+            "csrw satp, {probe}",
+            "csrr {probed}, satp",
+            "csrw satp, {original}",
+            "sfence.vma zero, zero",
+            original = out(reg) _,
+            probe = in(reg) probe,
+            probed = out(reg) probed,
+        );
+    }
+    cpu::restore_interrupts(interrupts);
+    let implemented = if probed & SATP_PPN_MASK == probe & SATP_PPN_MASK {
+        (probed >> SATP_ASID_SHIFT) & SATP_ASID_MASK
+    } else {
+        0
+    };
+    ASID_BITS.store(implemented.count_ones() as usize, Ordering::Relaxed);
+}
+
+pub fn asid_bits() -> usize {
+    ASID_BITS.load(Ordering::Relaxed)
+}
+
+// Hand back the asid if it is still from the current generation, otherwise give out a new one.
+// 0 means the caller never had one
+pub fn refresh_asid(asid: usize) -> usize {
+    let _guard = ASID_LOCK.lock();
+    let mut generation = ASID_GENERATION.load(Ordering::Relaxed);
+    if asid != 0 && asid >> ASID_GENERATION_SHIFT == generation {
+        asid
+    } else {
+        let limit = 1 << asid_bits();
+        let mut number = ASID_NEXT.load(Ordering::Relaxed);
+        // with no ASID bits everyone shares number 0 and every switch flushes
+        if limit > 1 {
+            // one lap through the numbers at most
+            let mut tries = limit;
+            loop {
+                if number >= limit {
+                    generation += 1;
+                    ASID_GENERATION.store(generation, Ordering::Relaxed);
+                    number = 1;
+                }
+                if !loaded_on_any_hart(number) {
+                    break;
+                }
+                tries -= 1;
+                if tries == 0 {
+                    // every number is loaded on some hart. Take 1 anyway in a generation nobody
+                    // has switched to yet: each hart flushes everything before it runs anything
+                    // from the new generation, so the old owner's entries never get used for
+                    // this space
+                    generation += 1;
+                    ASID_GENERATION.store(generation, Ordering::Relaxed);
+                    number = 1;
+                    flush_all();
+                    break;
+                }
+                number += 1;
+            }
+            ASID_NEXT.store(number + 1, Ordering::Relaxed);
+        } else {
+            number = 0;
+        }
+        generation << ASID_GENERATION_SHIFT | number
+    }
+}
+
+fn loaded_on_any_hart(number: usize) -> bool {
+    HART_ASID.iter().any(|asid| asid.load(Ordering::Relaxed) & SATP_ASID_MASK == number)
+}
+
+// What satp holds for a Sv39 root table and asid
+pub fn satp(root_address: usize, asid: usize) -> usize {
+    SATP_SV39 | ((asid & SATP_ASID_MASK) << SATP_ASID_SHIFT) | (root_address >> 12)
+}
+
+// Install a root table and its asid in satp. The first switch a hart makes in a new
+// generation flushes everything, since numbers from the old generation may have been reused
+pub fn switch_to(root_address: usize, asid: usize) {
+    let flush = record(root_address, asid);
+    let satp = satp(root_address, asid);
+    unsafe { asm!("csrw satp, {}", in(reg) satp) };
+    if flush {
+        flush_all();
+    }
+}
+
+// Note the root and asid as this hart's, and whether loading them calls for a full flush
+fn record(root_address: usize, asid: usize) -> bool {
+    let hart = cpu::hart_id();
+    HART_ROOT[hart].store(root_address, Ordering::Relaxed);
+    HART_ASID[hart].store(asid, Ordering::Relaxed);
+    let generation = asid >> ASID_GENERATION_SHIFT;
+    // within a generation a number belongs to one space only, so its entries are still good
+    HART_GENERATION[hart].swap(generation, Ordering::Relaxed) != generation || asid_bits() == 0
+}
+
+// switch_to for the trampoline, which has to load satp itself since it keeps running across the
+// switch. Returns the flush it has to do, and what to hand leave once it's back
+pub fn enter(root_address: usize, asid: usize) -> (bool, Option<(usize, usize)>) {
+    let previous = active();
+    (record(root_address, asid), previous)
+}
+
+// The trampoline put the satp from before enter back. If that's from an older generation the
+// hart can't count as flushed for the new one anymore, it's been filling entries under an old number
+pub fn leave(previous: Option<(usize, usize)>) {
+    let hart = cpu::hart_id();
+    let (root_address, asid) = previous.unwrap_or((0, 0));
+    HART_ROOT[hart].store(root_address, Ordering::Relaxed);
+    HART_ASID[hart].store(asid, Ordering::Relaxed);
+    if previous.is_some() {
+        HART_GENERATION[hart].store(asid >> ASID_GENERATION_SHIFT, Ordering::Relaxed);
+    }
+}
+
+// Back to physical addressing, used when the active address space goes away
+pub fn switch_to_bare() {
+    HART_ROOT[cpu::hart_id()].store(0, Ordering::Relaxed);
+    HART_ASID[cpu::hart_id()].store(0, Ordering::Relaxed);
+    unsafe { asm!("csrw satp, zero") };
+    flush_all();
+}
+
+// The root table and asid this hart has loaded, None while it runs bare
+pub fn active() -> Option<(usize, usize)> {
+    let hart = cpu::hart_id();
+    let root = HART_ROOT[hart].load(Ordering::Relaxed);
+    (root != 0).then(|| (root, HART_ASID[hart].load(Ordering::Relaxed)))
+}
+
+// Flush the translation of one virtual address for one asid
+pub fn flush_address_asid(virtual_address: usize, asid: usize) {
+    unsafe { asm!("sfence.vma {}, {}", in(reg) virtual_address, in(reg) asid & SATP_ASID_MASK) };
+}
+
+// Flush the translation of one virtual address for every asid
+pub fn flush_address(virtual_address: usize) {
+    unsafe { asm!("sfence.vma {}, zero", in(reg) virtual_address) };
+}
+
+// Flush every non global translation tagged with the asid
+pub fn flush_asid(asid: usize) {
+    unsafe { asm!("sfence.vma zero, {}", in(reg) asid & SATP_ASID_MASK) };
+}
+
+// Flush the whole TLB on this hart
+pub fn flush_all() {
+    unsafe { asm!("sfence.vma zero, zero") };
+}
@@ -0,0 +1,93 @@
+//! Supervisor trap handling.
+//! asm_trap_vector (asm/trap.S) moves onto the hart's trap stack, spills every register
+//! into a TrapFrame there and calls kernel_trap, whatever it returns is written back to sepc before the sret
+use crate::address_space::Access;
+use crate::cpu::{self, MAX_HARTS};
+use crate::stack::{self, GuardedStack, StackOwner};
+use crate::thread;
+use crate::timer;
+use crate::tlb;
+use crate::{println, print};
+
+unsafe extern "C" {
+    fn asm_trap_vector();
+}
+
+// The registers x0-x31 as the trap vector saved them, regs[2] is the interrupted sp.
+// x0 is always zero so regs[0] holds the value sscratch is restored to instead
+#[repr(C)]
+pub struct TrapFrame {
+    pub regs: [usize; 32],
+}
+
+// scause has the interrupt flag in the top bit and the cause code in the rest
+pub const INTERRUPT_BIT: usize = 0b1 << 63;
+
+// Exception codes from the privileged spec
+pub const ILLEGAL_INSTRUCTION: usize = 2;
+pub const ENVIRONMENT_CALL_FROM_USER: usize = 8;
+pub const INSTRUCTION_PAGE_FAULT: usize = 12;
+pub const LOAD_PAGE_FAULT: usize = 13;
+pub const STORE_PAGE_FAULT: usize = 15;
+// and interrupt codes
+pub const SUPERVISOR_TIMER: usize = 5;
+
+// Each hart takes traps on its own stack, separate from whatever stack was running.
+// These live in .bss rather than coming from page::alloc so page::init can't pull them out from
+// under us, with a guard page each like every other kernel stack
+const TRAP_STACK_SIZE: usize = 16 * 1024;
+
+static mut TRAP_STACKS: [GuardedStack<TRAP_STACK_SIZE>; MAX_HARTS] = [const { GuardedStack::new() }; MAX_HARTS];
+
+// Point stvec at the trap vector (direct mode, the low bits stay 0) and
+// sscratch at the top of this hart's trap stack
+pub fn init() {
+    let hart = cpu::hart_id();
+    let trap_stack = unsafe { core::ptr::addr_of_mut!(TRAP_STACKS[hart]) };
+    let top = stack::register_static(trap_stack, StackOwner { hart, task: hart });
+    unsafe {
+        core::arch::asm!("csrw sscratch, {}", in(reg) top);
+        core::arch::asm!("csrw stvec, {}", in(reg) asm_trap_vector as *const () as usize);
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn kernel_trap(frame: &mut TrapFrame, cause: usize, tval: usize, epc: usize) -> usize {
+    // running into a guard page doesn't fault (see stack.rs), so check the interrupted stack and
+    // the one we're handling the trap on
+    stack::check(frame.regs[2]);
+    stack::check_current();
+    if cause == INTERRUPT_BIT | SUPERVISOR_TIMER {
+        timer::set_next();
+        // the thread we switch to might take traps of its own before we get back, and those
+        // leave sstatus (SPP, SPIE) set up for their sret instead of ours
+        let sstatus = cpu::sstatus();
+        thread::tick();
+        cpu::set_sstatus(sstatus);
+        return epc;
+    }
+    if cause & INTERRUPT_BIT != 0 {
+        println!("[WARN] unhandled interrupt {}", cause & !INTERRUPT_BIT);
+        return epc;
+    }
+    match cause {
+        INSTRUCTION_PAGE_FAULT | LOAD_PAGE_FAULT | STORE_PAGE_FAULT => {
+            let access = match cause {
+                INSTRUCTION_PAGE_FAULT => Access::Execute,
+                LOAD_PAGE_FAULT => Access::Load,
+                _ => Access::Store,
+            };
+            // the kernel reaches process memory through translate (see Process::copy_to_user),
+            // which sorts out lazily backed and copy on write pages itself, so nothing faults
+            // here on purpose. Faults from user mode go to Process::handle_trap
+            match tlb::active() {
+                Some((root, asid)) => panic!("{:?} page fault at 0x{:x} (pc 0x{:x}, root 0x{:x}, asid {})",
+                    access, tval, epc, root, asid & 0xffff),
+                None => panic!("{:?} page fault at 0x{:x} (pc 0x{:x})", access, tval, epc),
+            }
+        }
+        _ => {
+            panic!("unhandled exception {} (stval 0x{:x}, pc 0x{:x})", cause, tval, epc);
+        }
+    }
+}
@@ -0,0 +1,6 @@
+// Every program links with user.ld
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rustc-link-arg-bins=-T{}/user.ld", manifest_dir);
+    println!("cargo:rerun-if-changed=user.ld");
+}
@@ -0,0 +1,18 @@
+// Print the arguments, separated by spaces
+#![no_std]
+#![no_main]
+
+use user::{print, println};
+
+user::entry!(main);
+
+fn main() -> i32 {
+    for (i, arg) in user::args().skip(1).enumerate() {
+        if i > 0 {
+            print!(" ");
+        }
+        print!("{}", arg);
+    }
+    println!();
+    0
+}
@@ -0,0 +1,48 @@
+// Spawns a couple of children and waits for them, then leaves one behind as an orphan for the
+// kernel to clean up. Exits with 0 if every status came back the way it should
+#![no_std]
+#![no_main]
+
+use user::syscall::{self, Errno, WAIT_ANY};
+use user::{eprintln, println};
+
+user::entry!(main);
+
+fn main() -> i32 {
+    let children = [syscall::spawn("hello"), syscall::spawn("echo")];
+    let mut pids = [0; 2];
+    for (pid, child) in pids.iter_mut().zip(children) {
+        match child {
+            Ok(child) => *pid = child,
+            Err(error) => {
+                eprintln!("family: spawn failed: {:?}", error);
+                return 1;
+            }
+        }
+    }
+    if syscall::spawn("no such program") != Err(Errno::NO_ENTRY) {
+        eprintln!("family: spawned a program that doesn't exist");
+        return 1;
+    }
+    for _ in 0..pids.len() {
+        match syscall::waitpid(WAIT_ANY, 0) {
+            Ok((pid, status)) if pids.contains(&pid) && syscall::exit_code(status) == Some(0) => {
+                println!("family: child {} exited", pid);
+            }
+            other => {
+                eprintln!("family: unexpected wait: {:?}", other);
+                return 1;
+            }
+        }
+    }
+    if syscall::waitpid(WAIT_ANY, 0) != Err(Errno::NO_CHILD) {
+        eprintln!("family: waited for a child that doesn't exist");
+        return 1;
+    }
+    // nobody waits for this one but the kernel
+    match syscall::spawn("spin") {
+        Ok(pid) => println!("family: leaving {} behind", pid),
+        Err(_) => return 1,
+    }
+    0
+}
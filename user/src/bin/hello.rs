@@ -0,0 +1,12 @@
+// The smallest program there is
+#![no_std]
+#![no_main]
+
+use user::{println, syscall};
+
+user::entry!(main);
+
+fn main() -> i32 {
+    println!("hello from user mode, this is pid {}", syscall::getpid());
+    0
+}
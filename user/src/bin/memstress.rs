@@ -0,0 +1,69 @@
+// Works the allocator: lots of small blocks of every size class, a few big mmap'd buffers and a
+// vector that keeps growing, checking every byte is still what was written. memstress [rounds]
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use user::{eprintln, println};
+
+user::entry!(main);
+
+// what block i of a round gets filled with
+fn pattern(round: usize, i: usize) -> u8 {
+    (round * 31 + i * 7) as u8
+}
+
+fn main() -> i32 {
+    let rounds = user::args().nth(1).and_then(|rounds| rounds.parse().ok()).unwrap_or(4);
+    let mut allocations = 0;
+    let mut bytes = 0;
+    for round in 0..rounds {
+        // 16 bytes to 32 KiB, and freeing every other one so the free lists get reused
+        let mut blocks: Vec<Box<[u8]>> = Vec::new();
+        for i in 0..256 {
+            let size = 1 + (i * 131) % (32 * 1024);
+            blocks.push(alloc::vec![pattern(round, i); size].into_boxed_slice());
+            allocations += 1;
+            bytes += size;
+            if i % 2 == 1 {
+                blocks.swap_remove(i / 2);
+            }
+        }
+        // big enough to get mappings of their own
+        let mut big: Vec<Vec<u8>> = Vec::new();
+        for i in 0..4 {
+            let size = (64 << i) * 1024;
+            big.push(alloc::vec![pattern(round, i); size]);
+            allocations += 1;
+            bytes += size;
+        }
+        let mut growing = Vec::new();
+        for i in 0..20_000u32 {
+            growing.push(i ^ round as u32);
+        }
+        allocations += 1;
+        bytes += growing.capacity() * 4;
+        for block in &blocks {
+            let expected = block[0];
+            if block.iter().any(|byte| *byte != expected) {
+                eprintln!("memstress: round {}: a {} byte block got overwritten", round, block.len());
+                return 1;
+            }
+        }
+        for (i, buffer) in big.iter().enumerate() {
+            if buffer.iter().any(|byte| *byte != pattern(round, i)) {
+                eprintln!("memstress: round {}: mapping {} got overwritten", round, i);
+                return 1;
+            }
+        }
+        if growing.iter().enumerate().any(|(i, value)| *value != i as u32 ^ round as u32) {
+            eprintln!("memstress: round {}: the growing vector got overwritten", round);
+            return 1;
+        }
+    }
+    println!("memstress: ok, {} allocations, {} bytes in {} rounds", allocations, bytes, rounds);
+    0
+}
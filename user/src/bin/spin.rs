@@ -0,0 +1,12 @@
+// Burns CPU until someone kills it, something for ps and kill to look at
+#![no_std]
+#![no_main]
+
+user::entry!(main);
+
+fn main() -> i32 {
+    let mut count: usize = 0;
+    loop {
+        count = core::hint::black_box(count.wrapping_add(1));
+    }
+}
@@ -0,0 +1,99 @@
+//! The global allocator.
+//! Small allocations come out of power of two size classes, from 16 bytes to 32 KiB, carved out
+//! of pages the heap grows by with sbrk. A freed block goes back on its class's free list and
+//! never back to the kernel. Anything bigger gets a mapping of its own from mmap, and munmap
+//! when it's freed.
+//! A process only has the one thread, so there's no locking
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr::null_mut;
+use crate::syscall::{self, PROT_READ, PROT_WRITE};
+
+const PAGE_SIZE: usize = 4096;
+const SMALLEST: usize = 16;
+const CLASSES: usize = 12;
+// 64 KiB and up go straight to mmap
+const LARGE: usize = SMALLEST << CLASSES;
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+pub struct Heap {
+    free: UnsafeCell<[*mut FreeBlock; CLASSES]>,
+}
+
+// one thread per process
+unsafe impl Sync for Heap {}
+
+#[global_allocator]
+static HEAP: Heap = Heap { free: UnsafeCell::new([null_mut(); CLASSES]) };
+
+// The size class for the layout, None for mmap. A class's blocks are aligned to their size (up to
+// a page), so rounding the alignment in takes care of it
+fn class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(SMALLEST).next_power_of_two();
+    if size >= LARGE {
+        return None;
+    }
+    Some((size / SMALLEST).trailing_zeros() as usize)
+}
+
+// What a large allocation maps, rounded the same way on the way in and out
+fn large_size(layout: Layout) -> usize {
+    layout.size().max(layout.align())
+}
+
+impl Heap {
+    // sbrk a page (or one block, for the classes bigger than that) and put all of it on the free
+    // list. The heap only ever grows by whole pages, so every chunk starts page aligned
+    unsafe fn refill(&self, class: usize) -> bool {
+        let block_size = SMALLEST << class;
+        let chunk = block_size.max(PAGE_SIZE);
+        let Ok(start) = syscall::sbrk(chunk as isize) else {
+            return false;
+        };
+        let free = unsafe { &mut *self.free.get() };
+        for block in (start..start + chunk).step_by(block_size).rev() {
+            let block = block as *mut FreeBlock;
+            unsafe { (*block).next = free[class] };
+            free[class] = block;
+        }
+        true
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // nothing the kernel hands out is aligned past a page
+        if layout.align() > PAGE_SIZE {
+            return null_mut();
+        }
+        let Some(class) = class_of(layout) else {
+            return match syscall::mmap(0, large_size(layout), PROT_READ | PROT_WRITE) {
+                Ok(address) => address as *mut u8,
+                Err(_) => null_mut(),
+            };
+        };
+        let free = self.free.get();
+        if unsafe { (*free)[class].is_null() } && !unsafe { self.refill(class) } {
+            return null_mut();
+        }
+        unsafe {
+            let block = (*free)[class];
+            (*free)[class] = (*block).next;
+            block as *mut u8
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(class) = class_of(layout) else {
+            let _ = syscall::munmap(ptr as usize, large_size(layout));
+            return;
+        };
+        let free = unsafe { &mut *self.free.get() };
+        let block = ptr as *mut FreeBlock;
+        unsafe { (*block).next = free[class] };
+        free[class] = block;
+    }
+}
@@ -0,0 +1,146 @@
+//! The runtime shmageOS user programs link against.
+//! _start, the system call wrappers, a heap allocator over sbrk and mmap, print! over the write
+//! call and a panic handler. A program is a no_std, no_main binary that names its main with
+//! entry!:
+//!
+//!     #![no_std]
+//!     #![no_main]
+//!     user::entry!(main);
+//!     fn main() -> i32 {
+//!         user::println!("hello");
+//!         0
+//!     }
+//!
+//! main's return value is the exit code
+#![no_std]
+
+pub mod heap;
+pub mod syscall;
+
+use core::ffi::CStr;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+// The kernel starts a process with argc, argv and envp in a0-a2 (and on the stack, auxv after
+// them), sp 16 byte aligned. gp isn't set up, so the linker mustn't relax anything against it
+core::arch::global_asm!(
+    ".section .text.start, \"ax\"",
+    ".global _start",
+    "_start:",
+    ".option push",
+    ".option norelax",
+    "call {start}",
+    ".option pop",
+    start = sym start,
+);
+
+unsafe extern "C" {
+    // what entry! names main as
+    fn __user_main() -> i32;
+}
+
+static mut ARGC: usize = 0;
+static mut ARGV: *const *const u8 = core::ptr::null();
+static mut ENVP: *const *const u8 = core::ptr::null();
+
+extern "C" fn start(argc: usize, argv: *const *const u8, envp: *const *const u8) -> ! {
+    unsafe {
+        ARGC = argc;
+        ARGV = argv;
+        ENVP = envp;
+    }
+    let code = unsafe { __user_main() };
+    syscall::exit(code)
+}
+
+// Name the program's main, a fn() -> i32
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[unsafe(no_mangle)]
+        extern "C" fn __user_main() -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
+
+// The strings of a null terminated array the kernel put on the stack, anything that isn't utf-8
+// comes out empty
+fn strings(mut array: *const *const u8) -> impl Iterator<Item = &'static str> {
+    core::iter::from_fn(move || unsafe {
+        if array.is_null() || (*array).is_null() {
+            return None;
+        }
+        let string = CStr::from_ptr(*array as *const core::ffi::c_char);
+        array = array.add(1);
+        Some(string.to_str().unwrap_or(""))
+    })
+}
+
+// The program's arguments, the program name first
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let (argc, argv) = unsafe { (ARGC, ARGV) };
+    strings(argv).take(argc)
+}
+
+// NAME=value strings
+pub fn env() -> impl Iterator<Item = &'static str> {
+    strings(unsafe { ENVP })
+}
+
+pub fn var(name: &str) -> Option<&'static str> {
+    env().find_map(|entry| entry.strip_prefix(name)?.strip_prefix('='))
+}
+
+// A file descriptor to format into, print! and eprint! go through these
+pub struct Output(pub usize);
+
+impl Write for Output {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        let mut bytes = string.as_bytes();
+        while !bytes.is_empty() {
+            match syscall::write(self.0, bytes) {
+                Ok(written) => bytes = &bytes[written..],
+                Err(_) => return Err(fmt::Error),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn print_to(fd: usize, args: fmt::Arguments) {
+    let _ = Output(fd).write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($args:tt)+) => ($crate::print_to($crate::syscall::STDOUT, format_args!($($args)+)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($args:tt)+) => ($crate::print_to($crate::syscall::STDOUT, format_args!("{}\n", format_args!($($args)+))));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($args:tt)+) => ($crate::print_to($crate::syscall::STDERR, format_args!($($args)+)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($args:tt)+) => ($crate::print_to($crate::syscall::STDERR, format_args!("{}\n", format_args!($($args)+))));
+}
+
+// Exit code for a panic, the same one Rust uses elsewhere
+pub const PANIC_EXIT_CODE: i32 = 101;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{} panicked: {}", args().next().unwrap_or("?"), info);
+    syscall::exit(PANIC_EXIT_CODE)
+}
@@ -0,0 +1,149 @@
+//! System call wrappers.
+//! The numbers, errnos and flags are the kernel's ABI (src/syscall.rs in the kernel): the call
+//! number in a7, arguments in a0-a2, the result back in a0 with -4095..=-1 meaning an errno
+use core::arch::asm;
+
+pub const SYS_EXIT: usize = 1;
+pub const SYS_WRITE: usize = 2;
+pub const SYS_READ: usize = 3;
+pub const SYS_YIELD: usize = 4;
+pub const SYS_GETPID: usize = 5;
+pub const SYS_SLEEP: usize = 6;
+pub const SYS_SBRK: usize = 7;
+pub const SYS_MMAP: usize = 8;
+pub const SYS_MUNMAP: usize = 9;
+pub const SYS_WAITPID: usize = 10;
+pub const SYS_KILL: usize = 11;
+pub const SYS_SPAWN: usize = 12;
+pub const SYS_GETPPID: usize = 13;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+pub const PROT_READ: usize = 0b1;
+pub const PROT_WRITE: usize = 0b1 << 1;
+pub const PROT_EXEC: usize = 0b1 << 2;
+
+pub const WNOHANG: usize = 0b1;
+pub const WAIT_ANY: usize = usize::MAX;
+
+// A failed call, the errno the kernel returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub usize);
+
+impl Errno {
+    pub const PERMISSION_DENIED: Errno = Errno(1);
+    pub const NO_ENTRY: Errno = Errno(2);
+    pub const NO_SUCH_PROCESS: Errno = Errno(3);
+    pub const INTERRUPTED: Errno = Errno(4);
+    pub const BAD_FILE: Errno = Errno(9);
+    pub const NO_CHILD: Errno = Errno(10);
+    pub const TRY_AGAIN: Errno = Errno(11);
+    pub const OUT_OF_MEMORY: Errno = Errno(12);
+    pub const BAD_ADDRESS: Errno = Errno(14);
+    pub const INVALID_ARGUMENT: Errno = Errno(22);
+    pub const NOT_IMPLEMENTED: Errno = Errno(38);
+}
+
+// The biggest errno, anything from -MAX_ERRNO up is a failure
+const MAX_ERRNO: usize = 4095;
+
+fn decode(result: usize) -> Result<usize, Errno> {
+    if result.wrapping_neg() <= MAX_ERRNO && result != 0 {
+        Err(Errno(result.wrapping_neg()))
+    } else {
+        Ok(result)
+    }
+}
+
+// Make system call number with three arguments, calls with fewer just pass zeroes
+pub fn syscall(number: usize, args: [usize; 3]) -> Result<usize, Errno> {
+    let result;
+    // the kernel only touches a0, and memory the call is about
+    unsafe {
+        asm!("ecall", inlateout("a0") args[0] => result, in("a1") args[1], in("a2") args[2], in("a7") number,
+            options(nostack));
+    }
+    decode(result)
+}
+
+pub fn exit(code: i32) -> ! {
+    let _ = syscall(SYS_EXIT, [code as usize, 0, 0]);
+    // exit never comes back, but just in case
+    loop {
+        yield_now();
+    }
+}
+
+pub fn write(fd: usize, buffer: &[u8]) -> Result<usize, Errno> {
+    syscall(SYS_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
+}
+
+// Waits for at least one byte
+pub fn read(fd: usize, buffer: &mut [u8]) -> Result<usize, Errno> {
+    syscall(SYS_READ, [fd, buffer.as_mut_ptr() as usize, buffer.len()])
+}
+
+pub fn yield_now() {
+    let _ = syscall(SYS_YIELD, [0; 3]);
+}
+
+pub fn getpid() -> usize {
+    syscall(SYS_GETPID, [0; 3]).unwrap_or(0)
+}
+
+pub fn sleep_ms(milliseconds: usize) {
+    let _ = syscall(SYS_SLEEP, [milliseconds, 0, 0]);
+}
+
+// Grow (or shrink) the heap, returns where it ended before
+pub fn sbrk(increment: isize) -> Result<usize, Errno> {
+    syscall(SYS_SBRK, [increment as usize, 0, 0])
+}
+
+// Anonymous zeroed memory, address 0 lets the kernel pick where
+pub fn mmap(address: usize, length: usize, prot: usize) -> Result<usize, Errno> {
+    syscall(SYS_MMAP, [address, length, prot])
+}
+
+// Only whole mappings, with the length they were made with
+pub fn munmap(address: usize, length: usize) -> Result<(), Errno> {
+    syscall(SYS_MUNMAP, [address, length, 0]).map(|_| ())
+}
+
+pub fn getppid() -> usize {
+    syscall(SYS_GETPPID, [0; 3]).unwrap_or(0)
+}
+
+// Start one of the programs built into the kernel as a child, returns its pid
+pub fn spawn(name: &str) -> Result<usize, Errno> {
+    syscall(SYS_SPAWN, [name.as_ptr() as usize, name.len(), 0])
+}
+
+// Stop the process itself or one of its children, anyone else is PERMISSION_DENIED
+pub fn kill(pid: usize) -> Result<(), Errno> {
+    syscall(SYS_KILL, [pid, 0, 0]).map(|_| ())
+}
+
+// Collect an exited child (WAIT_ANY for whichever), returns its pid and wait status. With WNOHANG
+// the pid is 0 when no child has exited yet
+pub fn waitpid(pid: usize, options: usize) -> Result<(usize, u32), Errno> {
+    let mut status = 0u32;
+    let pid = syscall(SYS_WAITPID, [pid, &mut status as *mut u32 as usize, options])?;
+    Ok((pid, status))
+}
+
+// The exit code in a wait status, None if the child was stopped by a signal (killed or a fault)
+pub fn exit_code(status: u32) -> Option<i32> {
+    if status & 0x7f == 0 {
+        Some((status >> 8 & 0xff) as i32)
+    } else {
+        None
+    }
+}
+
+// The signal that stopped the child, if one did
+pub fn signal(status: u32) -> Option<u32> {
+    if status & 0x7f != 0 { Some(status & 0x7f) } else { None }
+}